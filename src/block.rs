@@ -2,7 +2,7 @@ use crate::errors::UnrealpakError;
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use std::io::{Read, Write};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct Block {
     pub(crate) start: u64,
     pub(crate) end: u64,
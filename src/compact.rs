@@ -0,0 +1,95 @@
+//! Reclaiming the space left in a pak by entries that were edited or removed in place.
+
+use crate::errors::UnrealpakError;
+use crate::pak_reader::PakReader;
+use crate::pak_writer::{PakBuilder, PakWriterOptions};
+use crate::version::Version;
+use std::io::{Read, Seek};
+
+impl PakBuilder {
+    /// Creates a builder that copies every entry of `pak` as stored, laid out in the order of its
+    /// data, with the pak's version, mount point and path hash seed. This drops the gaps in its
+    /// data left by edited or removed entries, as found by [`PakReader::data_layout`]. Entries
+    /// that share data only keep sharing it if [`PakWriterOptions::deduplicate`] is set.
+    pub fn from_pak<R>(pak: PakReader<R>, options: PakWriterOptions) -> Result<Self, UnrealpakError>
+    where
+        R: Read + Seek + 'static,
+    {
+        let version = pak.pak.version;
+        let path_hash_seed = pak.pak.index.path_hash_seed.unwrap_or_default();
+        PakBuilder::copy_pak(pak, version, path_hash_seed, options)
+    }
+
+    pub(crate) fn copy_pak<R>(
+        pak: PakReader<R>,
+        version: Version,
+        path_hash_seed: u64,
+        mut options: PakWriterOptions,
+    ) -> Result<Self, UnrealpakError>
+    where
+        R: Read + Seek + 'static,
+    {
+        // The data keeps the encryption of the pak, and with it the GUID of its key.
+        if options.encryption_key_guid == 0 {
+            options.encryption_key_guid = pak.pak.encryption_key_guid;
+        }
+        let mut builder = PakBuilder::with_path_hash_seed(
+            version,
+            pak.pak.index.mount_point.clone(),
+            path_hash_seed,
+            options,
+        )?;
+        let mut entries = pak
+            .pak
+            .index
+            .entries()
+            .into_iter()
+            .map(|(path, record)| (path, record.offset))
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|&(_, offset)| offset);
+
+        let source = builder.add_source_pak(pak)?;
+        for (path, _) in entries {
+            builder.copy_entry(path.clone(), source, &path)?;
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::KeyInit;
+    use aes::{Aes256Dec, Aes256Enc};
+    use base64::Engine;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_compact_keeps_index_encryption() {
+        let key = base64::engine::general_purpose::STANDARD
+            .decode("lNJbw660IOC+kU7cnVQ1oeqrXyhk4J6UAZrCBbcnp94=")
+            .unwrap();
+        let v11_pak = include_bytes!("../tests/packs/pack_v11_encryptindex.pak");
+        let read = || {
+            let key = Aes256Dec::new_from_slice(&key).unwrap();
+            PakReader::read_any(Cursor::new(v11_pak), Some(key)).unwrap()
+        };
+
+        assert!(matches!(
+            PakBuilder::from_pak(read(), Default::default()),
+            Err(UnrealpakError::MissingKey)
+        ));
+
+        let options = PakWriterOptions {
+            encrypt_index: Some(Aes256Enc::new_from_slice(&key).unwrap()),
+            deduplicate: true,
+            ..Default::default()
+        };
+        let mut out_bytes = vec![];
+        PakBuilder::from_pak(read(), options)
+            .unwrap()
+            .finish(&mut Cursor::new(&mut out_bytes))
+            .unwrap();
+        assert_eq!(&out_bytes[..], &v11_pak[..]);
+    }
+}
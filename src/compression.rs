@@ -1,8 +1,47 @@
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u32)]
 pub enum Compression {
+    #[default]
     None,
     Zlib,
     Gzip,
     Oodle,
 }
+
+/// How hard a codec tries, from fastest to best.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum CompressionLevel {
+    Fastest,
+    #[default]
+    Default,
+    Best,
+    /// A codec specific level, clamped to the levels the codec supports (`0..=9` for zlib).
+    Level(u32),
+}
+
+/// The [`CompressionLevel`] of each codec the writer supports.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CompressionLevels {
+    pub zlib: CompressionLevel,
+}
+
+impl CompressionLevels {
+    /// The levels UnrealPak compresses with. It uses zlib's default level and strategy, so this is
+    /// the same as [`CompressionLevels::default`]; [`PakWriterOptions::unrealpak`] also covers
+    /// which entries UnrealPak compresses at all.
+    ///
+    /// [`PakWriterOptions::unrealpak`]: crate::PakWriterOptions::unrealpak
+    pub const UNREALPAK: CompressionLevels = CompressionLevels {
+        zlib: CompressionLevel::Default,
+    };
+
+    pub(crate) fn zlib(&self) -> flate2::Compression {
+        match self.zlib {
+            CompressionLevel::Fastest => flate2::Compression::fast(),
+            CompressionLevel::Default => flate2::Compression::default(),
+            CompressionLevel::Best => flate2::Compression::best(),
+            CompressionLevel::Level(level) => flate2::Compression::new(level.min(9)),
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Reports of the virtual paths that several paks provide, to tell which pak overrides which.
+
+use crate::errors::UnrealpakError;
+use crate::pak_reader::PakReader;
+use crate::vfs::pak_order;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+/// A pak providing a path listed in a [`Conflict`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Provider {
+    /// File name of the pak.
+    pub pak: String,
+    /// Order the pak is mounted with, as given by [`pak_order`].
+    pub order: i32,
+    /// SHA-1 of the entry's stored data, which is `None` for delete records. Entries stored
+    /// with different compression or encryption hash differently even if their contents match.
+    pub hash: Option<[u8; 20]>,
+}
+
+/// A virtual path provided by more than one pak.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    /// Full virtual path, e.g. `../../../Game/Content/Maps/Main.umap`.
+    pub path: String,
+    /// Every pak providing the path, from the one the engine resolves it to down.
+    pub providers: Vec<Provider>,
+    /// Whether any provider's entry differs from the winner's.
+    pub contents_differ: bool,
+}
+
+impl Conflict {
+    /// The provider whose entry the engine uses.
+    pub fn winner(&self) -> &Provider {
+        &self.providers[0]
+    }
+}
+
+/// Lists every virtual path that more than one of `paks` provides, in path order. Paks are
+/// named by the file name of their path and ranked like [`PakVfs::mount`](crate::PakVfs::mount)
+/// ranks them: by [`pak_order`], then by their position in `paks`.
+pub fn conflict_report<P, R>(
+    paks: &mut [(P, PakReader<R>)],
+) -> Result<Vec<Conflict>, UnrealpakError>
+where
+    P: AsRef<Path>,
+    R: Read + Seek,
+{
+    let mut providers: BTreeMap<String, Vec<Provider>> = BTreeMap::new();
+    for (pak_path, pak) in paks.iter_mut() {
+        let pak_path = pak_path.as_ref();
+        let name = pak_path
+            .file_name()
+            .unwrap_or(pak_path.as_os_str())
+            .to_string_lossy()
+            .into_owned();
+        let order = pak_order(pak_path);
+        for path in pak.files().collect::<Vec<_>>() {
+            let provider = Provider {
+                pak: name.clone(),
+                order,
+                hash: pak.stored_hash(&path)?.map(|hash| hash.0),
+            };
+            providers
+                .entry(pak.full_path(&path))
+                .or_default()
+                .push(provider);
+        }
+    }
+
+    Ok(providers
+        .into_iter()
+        .filter(|(_, providers)| providers.len() > 1)
+        .map(|(path, mut providers)| {
+            // A stable sort keeps the first pak mounted ahead of others of the same order.
+            providers.sort_by_key(|provider| Reverse(provider.order));
+            let contents_differ = providers
+                .iter()
+                .any(|provider| provider.hash != providers[0].hash);
+            Conflict {
+                path,
+                providers,
+                contents_differ,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::pak_writer::build_test_pak;
+    use crate::version::Version;
+
+    #[test]
+    fn test_conflict_report() {
+        let base = build_test_pak(
+            Version::V11,
+            "../../../Game/",
+            &[
+                ("Content/a.txt", Some("base"), Compression::None),
+                ("Content/b.txt", Some("same"), Compression::None),
+                ("Content/c.txt", Some("base"), Compression::None),
+                ("Content/d.txt", Some("base"), Compression::None),
+            ],
+        );
+        let mod_a = build_test_pak(
+            Version::V11,
+            "../../../Game/Content/",
+            &[
+                ("a.txt", Some("mod a"), Compression::None),
+                ("b.txt", Some("same"), Compression::None),
+            ],
+        );
+        let mod_b = build_test_pak(
+            Version::V11,
+            "../../../Game/Content/",
+            &[
+                ("a.txt", Some("mod b"), Compression::None),
+                ("c.txt", None, Compression::None),
+            ],
+        );
+        let mut paks = [
+            ("Paks/pakchunk0-Windows.pak", base),
+            ("Paks/~mods/ModA_P.pak", mod_a),
+            ("Paks/~mods/ModB_P.pak", mod_b),
+        ];
+        let report = conflict_report(&mut paks).unwrap();
+
+        let summary = report
+            .iter()
+            .map(|conflict| {
+                let paks = conflict
+                    .providers
+                    .iter()
+                    .map(|provider| provider.pak.as_str())
+                    .collect::<Vec<_>>();
+                (conflict.path.as_str(), paks, conflict.contents_differ)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "../../../Game/Content/a.txt",
+                    vec!["ModA_P.pak", "ModB_P.pak", "pakchunk0-Windows.pak"],
+                    true
+                ),
+                (
+                    "../../../Game/Content/b.txt",
+                    vec!["ModA_P.pak", "pakchunk0-Windows.pak"],
+                    false
+                ),
+                (
+                    "../../../Game/Content/c.txt",
+                    vec!["ModB_P.pak", "pakchunk0-Windows.pak"],
+                    true
+                ),
+            ]
+        );
+        assert_eq!(report[0].winner().order, 100);
+        assert_eq!(report[2].winner().hash, None);
+        assert_eq!(report[1].providers[0].hash, report[1].providers[1].hash);
+    }
+}
@@ -0,0 +1,124 @@
+//! Conversion of paks between format versions.
+
+use crate::errors::UnrealpakError;
+use crate::pak_reader::PakReader;
+use crate::pak_writer::{utf16le_path_to_bytes, PakBuilder, PakWriterOptions};
+use crate::strcrc32::strcrc32;
+use crate::version::Version;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+impl PakBuilder {
+    /// Like [`PakBuilder::from_pak`], but for a pak of another `version`. Entry data, compression
+    /// blocks and encryption are copied as stored, while record headers, the index and the footer
+    /// are encoded as `version` needs. Paks from before [`VersionMajor::PathHashIndex`] have no
+    /// path hash seed, so it is derived from `output_pak_path` the same way UnrealPak does.
+    ///
+    /// Compressed and encrypted entries cannot be converted to versions before
+    /// [`VersionMajor::CompressionEncryption`]; writing them fails with
+    /// [`UnrealpakError::IncompatibleEntry`].
+    ///
+    /// [`VersionMajor::PathHashIndex`]: crate::VersionMajor::PathHashIndex
+    /// [`VersionMajor::CompressionEncryption`]: crate::VersionMajor::CompressionEncryption
+    pub fn convert<R, O>(
+        pak: PakReader<R>,
+        version: Version,
+        output_pak_path: O,
+        options: PakWriterOptions,
+    ) -> Result<Self, UnrealpakError>
+    where
+        R: Read + Seek + 'static,
+        O: AsRef<Path>,
+    {
+        let path_hash_seed = match pak.pak.index.path_hash_seed {
+            Some(path_hash_seed) => path_hash_seed,
+            None => strcrc32(&utf16le_path_to_bytes(output_pak_path)?) as u64,
+        };
+        PakBuilder::copy_pak(pak, version, path_hash_seed, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn options() -> PakWriterOptions {
+        PakWriterOptions {
+            deduplicate: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_convert_between_versions() {
+        init_logger();
+
+        let packs = [
+            ("pack_v5", Version::V5),
+            ("pack_v7", Version::V7),
+            ("pack_v8a", Version::V8A),
+            ("pack_v8b", Version::V8B),
+            ("pack_v9", Version::V9),
+            ("pack_v11", Version::V11),
+        ];
+        for suffix in ["", "_compress"] {
+            for (source_name, source_version) in packs {
+                // v11_compress leaves text files uncompressed, unlike the older fixtures.
+                if suffix == "_compress" && source_version == Version::V11 {
+                    continue;
+                }
+                let source = fs::read(format!("./tests/packs/{source_name}{suffix}.pak")).unwrap();
+                for (target_name, target_version) in packs {
+                    if suffix == "_compress" && target_version == Version::V11 {
+                        continue;
+                    }
+                    let pak =
+                        crate::PakReader::read_any(Cursor::new(source.clone()), None).unwrap();
+                    assert_eq!(pak.pak.version, source_version);
+
+                    let mut out_bytes = vec![];
+                    PakBuilder::convert(
+                        pak,
+                        target_version,
+                        format!("/home/truman/projects/drg-modding/tools/unpak/tests/packs/{target_name}{suffix}.pak"),
+                        options(),
+                    )
+                    .unwrap()
+                    .finish(&mut Cursor::new(&mut out_bytes))
+                    .unwrap();
+                    let expected =
+                        fs::read(format!("./tests/packs/{target_name}{suffix}.pak")).unwrap();
+                    assert!(
+                        out_bytes == expected,
+                        "{source_name}{suffix} -> {target_name}{suffix}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_convert_rejects_compressed_entries_before_v3() {
+        init_logger();
+
+        let pak = crate::PakReader::read_any(
+            Cursor::new(include_bytes!("../tests/packs/pack_v9_compress.pak")),
+            None,
+        )
+        .unwrap();
+        let mut out_bytes = vec![];
+        let result = PakBuilder::convert(pak, Version::V2, "/tmp/pack_v2.pak", options())
+            .unwrap()
+            .finish(&mut Cursor::new(&mut out_bytes));
+        assert!(matches!(
+            result,
+            Err(UnrealpakError::IncompatibleEntry(_, 2))
+        ));
+    }
+}
@@ -0,0 +1,324 @@
+//! Comparison of two paks entry by entry, from their records where they tell entries apart and
+//! from their decoded contents only where they do not.
+
+use crate::errors::UnrealpakError;
+use crate::hash::HashingWriter;
+use crate::pak_reader::PakReader;
+use crate::pak_writer::stored_size;
+use crate::version::Version;
+use crate::Compression;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek};
+
+/// An entry as [`diff`] compares it.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DiffEntry {
+    /// Full virtual path, e.g. `../../../Game/Content/Maps/Main.umap`.
+    pub path: String,
+    pub uncompressed_size: u64,
+    /// Size of the data following the record, including the padding of encrypted data.
+    pub stored_size: u64,
+    pub compression: Compression,
+    pub encrypted: bool,
+    pub deleted: bool,
+    /// SHA-1 of the stored data, which is `None` for delete records.
+    pub hash: Option<[u8; 20]>,
+}
+
+/// A path both paks provide, with entries that differ.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EntryChange {
+    pub a: DiffEntry,
+    pub b: DiffEntry,
+    /// Whether the contents differ, rather than only the way they are stored.
+    pub content_changed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum MetadataChange {
+    Version {
+        a: Version,
+        b: Version,
+    },
+    MountPoint {
+        a: String,
+        b: String,
+    },
+    IndexEncryption {
+        a: bool,
+        b: bool,
+    },
+    /// Whether any entry is encrypted.
+    DataEncryption {
+        a: bool,
+        b: bool,
+    },
+}
+
+/// The differences between two paks, with entries in path order.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PakDiff {
+    pub metadata: Vec<MetadataChange>,
+    /// Entries only in `b`.
+    pub added: Vec<DiffEntry>,
+    /// Entries only in `a`.
+    pub removed: Vec<DiffEntry>,
+    pub changed: Vec<EntryChange>,
+}
+
+impl PakDiff {
+    pub fn is_empty(&self) -> bool {
+        self.metadata.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+/// Lists what changed from `a` to `b`, matching entries by their full virtual path. Entries
+/// stored alike are compared by the SHA-1 in front of their data, and entries of different sizes
+/// differ outright; the contents of the rest are decrypted and decompressed to compare them,
+/// which fails with [`UnrealpakError::Encrypted`] if their pak was read without a key.
+pub fn diff<A, B>(a: &mut PakReader<A>, b: &mut PakReader<B>) -> Result<PakDiff, UnrealpakError>
+where
+    A: Read + Seek,
+    B: Read + Seek,
+{
+    let entries_a = diff_entries(a)?;
+    let mut entries_b = diff_entries(b)?;
+
+    let mut metadata = vec![];
+    if a.pak.version != b.pak.version {
+        metadata.push(MetadataChange::Version {
+            a: a.pak.version,
+            b: b.pak.version,
+        });
+    }
+    if a.mount_point() != b.mount_point() {
+        metadata.push(MetadataChange::MountPoint {
+            a: a.mount_point().to_owned(),
+            b: b.mount_point().to_owned(),
+        });
+    }
+    if a.pak.is_index_encrypted != b.pak.is_index_encrypted {
+        metadata.push(MetadataChange::IndexEncryption {
+            a: a.pak.is_index_encrypted,
+            b: b.pak.is_index_encrypted,
+        });
+    }
+    let any_encrypted = |entries: &BTreeMap<String, (String, DiffEntry)>| {
+        entries.values().any(|(_, entry)| entry.encrypted)
+    };
+    if any_encrypted(&entries_a) != any_encrypted(&entries_b) {
+        metadata.push(MetadataChange::DataEncryption {
+            a: any_encrypted(&entries_a),
+            b: any_encrypted(&entries_b),
+        });
+    }
+
+    let mut pak_diff = PakDiff {
+        metadata,
+        ..Default::default()
+    };
+    for (full_path, (path_a, entry_a)) in entries_a {
+        let Some((path_b, entry_b)) = entries_b.remove(&full_path) else {
+            pak_diff.removed.push(entry_a);
+            continue;
+        };
+        let same_storage = entry_a.stored_size == entry_b.stored_size
+            && entry_a.compression == entry_b.compression
+            && entry_a.encrypted == entry_b.encrypted
+            && entry_a.deleted == entry_b.deleted;
+        if same_storage && entry_a.hash == entry_b.hash {
+            continue;
+        }
+        let content_changed = if entry_a.deleted || entry_b.deleted {
+            entry_a.deleted != entry_b.deleted
+        } else if entry_a.uncompressed_size != entry_b.uncompressed_size {
+            true
+        } else if same_storage && entry_a.compression == Compression::None && !entry_a.encrypted {
+            // The stored data is the contents, so differing hashes mean differing contents.
+            true
+        } else {
+            content_hash(a, &path_a)? != content_hash(b, &path_b)?
+        };
+        pak_diff.changed.push(EntryChange {
+            a: entry_a,
+            b: entry_b,
+            content_changed,
+        });
+    }
+    pak_diff
+        .added
+        .extend(entries_b.into_values().map(|(_, entry)| entry));
+    Ok(pak_diff)
+}
+
+/// The entries of `pak` by full path, along with their path relative to the mount point.
+fn diff_entries<R: Read + Seek>(
+    pak: &mut PakReader<R>,
+) -> Result<BTreeMap<String, (String, DiffEntry)>, UnrealpakError> {
+    let records = pak
+        .pak
+        .index
+        .entries()
+        .into_iter()
+        .map(|(path, record)| (path, record.clone()))
+        .collect::<Vec<_>>();
+    let mut entries = BTreeMap::new();
+    for (path, record) in records {
+        let entry = DiffEntry {
+            path: pak.full_path(&path),
+            uncompressed_size: record.uncompressed_size,
+            stored_size: stored_size(&record),
+            compression: record.compression_method,
+            encrypted: record.is_encrypted.unwrap_or_default(),
+            deleted: record.is_deleted,
+            hash: pak.stored_hash(&path)?.map(|hash| hash.0),
+        };
+        entries.insert(entry.path.clone(), (path, entry));
+    }
+    Ok(entries)
+}
+
+/// SHA-1 of the decoded contents of the entry at `path`.
+fn content_hash<R: Read + Seek>(
+    pak: &mut PakReader<R>,
+    path: &str,
+) -> Result<[u8; 20], UnrealpakError> {
+    let mut sink = io::sink();
+    let mut writer = HashingWriter::new(&mut sink);
+    pak.read_file(path, &mut writer)?;
+    Ok(writer.finish().0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pak_writer::build_test_pak;
+    use aes::cipher::KeyInit;
+    use aes::Aes256Dec;
+
+    #[test]
+    fn test_diff_entries() {
+        let mut a = build_test_pak(
+            Version::V11,
+            "../../../Game/",
+            &[
+                ("Content/same.txt", Some("same"), Compression::None),
+                ("Content/edited.txt", Some("before"), Compression::None),
+                (
+                    "Content/recompressed.txt",
+                    Some("aaaaaaaa"),
+                    Compression::None,
+                ),
+                ("Content/rezipped.txt", Some("abcdefgh"), Compression::Zlib),
+                ("Content/removed.txt", Some("removed"), Compression::None),
+                ("Content/deleted.txt", Some("deleted"), Compression::None),
+            ],
+        );
+        let mut b = build_test_pak(
+            Version::V11,
+            "../../../Game/Content/",
+            &[
+                ("same.txt", Some("same"), Compression::None),
+                ("edited.txt", Some("after!"), Compression::None),
+                ("recompressed.txt", Some("aaaaaaaa"), Compression::Zlib),
+                ("rezipped.txt", Some("abcdefgi"), Compression::Zlib),
+                ("deleted.txt", None, Compression::None),
+                ("added.txt", Some("added"), Compression::None),
+            ],
+        );
+        let pak_diff = diff(&mut a, &mut b).unwrap();
+
+        assert_eq!(
+            pak_diff.metadata,
+            vec![MetadataChange::MountPoint {
+                a: "../../../Game/".to_owned(),
+                b: "../../../Game/Content/".to_owned(),
+            }]
+        );
+        let paths = |entries: &[DiffEntry]| {
+            entries
+                .iter()
+                .map(|entry| entry.path.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            paths(&pak_diff.added),
+            vec!["../../../Game/Content/added.txt"]
+        );
+        assert_eq!(
+            paths(&pak_diff.removed),
+            vec!["../../../Game/Content/removed.txt"]
+        );
+        let changed = pak_diff
+            .changed
+            .iter()
+            .map(|change| (change.b.path.as_str(), change.content_changed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changed,
+            vec![
+                ("../../../Game/Content/deleted.txt", true),
+                ("../../../Game/Content/edited.txt", true),
+                // Only the way the contents are stored changed.
+                ("../../../Game/Content/recompressed.txt", false),
+                ("../../../Game/Content/rezipped.txt", true),
+            ]
+        );
+        assert!(pak_diff.changed[0].b.deleted);
+        assert_eq!(pak_diff.changed[0].b.hash, None);
+        assert_eq!(pak_diff.changed[2].b.compression, Compression::Zlib);
+    }
+
+    #[test]
+    fn test_diff_metadata() {
+        use base64::Engine;
+
+        let key = base64::engine::general_purpose::STANDARD
+            .decode("lNJbw660IOC+kU7cnVQ1oeqrXyhk4J6UAZrCBbcnp94=")
+            .unwrap();
+        let key = Aes256Dec::new_from_slice(&key).unwrap();
+        let read = |name: &str, key: Option<Aes256Dec>| {
+            let file = std::fs::File::open(format!("./tests/packs/{name}.pak")).unwrap();
+            PakReader::read_any(file, key).unwrap()
+        };
+        let mut a = read("pack_v8b", None);
+        let mut b = read("pack_v11_compress_encrypt_encryptindex", Some(key));
+        let pak_diff = diff(&mut a, &mut b).unwrap();
+
+        assert_eq!(
+            pak_diff.metadata,
+            vec![
+                MetadataChange::Version {
+                    a: Version::V8B,
+                    b: Version::V11,
+                },
+                MetadataChange::IndexEncryption { a: false, b: true },
+                MetadataChange::DataEncryption { a: false, b: true },
+            ]
+        );
+        // Every entry is decrypted and decompressed to find that its contents did not change.
+        assert_eq!(pak_diff.changed.len(), 4);
+        assert!(pak_diff
+            .changed
+            .iter()
+            .all(|change| !change.content_changed));
+        assert!(pak_diff.added.is_empty() && pak_diff.removed.is_empty());
+
+        let mut same = read("pack_v8b", None);
+        assert!(diff(&mut a, &mut same).unwrap().is_empty());
+
+        // Telling the contents apart takes the key.
+        let mut b = read("pack_v11_compress_encrypt", None);
+        assert!(matches!(
+            diff(&mut a, &mut b),
+            Err(UnrealpakError::Encrypted)
+        ));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::compression::Compression;
 use std::ffi::OsString;
 
 use thiserror::Error;
@@ -24,6 +25,36 @@ pub enum UnrealpakError {
     UnsupportedVersion,
     #[error("missing key to decrypt encrypted pak")]
     Encrypted,
+    #[error("missing key to encrypt pak data")]
+    MissingKey,
+    #[error("cancelled")]
+    Cancelled,
+    #[error("entry {0} not found")]
+    EntryNotFound(String),
+    #[error("{0:?} compression is not supported")]
+    UnsupportedCompression(Compression),
+    #[error("{0} cannot be stored in a version {1} pak")]
+    IncompatibleEntry(String, u32),
+    #[error("{0} does not fit in a pak of {1} bytes")]
+    EntryTooLarge(String, u64),
+    #[error("duplicate entry {0}")]
+    DuplicateEntry(String),
+    #[error("entry path {0} would be extracted outside the destination directory")]
+    UnsafePath(String),
+    #[error("entries {0} and {1} would be extracted to the same path")]
+    TargetCollision(String, String),
+    #[error("invalid response file line {0}: {1}")]
+    ResponseFile(usize, String),
+    #[error("invalid order file line {0}: {1}")]
+    OrderFile(usize, String),
+    #[error("{0} is not under mount point {1}")]
+    NotUnderMountPoint(String, String),
+    #[error("invalid RSA key: {0}")]
+    InvalidKey(&'static str),
+    #[error("pak signature does not match its chunk hashes")]
+    InvalidSignature,
+    #[error("chunk {0} does not match the pak signature")]
+    ChunkHashMismatch(usize),
     #[error("failed to convert OsString to bytes")]
     OsString(OsString),
 }
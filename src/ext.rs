@@ -77,10 +77,73 @@ impl<W: Write> WriteExt for W {
     }
 
     fn write_cstring(&mut self, value: &str) -> Result<(), UnrealpakError> {
-        let bytes = value.as_bytes();
-        self.write_u32::<LE>(bytes.len() as u32 + 1)?;
-        self.write_all(bytes)?;
-        self.write_u8(0)?;
+        if value.is_ascii() {
+            let bytes = value.as_bytes();
+            self.write_u32::<LE>(bytes.len() as u32 + 1)?;
+            self.write_all(bytes)?;
+            self.write_u8(0)?;
+        } else {
+            // Non-ASCII strings are stored as UCS-2 with a negative length (in code units).
+            let units = value.encode_utf16().collect::<Vec<_>>();
+            self.write_i32::<LE>(-(units.len() as i32 + 1))?;
+            for unit in units {
+                self.write_u16::<LE>(unit)?;
+            }
+            self.write_u16::<LE>(0)?;
+        }
         Ok(())
     }
 }
+
+/// Number of bytes `write_cstring` emits for `value` after the `i32` length prefix, including the
+/// NUL terminator.
+pub(crate) fn cstring_serialized_size(value: &str) -> u64 {
+    if value.is_ascii() {
+        value.len() as u64 + 1
+    } else {
+        (value.encode_utf16().count() as u64 + 1) * 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_cstring_ascii() {
+        let mut buf = vec![];
+        Cursor::new(&mut buf).write_cstring("test.txt").unwrap();
+        assert_eq!(
+            buf,
+            [0x09, 0x00, 0x00, 0x00, 0x74, 0x65, 0x73, 0x74, 0x2E, 0x74, 0x78, 0x74, 0x00]
+        );
+        assert_eq!(buf.len() as u64, 4 + cstring_serialized_size("test.txt"));
+    }
+
+    #[test]
+    fn test_write_cstring_utf16() {
+        let mut buf = vec![];
+        Cursor::new(&mut buf).write_cstring("テスト.txt").unwrap();
+        assert_eq!(
+            buf,
+            [
+                0xF8, 0xFF, 0xFF, 0xFF, 0xC6, 0x30, 0xB9, 0x30, 0xC8, 0x30, 0x2E, 0x00, 0x74, 0x00,
+                0x78, 0x00, 0x74, 0x00, 0x00, 0x00
+            ]
+        );
+        assert_eq!(buf.len() as u64, 4 + cstring_serialized_size("テスト.txt"));
+
+        let mut reader = Cursor::new(&buf);
+        assert_eq!(reader.read_cstring().unwrap(), "テスト.txt");
+    }
+
+    #[test]
+    fn test_cstring_round_trip_outside_bmp() {
+        let value = "资源/😀.uasset";
+        let mut buf = vec![];
+        Cursor::new(&mut buf).write_cstring(value).unwrap();
+        assert_eq!(buf.len() as u64, 4 + cstring_serialized_size(value));
+        assert_eq!(Cursor::new(&buf).read_cstring().unwrap(), value);
+    }
+}
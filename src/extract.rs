@@ -0,0 +1,630 @@
+//! Extraction of the entries of a pak to a directory. Entry paths and mount points come from the
+//! pak, so they are sanitised to keep a malicious pak from writing outside the directory.
+
+use crate::errors::UnrealpakError;
+use crate::pak_reader::PakReader;
+use crate::progress::{NoProgress, Progress, ProgressTracker};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Options for [`PakReader::extract_all`].
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// Extracts entries under their mount point, e.g. to `Game/Content/Maps/Main.umap` for a pak
+    /// mounted at `../../../Game/`, rather than relative to it. The `..` components, roots and
+    /// drive prefixes of mount points are always dropped.
+    pub prefix_mount_point: bool,
+    /// Fails with [`UnrealpakError::UnsafePath`] on entry paths with `..` components, roots or
+    /// drive prefixes, rather than dropping those components.
+    pub reject_unsafe_paths: bool,
+}
+
+/// Joins the components of `path`, split at `/` and `\`, skipping `.` components. `..`
+/// components, roots and components with a `:`, such as drive prefixes, are unsafe: they are
+/// dropped, or rejected if `reject` is set.
+pub(crate) fn sanitize_path(path: &str, reject: bool) -> Result<PathBuf, UnrealpakError> {
+    let unsafe_path = || UnrealpakError::UnsafePath(path.to_owned());
+    if reject && path.starts_with(['/', '\\']) {
+        return Err(unsafe_path());
+    }
+    let mut sanitized = PathBuf::new();
+    for component in path.split(['/', '\\']) {
+        let is_unsafe = component == ".." || component.contains(':');
+        match component {
+            "" | "." => {}
+            _ if !is_unsafe => sanitized.push(component),
+            _ if reject => return Err(unsafe_path()),
+            _ => {}
+        }
+    }
+    Ok(sanitized)
+}
+
+/// Where each entry of `pak` is extracted to under `dest`, in the order of their data. Delete
+/// records have no data and are left out. Entries whose sanitised paths collide, ignoring case as
+/// UE does, or that would be extracted over another entry's directory fail with
+/// [`UnrealpakError::TargetCollision`].
+pub(crate) fn extraction_targets<R: Read + Seek>(
+    pak: &PakReader<R>,
+    dest: &Path,
+    options: &ExtractOptions,
+) -> Result<Vec<(String, PathBuf, u64)>, UnrealpakError> {
+    let dest = match options.prefix_mount_point {
+        true => dest.join(sanitize_path(pak.mount_point(), false)?),
+        false => dest.to_path_buf(),
+    };
+    let mut entries = pak
+        .pak
+        .index
+        .entries()
+        .into_iter()
+        .filter(|(_, record)| !record.is_deleted)
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|(_, record)| record.offset);
+
+    // Lowercased relative paths of the files and directories claimed so far, to the entry
+    // claiming them.
+    let mut files = HashMap::<String, String>::new();
+    let mut dirs = HashMap::new();
+    let mut targets = vec![];
+    for (path, record) in entries {
+        let relative_path = sanitize_path(&path, options.reject_unsafe_paths)?;
+        if relative_path.as_os_str().is_empty() {
+            return Err(UnrealpakError::UnsafePath(path));
+        }
+        let key = relative_path.to_string_lossy().to_lowercase();
+        let collision = files.get(&key).or_else(|| dirs.get(&key)).or_else(|| {
+            relative_path
+                .ancestors()
+                .skip(1)
+                .find_map(|dir| files.get(&dir.to_string_lossy().to_lowercase()))
+        });
+        if let Some(other) = collision {
+            return Err(UnrealpakError::TargetCollision(other.clone(), path));
+        }
+        for dir in relative_path.ancestors().skip(1) {
+            dirs.entry(dir.to_string_lossy().to_lowercase())
+                .or_insert_with(|| path.clone());
+        }
+        files.insert(key, path.clone());
+        targets.push((path, dest.join(relative_path), record.uncompressed_size));
+    }
+    Ok(targets)
+}
+
+/// Writes the entry at `path` to `target` with `read`, such as [`PakReader::read_file`], creating
+/// its directory. The partly written file is removed if this fails or is cancelled.
+pub(crate) fn extract_entry<R, F>(
+    pak: &mut PakReader<R>,
+    target: &Path,
+    read: F,
+) -> Result<(), UnrealpakError>
+where
+    R: Read + Seek,
+    F: FnOnce(&mut PakReader<R>, &mut BufWriter<fs::File>) -> Result<(), UnrealpakError>,
+{
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut writer = BufWriter::new(fs::File::create(target)?);
+    let result = read(pak, &mut writer).and_then(|()| Ok(writer.flush()?));
+    if result.is_err() {
+        drop(writer);
+        let _ = fs::remove_file(target);
+    }
+    result
+}
+
+/// What the threads of [`PakReader::extract_all_parallel_with_progress`] send to the thread
+/// reporting progress.
+enum Message {
+    Started(usize),
+    Wrote(usize, u64),
+    Failed(UnrealpakError),
+}
+
+/// Sends the bytes written for an entry as [`Message::Wrote`], failing once `stop` is set so
+/// that large entries are abandoned part way.
+struct ChannelWriter<'a, W> {
+    inner: W,
+    entry_index: usize,
+    sender: &'a mpsc::Sender<Message>,
+    stop: &'a AtomicBool,
+}
+
+impl<W: Write> Write for ChannelWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.stop.load(Ordering::Relaxed) {
+            return Err(io::Error::other(UnrealpakError::Cancelled.to_string()));
+        }
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            let _ = self.sender.send(Message::Wrote(self.entry_index, n as u64));
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R> PakReader<R>
+where
+    R: Read + Seek,
+{
+    /// Writes every entry to `dest`, decrypted and decompressed, returning the paths written.
+    /// Every entry path is checked before anything is written.
+    pub fn extract_all<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+        options: &ExtractOptions,
+    ) -> Result<Vec<PathBuf>, UnrealpakError> {
+        self.extract_all_with_progress(dest, options, &mut NoProgress)
+    }
+
+    /// Like [`PakReader::extract_all`], reporting the bytes written for each entry to `progress`.
+    /// Cancelling leaves the entries extracted so far in place and removes the one being written.
+    pub fn extract_all_with_progress<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+        options: &ExtractOptions,
+        progress: &mut dyn Progress,
+    ) -> Result<Vec<PathBuf>, UnrealpakError> {
+        let targets = extraction_targets(self, dest.as_ref(), options)?;
+        let bytes_total = targets.iter().map(|(_, _, size)| size).sum();
+        let mut tracker = ProgressTracker::new(progress, targets.len(), bytes_total);
+        for (i, (path, target, size)) in targets.iter().enumerate() {
+            tracker.start_entry(i, path, *size)?;
+            extract_entry(self, target, |pak, writer| {
+                let result = pak.read_file(path, &mut tracker.writer(writer));
+                result.map_err(|e| tracker.error(e))
+            })?;
+        }
+        Ok(targets.into_iter().map(|(_, target, _)| target).collect())
+    }
+
+    /// Like [`PakReader::extract_all`], but decrypts and decompresses entries on `threads`
+    /// threads. Each thread reads the pak through its own handle from `open`, such as
+    /// `|| File::open(&pak_path)`, so the reader this pak was read with is left alone.
+    pub fn extract_all_parallel<P, F, S>(
+        &self,
+        open: F,
+        dest: P,
+        options: &ExtractOptions,
+        threads: usize,
+    ) -> Result<Vec<PathBuf>, UnrealpakError>
+    where
+        P: AsRef<Path>,
+        F: Fn() -> io::Result<S> + Sync,
+        S: Read + Seek,
+    {
+        self.extract_all_parallel_with_progress(open, dest, options, threads, &mut NoProgress)
+    }
+
+    /// Like [`PakReader::extract_all_parallel`], reporting the bytes written for each entry to
+    /// `progress` as they are written, so events of entries written at once are interleaved.
+    /// Cancelling or failing stops every thread and removes the entries being written.
+    pub fn extract_all_parallel_with_progress<P, F, S>(
+        &self,
+        open: F,
+        dest: P,
+        options: &ExtractOptions,
+        threads: usize,
+        progress: &mut dyn Progress,
+    ) -> Result<Vec<PathBuf>, UnrealpakError>
+    where
+        P: AsRef<Path>,
+        F: Fn() -> io::Result<S> + Sync,
+        S: Read + Seek,
+    {
+        let targets = extraction_targets(self, dest.as_ref(), options)?;
+        let bytes_total = targets.iter().map(|(_, _, size)| size).sum();
+        let mut tracker = ProgressTracker::new(progress, targets.len(), bytes_total);
+
+        let next = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        let (sender, receiver) = mpsc::channel();
+        let (pak, key, targets_ref) = (&self.pak, &self.key, &targets);
+        let (open, next_ref, stop_ref) = (&open, &next, &stop);
+        let result = thread::scope(|scope| {
+            for _ in 0..threads.max(1) {
+                let sender = sender.clone();
+                scope.spawn(move || {
+                    let work = || -> Result<(), UnrealpakError> {
+                        let mut reader = PakReader {
+                            pak: pak.clone(),
+                            reader: open()?,
+                            key: key.clone(),
+                        };
+                        loop {
+                            let i = next_ref.fetch_add(1, Ordering::Relaxed);
+                            if i >= targets_ref.len() || stop_ref.load(Ordering::Relaxed) {
+                                return Ok(());
+                            }
+                            let (path, target, _) = &targets_ref[i];
+                            let _ = sender.send(Message::Started(i));
+                            extract_entry(&mut reader, target, |pak, writer| {
+                                let mut writer = ChannelWriter {
+                                    inner: writer,
+                                    entry_index: i,
+                                    sender: &sender,
+                                    stop: stop_ref,
+                                };
+                                pak.read_file(path, &mut writer)
+                            })?;
+                        }
+                    };
+                    if let Err(e) = work() {
+                        let _ = sender.send(Message::Failed(e));
+                    }
+                });
+            }
+            drop(sender);
+
+            // Only the first error is returned and nothing is reported after it; the threads
+            // stop once it has been seen.
+            let mut entry_bytes = vec![0; targets_ref.len()];
+            let mut current = None;
+            let mut result = Ok(());
+            for message in receiver {
+                if result.is_err() {
+                    continue;
+                }
+                let reported = match message {
+                    Message::Started(i) => {
+                        let (path, _, size) = &targets_ref[i];
+                        current = Some(i);
+                        tracker.start_entry(i, path, *size)
+                    }
+                    Message::Wrote(i, n) => {
+                        if current != Some(i) {
+                            let (path, _, size) = &targets_ref[i];
+                            current = Some(i);
+                            tracker.resume_entry(i, path, *size, entry_bytes[i]);
+                        }
+                        entry_bytes[i] += n;
+                        tracker.advance(n)
+                    }
+                    Message::Failed(e) => Err(e),
+                };
+                if let Err(e) = reported {
+                    stop_ref.store(true, Ordering::Relaxed);
+                    result = Err(e);
+                }
+            }
+            result
+        });
+        result?;
+        Ok(targets.into_iter().map(|(_, target, _)| target).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pak_writer::PakBuilder;
+    use crate::progress::ProgressEvent;
+    use crate::version::Version;
+    use std::io::Cursor;
+    use std::ops::ControlFlow;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = Path::new("./target").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn read_v11_pak() -> PakReader<Cursor<&'static [u8]>> {
+        let pak = &include_bytes!("../tests/packs/pack_v11_compress.pak")[..];
+        PakReader::read(Cursor::new(pak), Version::V11, None).unwrap()
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        let sanitize = |path| sanitize_path(path, false).unwrap();
+        assert_eq!(sanitize("Content/a.txt"), Path::new("Content/a.txt"));
+        assert_eq!(sanitize("../../../Game/"), Path::new("Game"));
+        assert_eq!(sanitize("/etc/passwd"), Path::new("etc/passwd"));
+        assert_eq!(sanitize("a/./b/../../c.txt"), Path::new("a/b/c.txt"));
+        assert_eq!(sanitize("C:\\Windows\\x.dll"), Path::new("Windows/x.dll"));
+        assert_eq!(sanitize("..\\..\\x.txt"), Path::new("x.txt"));
+
+        for path in ["../x", "a/../x", "/x", "\\x", "C:/x", "a\\..\\x"] {
+            assert!(matches!(
+                sanitize_path(path, true),
+                Err(UnrealpakError::UnsafePath(p)) if p == path
+            ));
+        }
+        assert_eq!(
+            sanitize_path("./a/b.txt", true).unwrap(),
+            Path::new("a/b.txt")
+        );
+    }
+
+    #[test]
+    fn test_extract_all() {
+        let dest = test_dir("test_extract_all");
+        let written = read_v11_pak()
+            .extract_all(&dest, &Default::default())
+            .unwrap();
+        assert_eq!(written.len(), 4);
+        for path in ["directory/nested.txt", "test.png", "test.txt", "zeros.bin"] {
+            assert_eq!(
+                fs::read(dest.join(path)).unwrap(),
+                fs::read(Path::new("./tests/pack/root").join(path)).unwrap(),
+                "{path}"
+            );
+        }
+
+        let options = ExtractOptions {
+            prefix_mount_point: true,
+            ..Default::default()
+        };
+        read_v11_pak().extract_all(&dest, &options).unwrap();
+        assert!(dest.join("mount/point/root/directory/nested.txt").is_file());
+    }
+
+    #[test]
+    fn test_extract_all_stays_in_destination() {
+        let mut builder =
+            PakBuilder::new(Version::V11, "../../../", "pak.pak", Default::default()).unwrap();
+        builder
+            .add_file("../../escaped.txt", b"escaped".to_vec())
+            .add_file("C:/drive.txt", b"drive".to_vec())
+            .add_file("ok.txt", b"ok".to_vec());
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+        let read = || PakReader::read(Cursor::new(out_bytes.clone()), Version::V11, None).unwrap();
+
+        let dir = test_dir("test_extract_all_stays_in_destination");
+        let dest = dir.join("dest");
+        let options = ExtractOptions {
+            prefix_mount_point: true,
+            reject_unsafe_paths: true,
+        };
+        assert!(matches!(
+            read().extract_all(&dest, &options),
+            Err(UnrealpakError::UnsafePath(_))
+        ));
+        assert!(!dest.exists());
+
+        let options = ExtractOptions {
+            prefix_mount_point: true,
+            reject_unsafe_paths: false,
+        };
+        let mut written = read().extract_all(&dest, &options).unwrap();
+        written.sort();
+        assert_eq!(
+            written,
+            vec![
+                dest.join("drive.txt"),
+                dest.join("escaped.txt"),
+                dest.join("ok.txt")
+            ]
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_extract_all_rejects_colliding_targets() {
+        let collisions = [
+            ["../x.txt", "x.txt"],
+            ["Dir/X.txt", "dir/x.txt"],
+            ["a", "a/b.txt"],
+            ["a/b.txt", "a"],
+        ];
+        for [first, second] in collisions {
+            let mut builder =
+                PakBuilder::new(Version::V11, "../../../", "pak.pak", Default::default()).unwrap();
+            builder
+                .add_file(first, b"first".to_vec())
+                .add_file(second, b"second".to_vec());
+            let mut out_bytes = vec![];
+            builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+            let mut pak = PakReader::read(Cursor::new(out_bytes), Version::V11, None).unwrap();
+
+            let dest = test_dir("test_extract_all_rejects_colliding_targets");
+            assert!(
+                matches!(
+                    pak.extract_all(&dest, &Default::default()),
+                    Err(UnrealpakError::TargetCollision(a, b)) if a == first && b == second
+                ),
+                "{first} {second}"
+            );
+            assert!(!dest.exists());
+        }
+    }
+
+    #[test]
+    fn test_extract_all_removes_cancelled_entry() {
+        let dest = test_dir("test_extract_all_removes_cancelled_entry");
+        let mut cancelled_path = None;
+        let mut cancel = |event: &ProgressEvent| match event.entry_bytes {
+            0 => ControlFlow::Continue(()),
+            _ => {
+                cancelled_path = Some(event.path.to_owned());
+                ControlFlow::Break(())
+            }
+        };
+        assert!(matches!(
+            read_v11_pak().extract_all_with_progress(&dest, &Default::default(), &mut cancel),
+            Err(UnrealpakError::Cancelled)
+        ));
+        let cancelled_path = cancelled_path.unwrap();
+        assert!(!dest.join(cancelled_path).exists());
+    }
+
+    #[test]
+    fn test_extract_all_reports_progress() {
+        let dest = test_dir("test_extract_all_reports_progress");
+        let mut events = vec![];
+        let mut progress = |event: &ProgressEvent| {
+            events.push((event.entry_index, event.bytes));
+            ControlFlow::Continue(())
+        };
+        read_v11_pak()
+            .extract_all_with_progress(&dest, &Default::default(), &mut progress)
+            .unwrap();
+        let bytes_total = ["directory/nested.txt", "test.png", "test.txt", "zeros.bin"]
+            .iter()
+            .map(|path| fs::metadata(dest.join(path)).unwrap().len())
+            .sum::<u64>();
+        assert_eq!(events.last(), Some(&(3, bytes_total)));
+
+        let mut cancel = |_: &ProgressEvent| ControlFlow::Break(());
+        assert!(matches!(
+            read_v11_pak().extract_all_with_progress(&dest, &Default::default(), &mut cancel),
+            Err(UnrealpakError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_extract_all_parallel() {
+        use aes::cipher::KeyInit;
+        use aes::Aes256Dec;
+        use base64::Engine;
+
+        let key = base64::engine::general_purpose::STANDARD
+            .decode("lNJbw660IOC+kU7cnVQ1oeqrXyhk4J6UAZrCBbcnp94=")
+            .unwrap();
+        let key = Aes256Dec::new_from_slice(&key).unwrap();
+        let pak_path = "./tests/packs/pack_v11_compress_encrypt_encryptindex.pak";
+        let pak = PakReader::read_any(fs::File::open(pak_path).unwrap(), Some(key)).unwrap();
+
+        let dest = test_dir("test_extract_all_parallel");
+        let mut events = vec![];
+        let mut progress = |event: &ProgressEvent| {
+            events.push((event.path.to_owned(), event.entry_bytes));
+            ControlFlow::Continue(())
+        };
+        let mut written = pak
+            .extract_all_parallel_with_progress(
+                || fs::File::open(pak_path),
+                &dest,
+                &Default::default(),
+                3,
+                &mut progress,
+            )
+            .unwrap();
+        written.sort();
+        let paths = ["directory/nested.txt", "test.png", "test.txt", "zeros.bin"];
+        assert_eq!(
+            written,
+            paths.iter().map(|path| dest.join(path)).collect::<Vec<_>>()
+        );
+        for path in paths {
+            let expected = fs::read(Path::new("./tests/pack/root").join(path)).unwrap();
+            assert_eq!(fs::read(dest.join(path)).unwrap(), expected, "{path}");
+            assert!(events.contains(&(path.to_owned(), expected.len() as u64)));
+        }
+
+        let missing = pak.extract_all_parallel(
+            || fs::File::open("./tests/packs/missing.pak"),
+            &dest,
+            &Default::default(),
+            2,
+        );
+        assert!(matches!(missing, Err(UnrealpakError::IoError(_))));
+
+        let mut cancel = |_: &ProgressEvent| ControlFlow::Break(());
+        let cancelled = pak.extract_all_parallel_with_progress(
+            || fs::File::open(pak_path),
+            &dest,
+            &Default::default(),
+            2,
+            &mut cancel,
+        );
+        assert!(matches!(cancelled, Err(UnrealpakError::Cancelled)));
+    }
+
+    #[test]
+    fn test_extract_all_parallel_reports_progress_like_sequential() {
+        let data = (0..300_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut builder =
+            PakBuilder::new(Version::V11, "../../../", "pak.pak", Default::default()).unwrap();
+        builder
+            .add_file("large.bin", data.clone())
+            .add_file("empty.txt", vec![])
+            .add_file("small.txt", b"small".to_vec());
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+        let pak = PakReader::read(Cursor::new(out_bytes.clone()), Version::V11, None).unwrap();
+        let open = || Ok(Cursor::new(out_bytes.clone()));
+
+        // The final bytes of each entry and of the whole job, and how many events there were.
+        let summarize = |events: &[(String, u64, u64)]| {
+            let entries = events
+                .iter()
+                .map(|(path, entry_bytes, _)| (path.clone(), *entry_bytes))
+                .collect::<std::collections::BTreeMap<_, _>>();
+            (entries, events.last().unwrap().2)
+        };
+        let record = |events: &mut Vec<(String, u64, u64)>, event: &ProgressEvent| {
+            events.push((event.path.to_owned(), event.entry_bytes, event.bytes));
+            ControlFlow::Continue(())
+        };
+
+        let mut sequential = vec![];
+        let dest = test_dir("test_extract_all_parallel_reports_progress_like_sequential");
+        PakReader::read(Cursor::new(out_bytes.clone()), Version::V11, None)
+            .unwrap()
+            .extract_all_with_progress(&dest, &Default::default(), &mut |event: &ProgressEvent| {
+                record(&mut sequential, event)
+            })
+            .unwrap();
+        let mut parallel = vec![];
+        pak.extract_all_parallel_with_progress(
+            open,
+            &dest,
+            &Default::default(),
+            2,
+            &mut |event: &ProgressEvent| record(&mut parallel, event),
+        )
+        .unwrap();
+        let expected = [
+            ("empty.txt".to_owned(), 0),
+            ("large.bin".to_owned(), data.len() as u64),
+            ("small.txt".to_owned(), 5),
+        ]
+        .into();
+        assert_eq!(summarize(&sequential), (expected, data.len() as u64 + 5));
+        assert_eq!(summarize(&parallel), summarize(&sequential));
+        assert!(parallel.len() > 3);
+
+        // Cancelling part way through an entry reports nothing more and leaves no partly
+        // written entry behind.
+        let dest = test_dir("test_extract_all_parallel_cancels_mid_entry");
+        let mut events_after_cancel = 0;
+        let mut cancelled = false;
+        let mut cancel = |event: &ProgressEvent| {
+            if cancelled {
+                events_after_cancel += 1;
+            }
+            match event.entry_bytes {
+                0 => ControlFlow::Continue(()),
+                _ => {
+                    cancelled = true;
+                    ControlFlow::Break(())
+                }
+            }
+        };
+        let result = pak.extract_all_parallel_with_progress(
+            open,
+            &dest,
+            &Default::default(),
+            2,
+            &mut cancel,
+        );
+        assert!(matches!(result, Err(UnrealpakError::Cancelled)));
+        assert_eq!(events_after_cancel, 0);
+        if let Ok(written) = fs::read(dest.join("large.bin")) {
+            assert_eq!(written, data);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Parser for UnrealPak order files, as passed with `-order=`. The engine records the files it
+//! opens into `GameOpenOrder.log`, one full virtual path per line followed by its open order:
+//!
+//! ```text
+//! "../../../Game/Content/Maps/Main.umap" 1
+//! "../../../Game/Content/Maps/Main_BuiltData.uasset" 2
+//! ```
+
+use crate::errors::UnrealpakError;
+use crate::response_file::tokenize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Load order of files by their full virtual path. Paths are matched case-insensitively, like
+/// UnrealPak does.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileOrder {
+    order: HashMap<String, u64>,
+}
+
+impl FileOrder {
+    pub fn from_order_file<P: AsRef<Path>>(path: P) -> Result<Self, UnrealpakError> {
+        FileOrder::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Lines without an order number are ordered by their position in the file. When a path is
+    /// listed more than once, its first order is kept.
+    pub fn parse(contents: &str) -> Result<Self, UnrealpakError> {
+        let mut order = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line_number = i + 1;
+            let tokens = tokenize(line).ok_or_else(|| {
+                UnrealpakError::OrderFile(line_number, "unterminated quote".to_owned())
+            })?;
+            let (path, position) = match tokens.as_slice() {
+                [] => continue,
+                [path] => (path, line_number as u64),
+                [path, position] => (
+                    path,
+                    position.parse().map_err(|_| {
+                        UnrealpakError::OrderFile(
+                            line_number,
+                            format!("invalid order number {}", position),
+                        )
+                    })?,
+                ),
+                _ => {
+                    return Err(UnrealpakError::OrderFile(
+                        line_number,
+                        "expected a path and an optional order number".to_owned(),
+                    ))
+                }
+            };
+            order.entry(normalize(path)).or_insert(position);
+        }
+        Ok(FileOrder { order })
+    }
+
+    /// Order of a full virtual path, e.g. `../../../Game/Content/Maps/Main.umap`, if it is listed.
+    pub fn position(&self, path: &str) -> Option<u64> {
+        self.order.get(&normalize(path)).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+fn normalize(path: &str) -> String {
+    path.replace('\\', "/").to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_game_open_order() {
+        let order = FileOrder::parse(
+            "\"../../../Game/Content/Maps/Main.umap\" 3\n\
+             \n\
+             \"..\\..\\..\\Game\\Content\\Maps\\Main_BuiltData.uasset\" 1\n\
+             ../../../Game/Content/Unordered.uasset\n\
+             \"../../../game/content/maps/main.umap\" 7\n",
+        )
+        .unwrap();
+
+        assert_eq!(order.len(), 3);
+        assert_eq!(
+            order.position("../../../Game/Content/Maps/Main.umap"),
+            Some(3)
+        );
+        assert_eq!(
+            order.position("../../../Game/Content/Maps/Main_BuiltData.uasset"),
+            Some(1)
+        );
+        assert_eq!(
+            order.position("../../../Game/Content/Unordered.uasset"),
+            Some(4)
+        );
+        assert_eq!(order.position("../../../Game/Content/Missing.uasset"), None);
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_lines() {
+        assert!(matches!(
+            FileOrder::parse("\"a.uasset\" 1\n\"b.uasset 2\n"),
+            Err(UnrealpakError::OrderFile(2, _))
+        ));
+        assert!(matches!(
+            FileOrder::parse("a.uasset one"),
+            Err(UnrealpakError::OrderFile(1, _))
+        ));
+        assert!(matches!(
+            FileOrder::parse("a.uasset 1 2"),
+            Err(UnrealpakError::OrderFile(1, _))
+        ));
+    }
+}
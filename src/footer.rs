@@ -1,9 +1,8 @@
 use crate::errors::UnrealpakError;
 use crate::ext::{ReadExt, WriteExt};
 use crate::hash::Hash;
-use crate::version::VersionMajor;
+use crate::version::{Version, VersionMajor};
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use core::panic;
 use std::io::{Read, Write};
 
 #[derive(Debug)]
@@ -14,7 +13,7 @@ pub(crate) struct Footer {
     pub(crate) is_index_encrypted: Option<bool>,
     /// Must be `0x5A6F12E1`.
     pub(crate) magic: u32,
-    pub(crate) version: VersionMajor,
+    pub(crate) version: Version,
     pub(crate) index_offset: u64,
     pub(crate) index_size: u64,
     pub(crate) index_hash: Hash,
@@ -43,22 +42,14 @@ impl Footer {
         size += 8; // index size
         size += 20; // index hash
         size += if self.is_index_frozen.is_some() { 1 } else { 0 };
-        size += if self.compression_methods.is_some() {
-            match self.version {
-                VersionMajor::FNameBasedCompression => 128,
-                v if v > VersionMajor::FNameBasedCompression => 160,
-                _ => panic!("unexpected compression method size"),
-            }
-        } else {
-            0
-        };
+        size += 32 * self.version.compression_method_slots() as u32;
         size
     }
 }
 
 pub(crate) fn read_footer<R: Read>(
     reader: &mut R,
-    version_hint: VersionMajor,
+    version_hint: Version,
 ) -> Result<Footer, UnrealpakError> {
     let encryption_key_guid = if version_hint >= VersionMajor::EncryptionKeyGuid {
         Some(reader.read_u128::<LE>()?)
@@ -78,27 +69,16 @@ pub(crate) fn read_footer<R: Read>(
     }
 
     let version = reader.read_u32::<LE>()?;
-    let version = match version {
-        1 => VersionMajor::Initial,
-        2 => VersionMajor::NoTimestamps,
-        3 => VersionMajor::CompressionEncryption,
-        4 => VersionMajor::IndexEncryption,
-        5 => VersionMajor::RelativeChunkOffsets,
-        6 => VersionMajor::DeleteRecords,
-        7 => VersionMajor::EncryptionKeyGuid,
-        8 => VersionMajor::FNameBasedCompression,
-        9 => VersionMajor::FrozenIndex,
-        10 => VersionMajor::PathHashIndex,
-        11 => VersionMajor::Fnv64BugFix,
-        v => return Err(UnrealpakError::UnknownVersion(v)),
-    };
-
-    if version != version_hint {
+    if !Version::iterator().any(|v| v.major() as u32 == version) {
+        return Err(UnrealpakError::UnknownVersion(version));
+    }
+    if version != version_hint.major() as u32 {
         return Err(UnrealpakError::VersionMismatch {
-            expected: version_hint as u32,
-            actual: version as u32,
+            expected: version_hint.major() as u32,
+            actual: version,
         });
     }
+    let version = version_hint;
 
     let index_offset = reader.read_u64::<LE>()?;
     let index_size = reader.read_u64::<LE>()?;
@@ -110,18 +90,9 @@ pub(crate) fn read_footer<R: Read>(
         None
     };
 
-    let compression_methods = match version_hint {
-        VersionMajor::FNameBasedCompression => {
-            let mut cm = [0u8; 128];
-            reader.read_exact(&mut cm)?;
-            Some(cm.to_vec())
-        }
-        v if v > VersionMajor::FNameBasedCompression => {
-            let mut cm = [0u8; 160];
-            reader.read_exact(&mut cm)?;
-            Some(cm.to_vec())
-        }
-        _ => None,
+    let compression_methods = match version.compression_method_slots() {
+        0 => None,
+        slots => Some(reader.read_len(32 * slots)?),
     };
 
     Ok(Footer {
@@ -150,7 +121,7 @@ pub(crate) fn write_footer<W: Write>(
     };
 
     writer.write_u32::<LE>(footer.magic)?;
-    writer.write_u32::<LE>(footer.version as u32)?;
+    writer.write_u32::<LE>(footer.version.major() as u32)?;
     writer.write_u64::<LE>(footer.index_offset)?;
     writer.write_u64::<LE>(footer.index_size)?;
     writer.write_all(&footer.index_hash.0)?;
@@ -194,13 +165,13 @@ mod tests {
         assert_eq!(v11_footer.len(), 221);
 
         let mut reader = Cursor::new(v11_footer);
-        let footer = read_footer(&mut reader, VersionMajor::Fnv64BugFix).unwrap();
+        let footer = read_footer(&mut reader, Version::V11).unwrap();
         assert_eq!(footer.size(), 221);
 
         assert_eq!(footer.encryption_key_guid, Some(0));
         assert_eq!(footer.is_index_encrypted, Some(false));
         assert_eq!(footer.magic, 0x5A6F12E1);
-        assert_eq!(footer.version, VersionMajor::Fnv64BugFix);
+        assert_eq!(footer.version, Version::V11);
         assert_eq!(footer.index_offset, 0x34F7);
         assert_eq!(footer.index_size, 0xAD);
         assert_eq!(
@@ -220,7 +191,7 @@ mod tests {
             encryption_key_guid: Some(0),
             is_index_encrypted: Some(false),
             magic: crate::MAGIC,
-            version: VersionMajor::Fnv64BugFix,
+            version: Version::V11,
             index_offset: 0x34F7,
             index_size: 0xAD,
             index_hash: Hash([
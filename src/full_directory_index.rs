@@ -1,24 +1,40 @@
 use crate::errors::UnrealpakError;
-use crate::ext::{ReadExt, WriteExt};
+use crate::ext::{cstring_serialized_size, ReadExt, WriteExt};
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use std::collections::BTreeMap;
 use std::io::{Read, Write};
 
 /// Map<DirectoryName, Map<FileName, Offset>>
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct FullDirectoryIndex(pub(crate) BTreeMap<String, BTreeMap<String, u32>>);
 
 impl FullDirectoryIndex {
+    /// Indexes paths, relative to the mount point, by the encoded offset of their record.
+    pub(crate) fn from_paths<'a, I>(paths: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, u32)>,
+    {
+        let mut directories: BTreeMap<String, BTreeMap<String, u32>> = BTreeMap::new();
+        for (path, offset) in paths {
+            let (dirname, filename) = split_pak_path(path);
+            directories
+                .entry(dirname)
+                .or_default()
+                .insert(filename, offset);
+        }
+        FullDirectoryIndex(directories)
+    }
+
     pub(crate) fn serialized_size(&self) -> u64 {
         let mut size = 0u64;
         size += 4; // dir count
         for (dir_name, map) in self.0.iter() {
             size += 4; // dir_name size
-            size += dir_name.len() as u64 + 1; // dir_name with NUL terminating byte
+            size += cstring_serialized_size(dir_name); // dir_name with NUL terminating byte
             size += 4; // file count
             for (file_name, _offset) in map.iter() {
                 size += 4; // file_name size
-                size += file_name.len() as u64 + 1; // file_name with NUL terminating byte
+                size += cstring_serialized_size(file_name); // file_name with NUL terminating byte
                 size += 4; // offset
             }
         }
@@ -26,6 +42,19 @@ impl FullDirectoryIndex {
     }
 }
 
+/// Splits a pak path into the directory and file name keys of the [`FullDirectoryIndex`]. Files at
+/// the root of the mount point live in the `/` directory.
+pub(crate) fn split_pak_path(pak_path: &str) -> (String, String) {
+    // Need to +1 so the path on the left has the slash.
+    match pak_path.rfind('/').map(|i| i + 1) {
+        Some(i) => {
+            let (l, r) = pak_path.split_at(i);
+            (l.to_owned(), r.to_owned())
+        }
+        None => ("/".to_owned(), pak_path.to_owned()),
+    }
+}
+
 pub(crate) fn read_full_directory_index<R: Read>(
     reader: &mut R,
 ) -> Result<FullDirectoryIndex, UnrealpakError> {
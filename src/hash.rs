@@ -1,2 +1,36 @@
+use sha1::{Digest, Sha1};
+use std::io::Write;
+
 #[derive(Debug, PartialEq, Clone)]
 pub(crate) struct Hash(pub(crate) [u8; 20]);
+
+/// Forwards writes to `inner` while computing the SHA1 of everything written through it.
+pub(crate) struct HashingWriter<'a, W> {
+    inner: &'a mut W,
+    hasher: Sha1,
+}
+
+impl<'a, W: Write> HashingWriter<'a, W> {
+    pub(crate) fn new(inner: &'a mut W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha1::new(),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Hash {
+        Hash(self.hasher.finalize().into())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -1,17 +1,23 @@
 use crate::decrypt::decrypt;
 use crate::errors::UnrealpakError;
-use crate::ext::{ReadExt, WriteExt};
+use crate::ext::{cstring_serialized_size, ReadExt, WriteExt};
 use crate::full_directory_index::{
     read_full_directory_index, write_full_directory_index, FullDirectoryIndex,
 };
+use crate::hash::Hash;
+use crate::pak_writer::{encrypt, pad_with_repeated};
 use crate::path_hash_index::{read_path_hash_index, write_path_hash_index, PathHashIndex};
-use crate::record::{read_record, write_record, Record};
-use crate::version::VersionMajor;
+use crate::record::{read_record, write_record, EntryLocation, Record};
+use crate::version::{Version, VersionMajor};
+use aes::cipher::BlockSizeUser;
+use aes::Aes256Enc;
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use log::debug;
 use sha1::{Digest, Sha1};
+use std::collections::HashMap;
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct Index {
     pub(crate) mount_point: String,
     pub(crate) record_count: u32,
@@ -19,15 +25,15 @@ pub(crate) struct Index {
     pub(crate) path_hash_index: Option<PathHashIndex>,
     pub(crate) full_directory_index: Option<FullDirectoryIndex>,
     pub(crate) records: Vec<Record>,
+    /// Position in `records` of each encoded record offset, see [`record_positions`].
+    pub(crate) record_positions: HashMap<u32, usize>,
 }
 
 impl Index {
-    pub(crate) const ENCODED_INDEX_RECORD_SIZE: u64 = 0xC;
-
-    pub(crate) fn serialized_size(&self, version: VersionMajor) -> u64 {
+    pub(crate) fn serialized_size(&self, version: Version) -> u64 {
         if version >= VersionMajor::PathHashIndex {
             4 // mount point size
-            + self.mount_point.len() as u64 + 1 // mount point with terminating byte
+            + cstring_serialized_size(&self.mount_point) // mount point with terminating byte
             + 4 // entry count
             + 8 // path hash seed
             + 4 // has path hash index
@@ -35,12 +41,102 @@ impl Index {
             + 4 // has full directory index
             + if self.full_directory_index.is_some() { 8 + 8 + 20 } else { 0 }
             + 4 // encoded entry size
-            + self.records.len() as u64 * Self::ENCODED_INDEX_RECORD_SIZE // encoded records
+            + self.encoded_records_size() // encoded records
             + 4 // file count
+            + self
+                .records
+                .iter()
+                .filter(|r| r.is_deleted)
+                .map(|r| r.serialized_size(version, EntryLocation::Index))
+                .sum::<u64>() // records which cannot be encoded
+        } else {
+            4 // mount point size
+            + cstring_serialized_size(&self.mount_point) // mount point with terminating byte
+            + 4 // entry count
+            + self
+                .entries()
+                .iter()
+                .map(|(path, record)| {
+                    4 + cstring_serialized_size(path)
+                        + record.serialized_size(version, EntryLocation::Index)
+                })
+                .sum::<u64>()
+        }
+    }
+
+    fn encoded_records_size(&self) -> u64 {
+        self.records
+            .iter()
+            .filter(|r| !r.is_deleted)
+            .map(|r| r.serialized_size(Version::V10, EntryLocation::Index))
+            .sum()
+    }
+
+    /// Looks up the record of `path`, relative to the mount point, through the full directory
+    /// index, whose entries point into the encoded records.
+    pub(crate) fn find_record(&self, path: &str) -> Option<&Record> {
+        let (directory, filename) = match path.rfind('/').map(|i| i + 1) {
+            Some(i) => path.split_at(i),
+            None => ("/", path),
+        };
+        let location = *self
+            .full_directory_index
+            .as_ref()?
+            .0
+            .get(directory)?
+            .get(filename)?;
+
+        Some(&self.records[*self.record_positions.get(&location)?])
+    }
+
+    /// Every path in the full directory index, relative to the mount point, with its record, in
+    /// the order of the records.
+    pub(crate) fn entries(&self) -> Vec<(String, &Record)> {
+        let mut paths = vec![None; self.records.len()];
+        for (directory, files) in self.full_directory_index.iter().flat_map(|fdi| &fdi.0) {
+            for (filename, encoded_offset) in files {
+                if let Some(&i) = self.record_positions.get(encoded_offset) {
+                    paths[i] = Some(match directory.as_str() {
+                        "/" => filename.clone(),
+                        _ => format!("{}{}", directory, filename),
+                    });
+                }
+            }
+        }
+        paths
+            .into_iter()
+            .zip(&self.records)
+            .filter_map(|(path, record)| Some((path?, record)))
+            .collect()
+    }
+}
+
+/// Offset of each record in the encoded record section of a [`VersionMajor::PathHashIndex`]
+/// index, which is what the full directory index and path hash index point at. Records which
+/// cannot be encoded are located by their negated position, counting from one, among the rest.
+pub(crate) fn encoded_record_offsets(records: &[Record]) -> Vec<u32> {
+    let mut offsets = Vec::with_capacity(records.len());
+    let (mut offset, mut unencoded) = (0, 0);
+    for record in records {
+        if record.is_deleted {
+            unencoded += 1;
+            offsets.push(-unencoded as u32);
         } else {
-            todo!()
+            offsets.push(offset);
+            offset += record.serialized_size(Version::V10, EntryLocation::Index) as u32;
         }
     }
+    offsets
+}
+
+/// Position of each record by its [encoded offset](encoded_record_offsets), which is how the full
+/// directory index refers to it.
+pub(crate) fn record_positions(records: &[Record]) -> HashMap<u32, usize> {
+    encoded_record_offsets(records)
+        .into_iter()
+        .enumerate()
+        .map(|(i, offset)| (offset, i))
+        .collect()
 }
 
 /// Reading an [`Index`] requires a reader to the full file stream because the offsets for
@@ -49,7 +145,7 @@ pub(crate) fn read_index<R: Read + Seek>(
     pak_reader: &mut R,
     index_offset: u64,
     index_size: u64,
-    version: VersionMajor,
+    version: Version,
     is_index_encrypted: bool,
     key: Option<aes::Aes256Dec>,
 ) -> Result<Index, UnrealpakError> {
@@ -62,6 +158,37 @@ pub(crate) fn read_index<R: Read + Seek>(
 
     let mount_point = index_reader.read_cstring()?;
     let record_count = index_reader.read_u32::<LE>()?;
+
+    if version < VersionMajor::PathHashIndex {
+        // Legacy indexes list every path along with its record. They are indexed by a full
+        // directory index of their own, so that entries are looked up alike in every version.
+        let mut paths = Vec::with_capacity(record_count as usize);
+        let mut records = Vec::with_capacity(record_count as usize);
+        for _ in 0..record_count {
+            paths.push(index_reader.read_cstring()?);
+            records.push(read_record(
+                &mut index_reader,
+                version,
+                EntryLocation::Index,
+            )?);
+        }
+        let full_directory_index = FullDirectoryIndex::from_paths(
+            paths
+                .iter()
+                .map(String::as_str)
+                .zip(encoded_record_offsets(&records)),
+        );
+        return Ok(Index {
+            mount_point,
+            record_count,
+            path_hash_seed: None,
+            path_hash_index: None,
+            full_directory_index: Some(full_directory_index),
+            record_positions: record_positions(&records),
+            records,
+        });
+    }
+
     let path_hash_seed = if version >= VersionMajor::PathHashIndex {
         Some(index_reader.read_u64::<LE>()?)
     } else {
@@ -120,10 +247,22 @@ pub(crate) fn read_index<R: Read + Seek>(
         None
     };
 
-    let _record_info_size = index_reader.read_u32::<LE>()?;
+    let encoded_records_size = index_reader.read_u32::<LE>()? as u64;
+    let encoded_records_end = index_reader.position() + encoded_records_size;
     let mut records = vec![];
-    for _ in 0..record_count {
-        records.push(read_record(&mut index_reader, version)?);
+    while index_reader.position() < encoded_records_end {
+        records.push(read_record(
+            &mut index_reader,
+            version,
+            EntryLocation::Index,
+        )?);
+    }
+    for _ in 0..index_reader.read_u32::<LE>()? {
+        records.push(read_record(
+            &mut index_reader,
+            version,
+            EntryLocation::Data,
+        )?);
     }
 
     Ok(Index {
@@ -132,87 +271,142 @@ pub(crate) fn read_index<R: Read + Seek>(
         path_hash_seed,
         path_hash_index,
         full_directory_index,
+        record_positions: record_positions(&records),
         records,
     })
 }
 
-pub(crate) fn write_index<W: Write + Seek>(
+/// Writes the index followed by its path hash index and full directory index, if the version has
+/// them, returning the hash and size of the index itself which go into the
+/// [`Footer`](crate::footer::Footer). With a `key`, each of them is padded and encrypted like
+/// UnrealPak does, and hashed before being encrypted.
+pub(crate) fn write_index<W: Write>(
     writer: &mut W,
     index: &Index,
     offset: u64,
-    version: VersionMajor,
-) -> Result<(), UnrealpakError> {
-    // TODO: handle encryptindex
-    writer.write_cstring(&index.mount_point)?;
-    writer.write_u32::<LE>(index.record_count)?;
+    version: Version,
+    key: Option<&Aes256Enc>,
+) -> Result<(Hash, u64), UnrealpakError> {
+    let mut index_buf = vec![];
+    let mut index_writer = Cursor::new(&mut index_buf);
+    index_writer.write_cstring(&index.mount_point)?;
+    index_writer.write_u32::<LE>(index.record_count)?;
 
     if version < VersionMajor::PathHashIndex {
-        // TODO: determine (version < 10)'s IndexRecord[N]
-        todo!();
-        return Ok(());
+        let entries = index.entries();
+        if entries.len() != index.records.len() {
+            return Err(UnrealpakError::ValidationError(
+                "index record without a path",
+            ));
+        }
+        for (path, record) in entries {
+            index_writer.write_cstring(&path)?;
+            write_record(&mut index_writer, version, record, EntryLocation::Index)?;
+        }
+        return write_padded(writer, index_buf, key);
     }
 
-    writer.write_u64::<LE>(index.path_hash_seed.unwrap())?;
+    index_writer.write_u64::<LE>(index.path_hash_seed.unwrap())?;
 
     let mut phi_buf = vec![];
     if let Some(phi) = &index.path_hash_index {
         let mut phi_writer = Cursor::new(&mut phi_buf);
         write_path_hash_index(&mut phi_writer, phi)?;
+        pad(&mut phi_buf, key);
     }
 
     let mut fdi_buf = vec![];
     if let Some(fdi) = &index.full_directory_index {
         let mut fdi_writer = Cursor::new(&mut fdi_buf);
         write_full_directory_index(&mut fdi_writer, fdi)?;
+        pad(&mut fdi_buf, key);
     }
 
-    let records_size = if index.record_count > 0 {
-        assert!(!index.records.is_empty());
-        let mut size = 0;
-        for r in &index.records {
-            size += Index::ENCODED_INDEX_RECORD_SIZE;
-        }
-        size
-    } else {
-        0
-    };
-
-    dbg!(records_size);
+    let records_size = index.encoded_records_size();
 
-    dbg!(index.serialized_size(version));
-    let phi_offset = offset + index.serialized_size(version);
+    let phi_offset = offset + padded_size(index.serialized_size(version), key);
     let fdi_offset = phi_offset + phi_buf.len() as u64;
-    eprintln!("phi_offset = 0x{:X?}", phi_offset);
-    dbg!(phi_buf.len());
-    eprintln!("fdi_offset = 0x{:X?}", fdi_offset);
+    debug!("phi_offset = 0x{:X?}", phi_offset);
+    debug!("fdi_offset = 0x{:X?}", fdi_offset);
 
-    if let Some(phi) = &index.path_hash_index {
-        writer.write_u32::<LE>(1)?;
-        writer.write_u64::<LE>(phi_offset)?;
-        writer.write_u64::<LE>(phi.serialized_size())?;
+    if index.path_hash_index.is_some() {
+        index_writer.write_u32::<LE>(1)?;
+        index_writer.write_u64::<LE>(phi_offset)?;
+        index_writer.write_u64::<LE>(phi_buf.len() as u64)?;
         let path_hash_index_hash = sha1_hash(&phi_buf[..]);
-        writer.write_all(&path_hash_index_hash)?;
+        index_writer.write_all(&path_hash_index_hash)?;
+    } else {
+        index_writer.write_u32::<LE>(0)?;
     }
 
-    if let Some(fdi) = &index.full_directory_index {
-        writer.write_u32::<LE>(1)?;
-        writer.write_u64::<LE>(fdi_offset)?;
-        writer.write_u64::<LE>(fdi.serialized_size())?;
+    if index.full_directory_index.is_some() {
+        index_writer.write_u32::<LE>(1)?;
+        index_writer.write_u64::<LE>(fdi_offset)?;
+        index_writer.write_u64::<LE>(fdi_buf.len() as u64)?;
         let full_directory_index_hash = sha1_hash(&fdi_buf[..]);
-        writer.write_all(&full_directory_index_hash)?;
+        index_writer.write_all(&full_directory_index_hash)?;
+    } else {
+        index_writer.write_u32::<LE>(0)?;
+    }
+
+    index_writer.write_u32::<LE>(records_size as u32)?;
+
+    let (unencoded, encoded): (Vec<_>, Vec<_>) = index.records.iter().partition(|r| r.is_deleted);
+    for rec in encoded {
+        write_record(
+            &mut index_writer,
+            version,
+            rec,
+            crate::record::EntryLocation::Index,
+        )?;
+    }
+    index_writer.write_u32::<LE>(unencoded.len() as u32)?;
+    for rec in unencoded {
+        write_record(&mut index_writer, version, rec, EntryLocation::Index)?;
     }
 
-    writer.write_u32::<LE>(records_size as u32)?;
+    let index_hash = write_padded(writer, index_buf, key)?;
 
-    for rec in &index.records {
-        write_record(writer, version, rec, crate::record::EntryLocation::Index)?;
+    for mut buf in [phi_buf, fdi_buf] {
+        if let Some(key) = key {
+            encrypt(key, &mut buf);
+        }
+        writer.write_all(&buf)?;
     }
-    writer.write_u32::<LE>(0)?; // file_count?
 
-    writer.write_all(&phi_buf[..])?;
-    writer.write_all(&fdi_buf[..])?;
+    Ok(index_hash)
+}
 
-    Ok(())
+/// Pads `data` with its own start when it is about to be encrypted with `key`.
+fn pad(data: &mut Vec<u8>, key: Option<&Aes256Enc>) {
+    if key.is_some() {
+        let head = data[..data.len().min(Aes256Enc::block_size())].to_vec();
+        pad_with_repeated(data, &head);
+    }
+}
+
+fn padded_size(size: u64, key: Option<&Aes256Enc>) -> u64 {
+    let block_size = Aes256Enc::block_size() as u64;
+    match key {
+        Some(_) => size.div_ceil(block_size) * block_size,
+        None => size,
+    }
+}
+
+/// Pads, hashes and encrypts `data` with `key`, if any, and writes it out, returning its hash and
+/// the number of bytes written.
+fn write_padded<W: Write>(
+    writer: &mut W,
+    mut data: Vec<u8>,
+    key: Option<&Aes256Enc>,
+) -> Result<(Hash, u64), UnrealpakError> {
+    pad(&mut data, key);
+    let hash = Hash(sha1_hash(&data));
+    if let Some(key) = key {
+        encrypt(key, &mut data);
+    }
+    writer.write_all(&data)?;
+    Ok((hash, data.len() as u64))
 }
 
 fn sha1_hash(data: &[u8]) -> [u8; 20] {
@@ -232,15 +426,7 @@ mod tests {
     fn test_read_index_pack_v11() {
         let mut pack_v11 = include_bytes!("../tests/packs/pack_v11.pak");
         let mut pak_reader = Cursor::new(&mut pack_v11);
-        let index = read_index(
-            &mut pak_reader,
-            0x34F7,
-            0xAD,
-            VersionMajor::Fnv64BugFix,
-            false,
-            None,
-        )
-        .unwrap();
+        let index = read_index(&mut pak_reader, 0x34F7, 0xAD, Version::V11, false, None).unwrap();
 
         assert_eq!(index.mount_point, "../mount/point/root/".to_owned());
         assert_eq!(index.record_count, 4);
@@ -302,6 +488,7 @@ mod tests {
                 is_encrypted: Some(false),
                 compression_block_size: Some(0),
                 hash: None,
+                is_deleted: false,
             },
             Record {
                 offset: 649,
@@ -313,6 +500,7 @@ mod tests {
                 is_encrypted: Some(false),
                 compression_block_size: Some(0),
                 hash: None,
+                is_deleted: false,
             },
             Record {
                 offset: 10959,
@@ -324,6 +512,7 @@ mod tests {
                 is_encrypted: Some(false),
                 compression_block_size: Some(0),
                 hash: None,
+                is_deleted: false,
             },
             Record {
                 offset: 11458,
@@ -335,6 +524,7 @@ mod tests {
                 is_encrypted: Some(false),
                 compression_block_size: Some(0),
                 hash: None,
+                is_deleted: false,
             },
         ];
 
@@ -394,6 +584,7 @@ mod tests {
                     is_encrypted: Some(false),
                     compression_block_size: Some(0),
                     hash: None,
+                    is_deleted: false,
                 },
                 Record {
                     offset: 649,
@@ -405,6 +596,7 @@ mod tests {
                     is_encrypted: Some(false),
                     compression_block_size: Some(0),
                     hash: None,
+                    is_deleted: false,
                 },
                 Record {
                     offset: 10959,
@@ -416,6 +608,7 @@ mod tests {
                     is_encrypted: Some(false),
                     compression_block_size: Some(0),
                     hash: None,
+                    is_deleted: false,
                 },
                 Record {
                     offset: 11458,
@@ -427,16 +620,19 @@ mod tests {
                     is_encrypted: Some(false),
                     compression_block_size: Some(0),
                     hash: None,
+                    is_deleted: false,
                 },
             ],
+            // Only needed to look records up, not to write them.
+            record_positions: Default::default(),
         };
 
         let expected_bytes = include_bytes!("../tests/packs/pack_v11.pak");
         let mut actual_bytes = vec![0u8; 173];
         let mut writer = Cursor::new(&mut actual_bytes);
         let index_offset = 0x34F7usize;
-        let footer_offset = expected_bytes.len() - VersionMajor::Fnv64BugFix.footer_size() as usize;
-        write_index(&mut writer, &index, 0x34F7, VersionMajor::Fnv64BugFix).unwrap();
+        let footer_offset = expected_bytes.len() - Version::V11.footer_size() as usize;
+        write_index(&mut writer, &index, 0x34F7, Version::V11, None).unwrap();
 
         eprintln!("{:02X?}", &expected_bytes[index_offset..footer_offset]);
         eprintln!("{:02X?}", &actual_bytes[..]);
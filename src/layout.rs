@@ -0,0 +1,126 @@
+//! Analysis of which parts of a pak's data section are referenced by its records.
+
+use crate::pak_writer::stored_size;
+use crate::record::{EntryLocation, Record};
+use crate::version::{Version, VersionMajor};
+use std::ops::Range;
+
+/// Which bytes of a pak's data section, everything before its index, are referenced by records.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DataLayout {
+    /// Ranges covered by record headers and their data, merged where they touch or overlap.
+    pub used: Vec<Range<u64>>,
+    /// Gaps between the used ranges, such as the data of replaced or removed entries.
+    pub unused: Vec<Range<u64>>,
+}
+
+impl DataLayout {
+    /// Lays out `records` over a data section ending at `data_end`. Records sharing data are
+    /// counted once, and delete records, which have no data, not at all.
+    pub(crate) fn new<'a, I>(version: Version, records: I, data_end: u64) -> Self
+    where
+        I: IntoIterator<Item = &'a Record>,
+    {
+        let mut ranges = records
+            .into_iter()
+            .filter(|record| !record.is_deleted)
+            .map(|record| record_range(version, record))
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|range| range.start);
+
+        let mut used: Vec<Range<u64>> = vec![];
+        for range in ranges {
+            match used.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => used.push(range),
+            }
+        }
+
+        let mut unused = vec![];
+        let mut end = 0;
+        for range in used.iter().chain([&(data_end..data_end)]) {
+            if range.start > end {
+                unused.push(end..range.start);
+            }
+            end = end.max(range.end);
+        }
+
+        DataLayout { used, unused }
+    }
+
+    pub fn used_size(&self) -> u64 {
+        self.used.iter().map(|r| r.end - r.start).sum()
+    }
+
+    pub fn unused_size(&self) -> u64 {
+        self.unused.iter().map(|r| r.end - r.start).sum()
+    }
+}
+
+/// The bytes taken by `record`'s inline header and its stored data. Compressed blocks are
+/// accounted for separately, since their ends are recorded rather than derived.
+fn record_range(version: Version, record: &Record) -> Range<u64> {
+    let header_size = record.serialized_size(version, EntryLocation::Data);
+    let mut end = record.offset + header_size + stored_size(record);
+    // Block offsets are relative to the record from v5 onwards.
+    let block_base = match version >= VersionMajor::RelativeChunkOffsets {
+        true => record.offset,
+        false => 0,
+    };
+    for block in record.blocks.iter().flatten() {
+        let block_end = match record.is_encrypted.unwrap_or_default() {
+            true => block_base + block.start + (block.end - block.start).next_multiple_of(16),
+            false => block_base + block.end,
+        };
+        end = end.max(block_end);
+    }
+    record.offset..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pak_reader::PakReader;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_data_layout_of_fixtures() {
+        for pak in [
+            &include_bytes!("../tests/packs/pack_v11.pak")[..],
+            &include_bytes!("../tests/packs/pack_v11_compress.pak")[..],
+            &include_bytes!("../tests/packs/pack_v11_compress_encrypt.pak")[..],
+        ] {
+            let pak = PakReader::read_any(Cursor::new(pak), None).unwrap();
+            let layout = pak.data_layout();
+            assert_eq!(layout.used, vec![0..pak.pak.index_offset]);
+            assert_eq!(layout.unused, vec![]);
+        }
+    }
+
+    #[test]
+    fn test_data_layout_finds_gaps() {
+        let pak = PakReader::read_any(
+            Cursor::new(include_bytes!("../tests/packs/pack_v11.pak")),
+            None,
+        )
+        .unwrap();
+        let entries = pak.pak.index.entries();
+        let record = |path: &str| entries.iter().find(|(p, _)| p == path).unwrap().1;
+        let (png, txt) = (record("test.png"), record("test.txt"));
+        let png_range = record_range(pak.pak.version, png);
+        let txt_range = record_range(pak.pak.version, txt);
+        assert_eq!(png_range.end, txt.offset);
+
+        let layout = DataLayout::new(pak.pak.version, [txt, txt], pak.pak.index_offset);
+        assert_eq!(layout.used, vec![txt_range.clone()]);
+        assert_eq!(
+            layout.unused,
+            vec![0..txt_range.start, txt_range.end..pak.pak.index_offset]
+        );
+        assert_eq!(layout.used_size(), txt_range.end - txt_range.start);
+        assert_eq!(
+            layout.used_size() + layout.unused_size(),
+            pak.pak.index_offset
+        );
+    }
+}
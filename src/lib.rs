@@ -1,19 +1,58 @@
 mod block;
+mod compact;
 mod compression;
+mod conflicts;
+mod convert;
 mod decrypt;
+mod diff;
 mod errors;
 mod ext;
+mod extract;
+mod file_order;
 mod fnv64;
 mod footer;
 mod full_directory_index;
 mod hash;
 mod index;
+mod layout;
+mod merge;
 mod pak;
+mod pak_editor;
 mod pak_reader;
 mod pak_writer;
+mod patch;
 mod path_hash_index;
+mod progress;
 mod record;
+mod response_file;
+mod signing;
+mod split;
 mod strcrc32;
 mod version;
+mod vfs;
+
+pub use compression::{Compression, CompressionLevel, CompressionLevels};
+pub use conflicts::{conflict_report, Conflict, Provider};
+pub use diff::{diff, DiffEntry, EntryChange, MetadataChange, PakDiff};
+pub use errors::UnrealpakError;
+pub use extract::ExtractOptions;
+pub use file_order::FileOrder;
+pub use layout::DataLayout;
+pub use merge::ConflictPolicy;
+pub use pak_editor::PakEditor;
+pub use pak_reader::PakReader;
+pub use pak_writer::{
+    write_pak, Alignment, EntryOptions, EntryRule, PakBuilder, PakWriterOptions, SourcePakId,
+    WriteSummary,
+};
+pub use progress::{Progress, ProgressEvent};
+pub use response_file::{PackInput, PackPlan};
+pub use signing::{
+    read_signature, sign_pak, sign_pak_file, verify_pak, verify_pak_file, write_signature,
+    PakSignature, RsaKey, SigningKeyPair,
+};
+pub use split::{split_directory, split_pak};
+pub use version::{Version, VersionMajor};
+pub use vfs::{pak_order, PakVfs};
 
 pub(crate) const MAGIC: u32 = 0x5A6F12E1;
@@ -0,0 +1,175 @@
+//! Merging several paks into one.
+
+use crate::errors::UnrealpakError;
+use crate::pak_reader::PakReader;
+use crate::pak_writer::{PakBuilder, PakWriterOptions, SourcePakId};
+use crate::response_file::common_directory;
+use crate::version::Version;
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+/// Which entry [`PakBuilder::merge`] keeps when several paks contain the same path.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Later paks override earlier ones, like patch paks do.
+    #[default]
+    LastWins,
+    FirstWins,
+    /// Fails with [`UnrealpakError::DuplicateEntry`].
+    Error,
+}
+
+impl PakBuilder {
+    /// Creates a builder that combines the entries of `paks`, given from lowest to highest
+    /// priority, resolving paths that several of them contain with `conflict_policy`. The merged
+    /// pak is mounted at the longest directory shared by the mount points of `paks`, and entries
+    /// are copied as stored, in the order of the paks and then of their data.
+    ///
+    /// Encrypted entries are re-encrypted with [`PakWriterOptions::encrypt_data`] when their pak
+    /// was opened with a different key, which leaves their compression untouched. They are copied
+    /// as stored if either key is missing.
+    pub fn merge<R, O>(
+        paks: Vec<PakReader<R>>,
+        version: Version,
+        output_pak_path: O,
+        conflict_policy: ConflictPolicy,
+        options: PakWriterOptions,
+    ) -> Result<Self, UnrealpakError>
+    where
+        R: Read + Seek + 'static,
+        O: AsRef<Path>,
+    {
+        let mount_points = paks
+            .iter()
+            .map(|pak| match pak.mount_point() {
+                m if m.is_empty() || m.ends_with('/') => m.to_owned(),
+                m => format!("{m}/"),
+            })
+            .collect::<Vec<_>>();
+        let mount_point = common_directory(mount_points.iter().map(String::as_str));
+        let mut builder = PakBuilder::new(version, mount_point.clone(), output_pak_path, options)?;
+
+        // Winning entries in write order, with their position by path.
+        let mut entries: Vec<Option<(String, SourcePakId, String)>> = vec![];
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (pak, pak_mount_point) in paks.into_iter().zip(mount_points) {
+            let prefix = &pak_mount_point[mount_point.len()..];
+            let mut records = pak
+                .pak
+                .index
+                .entries()
+                .into_iter()
+                .map(|(path, record)| (path, record.offset))
+                .collect::<Vec<_>>();
+            records.sort_by_key(|&(_, offset)| offset);
+
+            let source = builder.add_source_pak(pak)?;
+            for (source_path, _) in records {
+                let path = prefix.to_owned() + &source_path;
+                if let Some(&position) = positions.get(&path) {
+                    match conflict_policy {
+                        ConflictPolicy::LastWins => entries[position] = None,
+                        ConflictPolicy::FirstWins => continue,
+                        ConflictPolicy::Error => return Err(UnrealpakError::DuplicateEntry(path)),
+                    }
+                }
+                positions.insert(path.clone(), entries.len());
+                entries.push(Some((path, source, source_path)));
+            }
+        }
+        for (path, source, source_path) in entries.into_iter().flatten() {
+            builder.copy_entry(path, source, &source_path)?;
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::hash::Hash;
+    use crate::pak_writer::build_test_pak;
+    use crate::record::{read_record, EntryLocation};
+    use sha1::{Digest, Sha1};
+    use std::io::Cursor;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_merge_paks() {
+        init_logger();
+
+        let content = || {
+            build_test_pak(
+                Version::V11,
+                "../../../Game/Content/",
+                &[
+                    ("a.txt", Some("a"), Compression::None),
+                    ("shared.txt", Some("first"), Compression::None),
+                ],
+            )
+        };
+        let game = || {
+            build_test_pak(
+                Version::V11,
+                "../../../Game/",
+                &[
+                    ("Content/shared.txt", Some("second"), Compression::None),
+                    ("Config/b.ini", Some("b"), Compression::None),
+                ],
+            )
+        };
+        let merge_with = |conflict_policy| {
+            let options = PakWriterOptions {
+                deduplicate: true,
+                ..Default::default()
+            };
+            let mut out_bytes = vec![];
+            PakBuilder::merge(
+                vec![content(), game()],
+                Version::V11,
+                "merged.pak",
+                conflict_policy,
+                options,
+            )
+            .and_then(|builder| builder.finish(&mut Cursor::new(&mut out_bytes)))
+            .map(|_| crate::PakReader::read(Cursor::new(out_bytes), Version::V11, None).unwrap())
+        };
+        // Index records of v10+ paks have no hash, unlike the headers before the data.
+        let hash_of_entry = |merged: &crate::PakReader<Cursor<Vec<u8>>>, path: &str| {
+            let offset = merged.pak.index.find_record(path).unwrap().offset;
+            let mut reader = Cursor::new(merged.reader.get_ref());
+            reader.set_position(offset);
+            read_record(&mut reader, Version::V11, EntryLocation::Data)
+                .unwrap()
+                .hash
+        };
+        let hash_of = |contents: &str| Some(Hash(Sha1::digest(contents).into()));
+
+        let merged = merge_with(ConflictPolicy::LastWins).unwrap();
+        assert_eq!(merged.mount_point(), "../../../Game/");
+        assert_eq!(
+            merged.files().collect::<Vec<_>>(),
+            vec!["Config/b.ini", "Content/a.txt", "Content/shared.txt"]
+        );
+        assert_eq!(
+            hash_of_entry(&merged, "Content/shared.txt"),
+            hash_of("second")
+        );
+
+        let merged = merge_with(ConflictPolicy::FirstWins).unwrap();
+        assert_eq!(
+            hash_of_entry(&merged, "Content/shared.txt"),
+            hash_of("first")
+        );
+
+        assert!(matches!(
+            merge_with(ConflictPolicy::Error),
+            Err(UnrealpakError::DuplicateEntry(path)) if path == "Content/shared.txt"
+        ));
+    }
+}
@@ -7,10 +7,14 @@ use crate::errors::UnrealpakError;
 use crate::ext::ReadExt;
 use crate::footer::read_footer;
 use crate::index::{read_index, Index};
-use crate::version::VersionMajor;
+use crate::version::Version;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Pak {
-    pub(crate) version: VersionMajor,
+    pub(crate) version: Version,
+    /// Where the index starts, which is also where the entry data ends.
+    pub(crate) index_offset: u64,
+    pub(crate) is_index_encrypted: bool,
+    pub(crate) encryption_key_guid: u128,
     pub(crate) index: Index,
 }
@@ -0,0 +1,628 @@
+//! In-place editing of existing paks.
+
+use crate::errors::UnrealpakError;
+use crate::pak_reader::PakReader;
+use crate::pak_writer::{
+    normalize_pak_path, write_index_and_footer, PakBuilder, PakWriterOptions, WriteSummary,
+};
+use crate::progress::{NoProgress, Progress};
+use crate::record::Record;
+use crate::version::Version;
+use aes::{Aes256Dec, Aes256Enc};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Adds, replaces, removes and renames entries of a pak without rebuilding it. Changes are staged
+/// and applied by [`PakEditor::commit`], which writes the data of the staged entries over the old
+/// index, followed by a fresh index and footer. The data of existing entries is left untouched,
+/// including that of replaced and removed entries, which is no longer referenced and can be
+/// reclaimed with [`PakBuilder::from_pak`](crate::PakBuilder::from_pak).
+///
+/// Staged entries are written with the [`PakWriterOptions`] the editor was opened with. The new
+/// index is encrypted with [`PakWriterOptions::encrypt_index`], if set, and keeps the encryption
+/// key GUID of the pak.
+pub struct PakEditor {
+    file: fs::File,
+    version: Version,
+    index_offset: u64,
+    /// The index and footer as found on disk, put back if a commit fails.
+    tail: Vec<u8>,
+    mount_point: String,
+    path_hash_seed: u64,
+    index_key: Option<Aes256Enc>,
+    encryption_key_guid: u128,
+    entries: BTreeMap<String, Record>,
+    staged: PakBuilder,
+}
+
+impl PakEditor {
+    /// Opens the pak at `path` for reading and writing. `key` decrypts its index, if encrypted, in
+    /// which case `options` must also encrypt the new index, or this fails with
+    /// [`UnrealpakError::MissingKey`].
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        key: Option<Aes256Dec>,
+        options: PakWriterOptions,
+    ) -> Result<Self, UnrealpakError> {
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let pak = PakReader::read_any(&mut file, key)?.pak;
+        if pak.is_index_encrypted && options.encrypt_index.is_none() {
+            return Err(UnrealpakError::MissingKey);
+        }
+
+        file.seek(SeekFrom::Start(pak.index_offset))?;
+        let mut tail = vec![];
+        file.read_to_end(&mut tail)?;
+
+        let entries = pak
+            .index
+            .entries()
+            .into_iter()
+            .map(|(path, record)| (path, record.clone()))
+            .collect();
+        let mount_point = pak.index.mount_point;
+        let path_hash_seed = pak.index.path_hash_seed.unwrap_or_default();
+
+        Ok(PakEditor {
+            file,
+            version: pak.version,
+            index_offset: pak.index_offset,
+            tail,
+            index_key: options.encrypt_index.clone(),
+            encryption_key_guid: pak.encryption_key_guid,
+            staged: PakBuilder::with_path_hash_seed(
+                pak.version,
+                mount_point.clone(),
+                path_hash_seed,
+                options,
+            )?,
+            mount_point,
+            path_hash_seed,
+            entries,
+        })
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn mount_point(&self) -> &str {
+        &self.mount_point
+    }
+
+    /// Whether the pak has an entry at `pak_path`, counting staged changes.
+    pub fn contains(&self, pak_path: &str) -> bool {
+        let pak_path = normalize_pak_path(pak_path);
+        self.entries.contains_key(&pak_path) || self.staged.contains(&pak_path)
+    }
+
+    /// Stages a new entry, failing with [`UnrealpakError::DuplicateEntry`] if the pak already has
+    /// one at `pak_path`, staged or not.
+    pub fn add_file<P, B>(&mut self, pak_path: P, bytes: B) -> Result<&mut Self, UnrealpakError>
+    where
+        P: Into<String>,
+        B: Into<Vec<u8>>,
+    {
+        let pak_path = self.stage(pak_path.into(), false)?;
+        self.staged.add_file(pak_path, bytes);
+        Ok(self)
+    }
+
+    pub fn add_reader<P, R>(&mut self, pak_path: P, reader: R) -> Result<&mut Self, UnrealpakError>
+    where
+        P: Into<String>,
+        R: Read + 'static,
+    {
+        let pak_path = self.stage(pak_path.into(), false)?;
+        self.staged.add_reader(pak_path, reader);
+        Ok(self)
+    }
+
+    pub fn add_from_disk<P, F>(
+        &mut self,
+        pak_path: P,
+        fs_path: F,
+    ) -> Result<&mut Self, UnrealpakError>
+    where
+        P: Into<String>,
+        F: AsRef<Path>,
+    {
+        let pak_path = self.stage(pak_path.into(), false)?;
+        self.staged.add_from_disk(pak_path, fs_path);
+        Ok(self)
+    }
+
+    /// Stages new data for an existing entry, failing with [`UnrealpakError::EntryNotFound`] if
+    /// the pak has no entry at `pak_path`, or [`UnrealpakError::DuplicateEntry`] if new data is
+    /// already staged for it.
+    pub fn replace_file<P, B>(&mut self, pak_path: P, bytes: B) -> Result<&mut Self, UnrealpakError>
+    where
+        P: Into<String>,
+        B: Into<Vec<u8>>,
+    {
+        let pak_path = self.stage(pak_path.into(), true)?;
+        self.staged.add_file(pak_path, bytes);
+        Ok(self)
+    }
+
+    pub fn replace_reader<P, R>(
+        &mut self,
+        pak_path: P,
+        reader: R,
+    ) -> Result<&mut Self, UnrealpakError>
+    where
+        P: Into<String>,
+        R: Read + 'static,
+    {
+        let pak_path = self.stage(pak_path.into(), true)?;
+        self.staged.add_reader(pak_path, reader);
+        Ok(self)
+    }
+
+    pub fn replace_from_disk<P, F>(
+        &mut self,
+        pak_path: P,
+        fs_path: F,
+    ) -> Result<&mut Self, UnrealpakError>
+    where
+        P: Into<String>,
+        F: AsRef<Path>,
+    {
+        let pak_path = self.stage(pak_path.into(), true)?;
+        self.staged.add_from_disk(pak_path, fs_path);
+        Ok(self)
+    }
+
+    /// Removes the entry at `pak_path` from the index, along with any data staged for it. Its
+    /// stored data stays where it is.
+    pub fn remove(&mut self, pak_path: &str) -> Result<&mut Self, UnrealpakError> {
+        let pak_path = normalize_pak_path(pak_path);
+        let removed = self.entries.remove(&pak_path).is_some();
+        if !(self.staged.remove(&pak_path) || removed) {
+            return Err(UnrealpakError::EntryNotFound(pak_path));
+        }
+        Ok(self)
+    }
+
+    /// Moves the entry at `from` to `to`, along with any data staged for it. Only the index
+    /// changes; fails with [`UnrealpakError::DuplicateEntry`] if `to` is already taken.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<&mut Self, UnrealpakError> {
+        let (from, to) = (normalize_pak_path(from), normalize_pak_path(to));
+        if !self.contains(&from) {
+            return Err(UnrealpakError::EntryNotFound(from));
+        }
+        if self.contains(&to) {
+            return Err(UnrealpakError::DuplicateEntry(to));
+        }
+        if let Some(record) = self.entries.remove(&from) {
+            self.entries.insert(to.clone(), record);
+        }
+        self.staged.rename(&from, &to);
+        Ok(self)
+    }
+
+    fn stage(&self, pak_path: String, replace: bool) -> Result<String, UnrealpakError> {
+        let pak_path = normalize_pak_path(&pak_path);
+        if self.staged.contains(&pak_path) {
+            return Err(UnrealpakError::DuplicateEntry(pak_path));
+        }
+        match (self.entries.contains_key(&pak_path), replace) {
+            (true, false) => Err(UnrealpakError::DuplicateEntry(pak_path)),
+            (false, true) => Err(UnrealpakError::EntryNotFound(pak_path)),
+            _ => Ok(pak_path),
+        }
+    }
+
+    /// Applies the staged changes. The summary only counts the staged entries.
+    pub fn commit(self) -> Result<WriteSummary, UnrealpakError> {
+        self.commit_with_progress(&mut NoProgress)
+    }
+
+    /// Like [`PakEditor::commit`], reporting each staged entry's data to `progress` as it is
+    /// written. If the commit fails or is cancelled, the pak is restored to how it was opened.
+    pub fn commit_with_progress(
+        self,
+        progress: &mut dyn Progress,
+    ) -> Result<WriteSummary, UnrealpakError> {
+        let PakEditor {
+            mut file,
+            version,
+            index_offset,
+            tail,
+            mount_point,
+            path_hash_seed,
+            index_key,
+            encryption_key_guid,
+            mut entries,
+            staged,
+        } = self;
+
+        let write = |file: &mut fs::File| {
+            file.seek(SeekFrom::Start(index_offset))?;
+            let mut writer = BufWriter::new(&mut *file);
+            let (written, summary) = staged.write_entries(&mut writer, progress)?;
+            entries.extend(written);
+            write_index_and_footer(
+                &mut writer,
+                version,
+                mount_point,
+                path_hash_seed,
+                entries.into_iter().collect(),
+                index_key.as_ref(),
+                encryption_key_guid,
+            )?;
+            let end = writer.stream_position()?;
+            writer.flush()?;
+            drop(writer);
+            file.set_len(end)?;
+            file.sync_all()?;
+            Ok(summary)
+        };
+        write(&mut file).inspect_err(|_| {
+            let _ = file
+                .seek(SeekFrom::Start(index_offset))
+                .and_then(|_| file.write_all(&tail))
+                .and_then(|_| file.set_len(index_offset + tail.len() as u64));
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::progress::ProgressEvent;
+    use crate::record::{header_size, read_record, EntryLocation};
+    use std::io::Cursor;
+    use std::ops::ControlFlow;
+    use std::path::PathBuf;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = Path::new("./target").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn copy_fixture(dir: &str, name: &str) -> PathBuf {
+        let path = test_dir(dir).join(name);
+        fs::copy(Path::new("./tests/packs").join(name), &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_pak_editor_appends_to_pack_v11() {
+        let path = test_dir("test_pak_editor_appends").join("pack_v11.pak");
+
+        // pack_v11 without its last entry, which the editor then appends.
+        let mut builder = PakBuilder::new(
+            Version::V11,
+            "../mount/point/root/",
+            "/home/truman/projects/drg-modding/tools/unpak/tests/packs/pack_v11.pak",
+            Default::default(),
+        )
+        .unwrap();
+        builder
+            .add_from_disk(
+                "directory/nested.txt",
+                "./tests/pack/root/directory/nested.txt",
+            )
+            .add_from_disk("test.png", "./tests/pack/root/test.png")
+            .add_from_disk("test.txt", "./tests/pack/root/test.txt");
+        builder
+            .finish(&mut fs::File::create(&path).unwrap())
+            .unwrap();
+
+        let mut editor = PakEditor::open(&path, None, Default::default()).unwrap();
+        assert!(editor.contains("test.txt"));
+        assert!(!editor.contains("zeros.bin"));
+        editor
+            .add_from_disk("zeros.bin", "./tests/pack/root/zeros.bin")
+            .unwrap();
+        let summary = editor.commit().unwrap();
+        assert_eq!(summary.entry_count, 1);
+
+        let v11_pak = include_bytes!("../tests/packs/pack_v11.pak");
+        assert_eq!(&fs::read(&path).unwrap()[..], &v11_pak[..]);
+    }
+
+    #[test]
+    fn test_pak_editor_replaces_entry() {
+        let path = copy_fixture("test_pak_editor_replaces", "pack_v11.pak");
+        let original = fs::read(&path).unwrap();
+        let original_pak = PakReader::read_any(Cursor::new(&original), None).unwrap();
+
+        let mut editor = PakEditor::open(&path, None, Default::default()).unwrap();
+        let index_offset = editor.index_offset;
+        editor
+            .replace_file("test.txt", "replaced")
+            .unwrap()
+            .add_file("directory/added.txt", "added")
+            .unwrap();
+        editor.commit().unwrap();
+
+        let edited = fs::read(&path).unwrap();
+        assert_eq!(
+            &edited[..index_offset as usize],
+            &original[..index_offset as usize]
+        );
+
+        let pak = PakReader::read_any(fs::File::open(&path).unwrap(), None).unwrap();
+        assert_eq!(
+            pak.files().collect::<Vec<_>>(),
+            vec![
+                "test.png",
+                "test.txt",
+                "zeros.bin",
+                "directory/added.txt",
+                "directory/nested.txt"
+            ]
+        );
+        for path in ["test.png", "zeros.bin", "directory/nested.txt"] {
+            assert_eq!(
+                pak.pak.index.find_record(path),
+                original_pak.pak.index.find_record(path)
+            );
+        }
+        for (path, contents) in [("test.txt", "replaced"), ("directory/added.txt", "added")] {
+            let record = pak.pak.index.find_record(path).unwrap();
+            assert!(record.offset >= index_offset);
+            let start = (record.offset + header_size(Version::V11, Compression::None, 0)) as usize;
+            assert_eq!(&edited[start..start + contents.len()], contents.as_bytes());
+
+            let mut data = Cursor::new(&edited[record.offset as usize..]);
+            let data_record = read_record(&mut data, pak.pak.version, EntryLocation::Data).unwrap();
+            assert_eq!(data_record.uncompressed_size, contents.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_pak_editor_edits_legacy_versions() {
+        for (name, version) in [("pack_v8b.pak", Version::V8B), ("pack_v9.pak", Version::V9)] {
+            let path = copy_fixture(&format!("test_pak_editor_legacy_{version:?}"), name);
+            let mut editor = PakEditor::open(&path, None, Default::default()).unwrap();
+            assert_eq!(editor.version(), version);
+            editor
+                .replace_file("test.txt", "replaced")
+                .unwrap()
+                .add_file("directory/added.txt", "added")
+                .unwrap();
+            editor.commit().unwrap();
+
+            let mut pak = PakReader::read_any(fs::File::open(&path).unwrap(), None).unwrap();
+            assert_eq!(pak.pak.version, version);
+            let mut read = |path: &str| {
+                let mut data = vec![];
+                pak.read_file(path, &mut data).unwrap();
+                data
+            };
+            assert_eq!(read("test.txt"), b"replaced");
+            assert_eq!(read("directory/added.txt"), b"added");
+            assert_eq!(
+                read("directory/nested.txt"),
+                fs::read("./tests/pack/root/directory/nested.txt").unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_pak_editor_keeps_index_encrypted() {
+        use aes::cipher::KeyInit;
+        use base64::Engine;
+
+        let key = base64::engine::general_purpose::STANDARD
+            .decode("lNJbw660IOC+kU7cnVQ1oeqrXyhk4J6UAZrCBbcnp94=")
+            .unwrap();
+        let dec_key = || Some(Aes256Dec::new_from_slice(&key).unwrap());
+        let path = copy_fixture(
+            "test_pak_editor_keeps_index_encrypted",
+            "pack_v11_encryptindex.pak",
+        );
+        let original = PakReader::read_any(fs::File::open(&path).unwrap(), dec_key()).unwrap();
+
+        // Committing without a key would drop the encryption of the index.
+        assert!(matches!(
+            PakEditor::open(&path, dec_key(), Default::default()),
+            Err(UnrealpakError::MissingKey)
+        ));
+
+        let options = PakWriterOptions {
+            encrypt_index: Some(Aes256Enc::new_from_slice(&key).unwrap()),
+            ..Default::default()
+        };
+        let mut editor = PakEditor::open(&path, dec_key(), options).unwrap();
+        editor.add_file("added.txt", "added").unwrap();
+        editor.commit().unwrap();
+
+        assert!(PakReader::read_any(fs::File::open(&path).unwrap(), None).is_err());
+        let mut pak = PakReader::read_any(fs::File::open(&path).unwrap(), dec_key()).unwrap();
+        assert!(pak.pak.is_index_encrypted);
+        assert_eq!(
+            pak.pak.encryption_key_guid,
+            original.pak.encryption_key_guid
+        );
+        let mut data = vec![];
+        pak.read_file("added.txt", &mut data).unwrap();
+        assert_eq!(data, b"added");
+    }
+
+    #[test]
+    fn test_pak_editor_checks_entry_existence() {
+        let path = copy_fixture("test_pak_editor_checks", "pack_v11.pak");
+        let mut editor = PakEditor::open(&path, None, Default::default()).unwrap();
+        assert!(matches!(
+            editor.add_file("test.txt", "duplicate"),
+            Err(UnrealpakError::DuplicateEntry(p)) if p == "test.txt"
+        ));
+        assert!(matches!(
+            editor.replace_file("/directory\\missing.txt", "missing"),
+            Err(UnrealpakError::EntryNotFound(p)) if p == "directory/missing.txt"
+        ));
+    }
+
+    #[test]
+    fn test_pak_editor_removes_and_renames_entries() {
+        let path = copy_fixture("test_pak_editor_removes", "pack_v11.pak");
+        let original = fs::read(&path).unwrap();
+        let original_pak = PakReader::read_any(Cursor::new(&original), None).unwrap();
+
+        let mut editor = PakEditor::open(&path, None, Default::default()).unwrap();
+        let index_offset = editor.index_offset;
+        editor
+            .remove("zeros.bin")
+            .unwrap()
+            .rename("directory/nested.txt", "moved/nested.txt")
+            .unwrap()
+            .add_file("staged.txt", "staged")
+            .unwrap()
+            .rename("staged.txt", "directory/staged.txt")
+            .unwrap()
+            .add_file("dropped.txt", "dropped")
+            .unwrap()
+            .remove("dropped.txt")
+            .unwrap();
+        assert!(!editor.contains("zeros.bin"));
+        assert!(editor.contains("moved/nested.txt"));
+        assert!(matches!(
+            editor.rename("test.txt", "test.png"),
+            Err(UnrealpakError::DuplicateEntry(p)) if p == "test.png"
+        ));
+        assert!(matches!(
+            editor.remove("zeros.bin"),
+            Err(UnrealpakError::EntryNotFound(p)) if p == "zeros.bin"
+        ));
+        assert_eq!(editor.commit().unwrap().entry_count, 1);
+
+        let edited = fs::read(&path).unwrap();
+        assert_eq!(
+            &edited[..index_offset as usize],
+            &original[..index_offset as usize]
+        );
+        let pak = PakReader::read_any(Cursor::new(&edited), None).unwrap();
+        assert_eq!(
+            pak.files().collect::<Vec<_>>(),
+            vec![
+                "test.png",
+                "test.txt",
+                "directory/staged.txt",
+                "moved/nested.txt"
+            ]
+        );
+        assert_eq!(
+            pak.pak.index.find_record("moved/nested.txt"),
+            original_pak.pak.index.find_record("directory/nested.txt")
+        );
+        let record = pak.pak.index.find_record("directory/staged.txt").unwrap();
+        assert_eq!(record.offset, index_offset);
+    }
+
+    #[test]
+    fn test_pak_editor_removes_and_renames_legacy_entries() {
+        for (name, version) in [
+            ("pack_v5_compress.pak", Version::V5),
+            ("pack_v8a.pak", Version::V8A),
+        ] {
+            let path = copy_fixture(&format!("test_pak_editor_removes_{version:?}"), name);
+            let original_pak = PakReader::read_any(fs::File::open(&path).unwrap(), None).unwrap();
+
+            let mut editor = PakEditor::open(&path, None, Default::default()).unwrap();
+            editor
+                .remove("zeros.bin")
+                .unwrap()
+                .rename("directory/nested.txt", "moved/nested.txt")
+                .unwrap();
+            editor.commit().unwrap();
+
+            let mut pak = PakReader::read_any(fs::File::open(&path).unwrap(), None).unwrap();
+            assert_eq!(pak.pak.version, version);
+            assert_eq!(
+                pak.files().collect::<Vec<_>>(),
+                vec!["test.png", "test.txt", "moved/nested.txt"]
+            );
+            assert_eq!(
+                pak.pak.index.find_record("moved/nested.txt"),
+                original_pak.pak.index.find_record("directory/nested.txt")
+            );
+            let mut data = vec![];
+            pak.read_file("moved/nested.txt", &mut data).unwrap();
+            assert_eq!(
+                data,
+                fs::read("./tests/pack/root/directory/nested.txt").unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_compact_edited_pak() {
+        let path = copy_fixture("test_compact_edited_pak", "pack_v11.pak");
+        let replaced_range = {
+            let pak = PakReader::read_any(fs::File::open(&path).unwrap(), None).unwrap();
+            let record = pak.pak.index.find_record("test.txt").unwrap();
+            let next = pak.pak.index.find_record("zeros.bin").unwrap();
+            record.offset..next.offset
+        };
+        let contents = fs::read("./tests/pack/root/test.txt").unwrap();
+        let mut editor = PakEditor::open(&path, None, Default::default()).unwrap();
+        editor.replace_file("test.txt", contents).unwrap();
+        editor.commit().unwrap();
+
+        let pak = PakReader::read_any(fs::File::open(&path).unwrap(), None).unwrap();
+        let layout = pak.data_layout();
+        assert_eq!(layout.unused, vec![replaced_range.clone()]);
+        assert_eq!(
+            layout.unused_size(),
+            replaced_range.end - replaced_range.start
+        );
+
+        let mut compacted = Cursor::new(vec![]);
+        let options = PakWriterOptions {
+            deduplicate: true,
+            ..Default::default()
+        };
+        PakBuilder::from_pak(pak, options)
+            .unwrap()
+            .finish(&mut compacted)
+            .unwrap();
+
+        let mut builder = PakBuilder::new(
+            Version::V11,
+            "../mount/point/root/",
+            "/home/truman/projects/drg-modding/tools/unpak/tests/packs/pack_v11.pak",
+            Default::default(),
+        )
+        .unwrap();
+        builder
+            .add_from_disk(
+                "directory/nested.txt",
+                "./tests/pack/root/directory/nested.txt",
+            )
+            .add_from_disk("test.png", "./tests/pack/root/test.png")
+            .add_from_disk("zeros.bin", "./tests/pack/root/zeros.bin")
+            .add_from_disk("test.txt", "./tests/pack/root/test.txt");
+        let mut expected = Cursor::new(vec![]);
+        builder.finish(&mut expected).unwrap();
+        assert_eq!(compacted.get_ref(), expected.get_ref());
+
+        let compacted = PakReader::read_any(compacted, None).unwrap();
+        assert_eq!(compacted.data_layout().unused, vec![]);
+    }
+
+    #[test]
+    fn test_pak_editor_cancelled_commit_restores_pak() {
+        let path = copy_fixture("test_pak_editor_cancelled", "pack_v11.pak");
+        let original = fs::read(&path).unwrap();
+
+        let mut editor = PakEditor::open(&path, None, Default::default()).unwrap();
+        editor.add_file("a.bin", vec![1u8; 0x20000]).unwrap();
+        let err = editor
+            .commit_with_progress(&mut |event: &ProgressEvent| match event.bytes {
+                0 => ControlFlow::Continue(()),
+                _ => ControlFlow::Break(()),
+            })
+            .unwrap_err();
+        assert!(matches!(err, UnrealpakError::Cancelled));
+        assert_eq!(fs::read(&path).unwrap(), original);
+    }
+}
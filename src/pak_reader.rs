@@ -1,12 +1,31 @@
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 use aes::Aes256Dec;
+use flate2::read::ZlibDecoder;
 
+use crate::compression::Compression;
+use crate::decrypt::decrypt;
 use crate::errors::UnrealpakError;
+use crate::ext::ReadExt;
 use crate::footer::read_footer;
+use crate::hash::Hash;
 use crate::index::read_index;
+use crate::layout::DataLayout;
 use crate::pak::Pak;
-use crate::version::VersionMajor;
+use crate::pak_writer::COMPRESSION_BLOCK_SIZE;
+use crate::progress::{Progress, ProgressTracker};
+use crate::record::{read_record, EntryLocation};
+use crate::version::{Version, VersionMajor};
+
+/// Joins `mount_point` and an entry path relative to it, adding a `/` if the mount point lacks
+/// one.
+pub(crate) fn full_path(mount_point: &str, path: &str) -> String {
+    if mount_point.is_empty() || mount_point.ends_with('/') {
+        format!("{}{}", mount_point, path)
+    } else {
+        format!("{}/{}", mount_point, path)
+    }
+}
 
 #[derive(Debug)]
 pub struct PakReader<R> {
@@ -21,7 +40,7 @@ where
 {
     pub fn read(
         mut reader: R,
-        version: VersionMajor,
+        version: Version,
         key: Option<Aes256Dec>,
     ) -> Result<Self, UnrealpakError> {
         let pak = {
@@ -40,7 +59,13 @@ where
                 key.clone(),
             )?;
 
-            Pak { version, index }
+            Pak {
+                version,
+                index_offset: footer.index_offset,
+                is_index_encrypted: footer.is_index_encrypted.unwrap_or(false),
+                encryption_key_guid: footer.encryption_key_guid.unwrap_or_default(),
+                index,
+            }
         };
 
         Ok(PakReader { pak, reader, key })
@@ -48,7 +73,7 @@ where
 
     pub fn read_any(mut reader: R, key: Option<Aes256Dec>) -> Result<Self, UnrealpakError> {
         // Try parsing from newest versions first.
-        for &v in VersionMajor::iterator().rev() {
+        for &v in Version::iterator().rev() {
             if let Ok(pak) = PakReader::read(&mut reader, v, key.clone()) {
                 return Ok(PakReader {
                     pak: pak.pak,
@@ -60,6 +85,25 @@ where
         Err(UnrealpakError::UnsupportedVersion)
     }
 
+    pub fn mount_point(&self) -> &str {
+        &self.pak.index.mount_point
+    }
+
+    /// Joins the mount point with a path from [`PakReader::files`] to produce the full virtual
+    /// path of the entry, e.g. `../../../Game/Content/Maps/Main.umap`.
+    pub fn full_path(&self, path: &str) -> String {
+        full_path(self.mount_point(), path)
+    }
+
+    /// Which bytes before the index are referenced by the pak's records.
+    pub fn data_layout(&self) -> DataLayout {
+        DataLayout::new(
+            self.pak.version,
+            &self.pak.index.records,
+            self.pak.index_offset,
+        )
+    }
+
     pub fn files(&self) -> impl Iterator<Item = String> {
         let mut fs = vec![];
         let fdi = self.pak.index.full_directory_index.as_ref().unwrap();
@@ -75,6 +119,122 @@ where
         }
         fs.into_iter()
     }
+
+    /// SHA-1 of the stored data of the entry at `path`, or `None` for a delete record. Indexes
+    /// from [`VersionMajor::PathHashIndex`] on leave hashes out, so they are read from the record
+    /// in front of the data.
+    pub(crate) fn stored_hash(&mut self, path: &str) -> Result<Option<Hash>, UnrealpakError> {
+        let record = self
+            .pak
+            .index
+            .find_record(path)
+            .ok_or_else(|| UnrealpakError::EntryNotFound(path.to_owned()))?;
+        if record.is_deleted {
+            return Ok(None);
+        }
+        self.reader.seek(SeekFrom::Start(record.offset))?;
+        Ok(read_record(&mut self.reader, self.pak.version, EntryLocation::Data)?.hash)
+    }
+
+    /// Reads the entry at `path`, relative to the mount point, decrypted and decompressed.
+    pub fn get(&mut self, path: &str) -> Result<Vec<u8>, UnrealpakError> {
+        let mut data = vec![];
+        self.read_file(path, &mut data)?;
+        Ok(data)
+    }
+
+    /// Streams the entry at `path`, relative to the mount point, into `writer` one compression
+    /// block at a time, decrypting it with the key the pak was read with. Delete records are
+    /// reported as [`UnrealpakError::EntryNotFound`].
+    pub fn read_file<W: Write>(
+        &mut self,
+        path: &str,
+        writer: &mut W,
+    ) -> Result<(), UnrealpakError> {
+        let record = self
+            .pak
+            .index
+            .find_record(path)
+            .filter(|record| !record.is_deleted)
+            .ok_or_else(|| UnrealpakError::EntryNotFound(path.to_owned()))?;
+        let (reader, key) = (&mut self.reader, &self.key);
+        let encrypted = record.is_encrypted.unwrap_or_default();
+        let mut read = |offset: u64, size: u64| -> Result<Vec<u8>, UnrealpakError> {
+            reader.seek(SeekFrom::Start(offset))?;
+            if !encrypted {
+                return reader.read_len(size as usize);
+            }
+            let mut data = reader.read_len(size.next_multiple_of(16) as usize)?;
+            decrypt(key, &mut data)?;
+            data.truncate(size as usize);
+            Ok(data)
+        };
+
+        let version = self.pak.version;
+        let written = match record.compression_method {
+            Compression::None => {
+                let data_offset =
+                    record.offset + record.serialized_size(version, EntryLocation::Data);
+                self.reader.seek(SeekFrom::Start(data_offset))?;
+                // Chunks are a multiple of the AES block size, so only the last one is padded.
+                let mut buf = vec![0u8; COMPRESSION_BLOCK_SIZE as usize];
+                let mut remaining = record.uncompressed_size;
+                while remaining > 0 {
+                    let n = remaining.min(COMPRESSION_BLOCK_SIZE) as usize;
+                    let stored = match encrypted {
+                        true => n.next_multiple_of(16),
+                        false => n,
+                    };
+                    self.reader.read_exact(&mut buf[..stored])?;
+                    if encrypted {
+                        decrypt(&self.key, &mut buf[..stored])?;
+                    }
+                    writer.write_all(&buf[..n])?;
+                    remaining -= n as u64;
+                }
+                record.uncompressed_size
+            }
+            Compression::Zlib => {
+                // Block offsets are relative to the record from v5 onwards.
+                let block_base = match version >= VersionMajor::RelativeChunkOffsets {
+                    true => record.offset,
+                    false => 0,
+                };
+                let mut written = 0;
+                for block in record.blocks.iter().flatten() {
+                    let data = read(block_base + block.start, block.end - block.start)?;
+                    written += std::io::copy(&mut ZlibDecoder::new(&data[..]), writer)?;
+                }
+                written
+            }
+            method => return Err(UnrealpakError::UnsupportedCompression(method)),
+        };
+        if written != record.uncompressed_size {
+            return Err(UnrealpakError::ValidationError("entry size"));
+        }
+        Ok(())
+    }
+
+    /// Like [`PakReader::read_file`], reporting the bytes written to `progress`, which can cancel
+    /// the read.
+    pub fn read_file_with_progress<W: Write>(
+        &mut self,
+        path: &str,
+        writer: &mut W,
+        progress: &mut dyn Progress,
+    ) -> Result<(), UnrealpakError> {
+        let size = self
+            .pak
+            .index
+            .find_record(path)
+            .filter(|record| !record.is_deleted)
+            .ok_or_else(|| UnrealpakError::EntryNotFound(path.to_owned()))?
+            .uncompressed_size;
+        let mut tracker = ProgressTracker::new(progress, 1, size);
+        tracker.start_entry(0, path, size)?;
+        let result = self.read_file(path, &mut tracker.writer(writer));
+        result.map_err(|e| tracker.error(e))
+    }
 }
 
 #[cfg(test)]
@@ -89,11 +249,130 @@ mod tests {
     fn test_read_pak_pack_v11() {
         let mut v11_pack = include_bytes!("../tests/packs/pack_v11.pak");
         let reader = Cursor::new(&mut v11_pack);
-        let pak = PakReader::read(reader, VersionMajor::Fnv64BugFix, None).unwrap();
+        let pak = PakReader::read(reader, Version::V11, None).unwrap();
         assert_eq!(
             pak.files().collect::<Vec<_>>(),
             vec!["test.png", "test.txt", "zeros.bin", "directory/nested.txt"]
         );
         assert_eq!(pak.pak.index.mount_point, "../mount/point/root/".to_owned());
     }
+
+    #[test]
+    fn test_full_path_pack_v11() {
+        let mut v11_pack = include_bytes!("../tests/packs/pack_v11.pak");
+        let reader = Cursor::new(&mut v11_pack);
+        let pak = PakReader::read(reader, Version::V11, None).unwrap();
+        assert_eq!(pak.mount_point(), "../mount/point/root/");
+        assert_eq!(
+            pak.files().map(|f| pak.full_path(&f)).collect::<Vec<_>>(),
+            vec![
+                "../mount/point/root/test.png",
+                "../mount/point/root/test.txt",
+                "../mount/point/root/zeros.bin",
+                "../mount/point/root/directory/nested.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_entries_of_every_fixture() {
+        use aes::cipher::KeyInit;
+        use base64::Engine;
+
+        let key = base64::engine::general_purpose::STANDARD
+            .decode("lNJbw660IOC+kU7cnVQ1oeqrXyhk4J6UAZrCBbcnp94=")
+            .unwrap();
+        let key = Aes256Dec::new_from_slice(&key).unwrap();
+        for entry in std::fs::read_dir("./tests/packs").unwrap() {
+            let path = entry.unwrap().path();
+            let file = std::fs::File::open(&path).unwrap();
+            let mut pak = PakReader::read_any(file, Some(key.clone())).unwrap();
+            for file in pak.files().collect::<Vec<_>>() {
+                let expected = std::fs::read(format!("./tests/pack/root/{file}")).unwrap();
+                assert_eq!(pak.get(&file).unwrap(), expected, "{path:?} {file}");
+            }
+        }
+
+        let mut pak = PakReader::read_any(
+            Cursor::new(include_bytes!("../tests/packs/pack_v11_encrypt.pak")),
+            None,
+        )
+        .unwrap();
+        assert!(matches!(
+            pak.get("test.txt"),
+            Err(UnrealpakError::Encrypted)
+        ));
+        assert!(matches!(
+            pak.get("missing.txt"),
+            Err(UnrealpakError::EntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_file_with_progress() {
+        use crate::progress::ProgressEvent;
+        use std::ops::ControlFlow;
+
+        let read = || {
+            let pak = include_bytes!("../tests/packs/pack_v11_compress.pak");
+            PakReader::read(Cursor::new(pak), Version::V11, None).unwrap()
+        };
+        let expected = std::fs::read("./tests/pack/root/zeros.bin").unwrap();
+        let mut events = vec![];
+        let mut progress = |event: &ProgressEvent| {
+            events.push((event.entry_bytes, event.bytes, event.bytes_total));
+            ControlFlow::Continue(())
+        };
+        let mut data = vec![];
+        read()
+            .read_file_with_progress("zeros.bin", &mut data, &mut progress)
+            .unwrap();
+        assert_eq!(data, expected);
+        let size = expected.len() as u64;
+        assert_eq!(events.first(), Some(&(0, 0, size)));
+        assert_eq!(events.last(), Some(&(size, size, size)));
+
+        let mut cancel = |event: &ProgressEvent| match event.bytes {
+            0 => ControlFlow::Continue(()),
+            _ => ControlFlow::Break(()),
+        };
+        assert!(matches!(
+            read().read_file_with_progress("zeros.bin", &mut vec![], &mut cancel),
+            Err(UnrealpakError::Cancelled)
+        ));
+        assert!(matches!(
+            read().read_file_with_progress("missing.txt", &mut vec![], &mut cancel),
+            Err(UnrealpakError::EntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_file_streams_uncompressed_entries() {
+        use crate::pak_writer::{PakBuilder, PakWriterOptions};
+        use aes::cipher::KeyInit;
+        use aes::Aes256Enc;
+
+        let key = [7u8; 32];
+        let data = (0..0x20005u32).map(|i| i as u8).collect::<Vec<_>>();
+        let options = PakWriterOptions {
+            compression_method: Compression::None,
+            encrypt_data: Some(Aes256Enc::new_from_slice(&key).unwrap()),
+            ..Default::default()
+        };
+        let mut builder = PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+        builder.add_file("data.bin", data.clone());
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+
+        let key = Aes256Dec::new_from_slice(&key).unwrap();
+        let mut pak = PakReader::read(Cursor::new(out_bytes), Version::V11, Some(key)).unwrap();
+        assert_eq!(pak.get("data.bin").unwrap(), data);
+
+        // Sizes come from the index, so a corrupt one must not be allocated up front.
+        pak.pak.index.records[0].uncompressed_size = 1 << 40;
+        assert!(matches!(
+            pak.get("data.bin"),
+            Err(UnrealpakError::IoError(_))
+        ));
+    }
 }
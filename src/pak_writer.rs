@@ -1,408 +1,2667 @@
 use crate::block::Block;
-use crate::compression::Compression;
+use crate::compression::{Compression, CompressionLevels};
+use crate::decrypt::decrypt;
 use crate::errors::UnrealpakError;
+use crate::file_order::FileOrder;
 use crate::fnv64::fnv64;
 use crate::footer::{write_footer, Footer};
-use crate::full_directory_index::FullDirectoryIndex;
+use crate::full_directory_index::{split_pak_path, FullDirectoryIndex};
 use crate::hash::Hash;
-use crate::index::{write_index, Index};
+use crate::index::{encoded_record_offsets, record_positions, write_index, Index};
+use crate::pak_reader::{full_path, PakReader};
 use crate::path_hash_index::PathHashIndex;
-use crate::record::{write_record, Record};
+use crate::progress::{NoProgress, Progress, ProgressTracker};
+use crate::record::{header_size, read_record, write_record, EntryLocation, Record};
+use crate::response_file::{wildcard_match, PackPlan};
 use crate::strcrc32::strcrc32;
-use crate::version::VersionMajor;
+use crate::version::{Version, VersionMajor};
 use crate::MAGIC;
-use aes::cipher::{BlockSizeUser, KeyInit};
-use aes::Aes256Enc;
+use aes::cipher::BlockSizeUser;
+use aes::{Aes256Dec, Aes256Enc};
 #[cfg(windows)]
 use byteorder::{ByteOrder, LittleEndian};
 use flate2::write::ZlibEncoder;
 use log::{debug, info};
 use sha1::{Digest, Sha1};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io::Cursor;
-use std::io::{Seek, Write};
-use std::path::Path;
+use std::io::{BufReader, BufWriter, Cursor};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct PakWriterOptions {
     pub compression_method: Compression,
-    pub encrypt_data: Option<u128>,
-    pub encrypt_index: Option<u128>,
+    pub compression_levels: CompressionLevels,
+    pub encrypt_data: Option<Aes256Enc>,
+    pub encrypt_index: Option<Aes256Enc>,
+    /// Identifies the key the pak is encrypted with in the footer, from
+    /// [`VersionMajor::EncryptionKeyGuid`] onwards. Zero stands for the default key.
+    pub encryption_key_guid: u128,
+    /// Lays entry data out in load order, as UnrealPak does with `-order=`. Listed entries are
+    /// written first by their order, followed by the rest sorted by path.
+    pub file_order: Option<FileOrder>,
+    /// Padding inserted between records; by default records are packed back-to-back.
+    pub alignment: Option<Alignment>,
+    /// Per-file overrides of the compression method and encryption, keyed by path. Later rules
+    /// take precedence over earlier ones, and the options an entry was added with over all rules.
+    pub rules: Vec<EntryRule>,
+    /// Stores entries uncompressed when compressing them does not make them any smaller. This
+    /// compresses each such entry twice: once to measure it and once to write it.
+    pub skip_incompressible: bool,
+    /// With [`PakWriterOptions::skip_incompressible`], also stores entries uncompressed when
+    /// compressing them saves fewer bytes than this.
+    pub min_compression_savings: u64,
+    /// Writes the data of byte-identical entries once and points the records of every duplicate
+    /// at it. Entries only share data when they are also compressed and encrypted alike.
+    pub deduplicate: bool,
+}
+
+impl PakWriterOptions {
+    /// The options UnrealPak 4.27 writes `-compress` paks with: zlib at
+    /// [`CompressionLevels::UNREALPAK`], storing entries uncompressed unless compressing them
+    /// saves at least 1KiB.
+    pub fn unrealpak() -> Self {
+        PakWriterOptions {
+            compression_method: Compression::Zlib,
+            compression_levels: CompressionLevels::UNREALPAK,
+            skip_incompressible: true,
+            min_compression_savings: 1024,
+            ..Default::default()
+        }
+    }
+
+    /// The options `entry` is written with, from the rules matching its path overridden by the
+    /// options it was added with. Raw entries are copied as stored, whatever the rules say.
+    fn entry_options(&self, entry: &PendingEntry) -> EntryOptions {
+        match entry.source {
+            EntrySource::Raw { .. } | EntrySource::Deleted => EntryOptions::default(),
+            _ => self
+                .rules
+                .iter()
+                .filter(|rule| rule.matches(&entry.pak_path))
+                .fold(EntryOptions::default(), |merged, rule| {
+                    merged.overridden_by(&rule.options)
+                })
+                .overridden_by(&entry.options),
+        }
+    }
+
+    /// Fails with [`UnrealpakError::UnsupportedCompression`] if any entry would be compressed
+    /// with a method that cannot be written.
+    fn check_compression(&self) -> Result<(), UnrealpakError> {
+        check_compression(self.compression_method)?;
+        for method in self
+            .rules
+            .iter()
+            .filter_map(|rule| rule.options.compression_method)
+        {
+            check_compression(method)?;
+        }
+        Ok(())
+    }
+}
+
+/// Only zlib is implemented for writing.
+fn check_compression(method: Compression) -> Result<(), UnrealpakError> {
+    match method {
+        Compression::None | Compression::Zlib => Ok(()),
+        method => Err(UnrealpakError::UnsupportedCompression(method)),
+    }
+}
+
+/// Statistics about a pak written by [`PakBuilder::finish`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteSummary {
+    pub entry_count: usize,
+    /// Entries whose record points at the data of an identical entry written before them.
+    pub deduplicated_entries: usize,
+    /// Size of the records and data that deduplication did not have to write.
+    pub deduplicated_bytes: u64,
+}
+
+/// What makes the stored data of two entries interchangeable.
+#[derive(PartialEq, Eq, Hash)]
+enum ContentKey {
+    Raw {
+        source: usize,
+        offset: u64,
+    },
+    Data {
+        hash: [u8; 20],
+        compression_method: Compression,
+        encrypted: bool,
+    },
+}
+
+/// How records are aligned, mirroring UnrealPak's `-patchpaddingalign=` and
+/// `-alignformemorymapping=` switches. Padding between records is zero filled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alignment {
+    /// A record that would straddle a multiple of the alignment starts on the next one instead,
+    /// so that a changed entry shifts as little of the pak as possible in platform delta patches.
+    /// Compressed entries are compressed into a temporary file first to learn their stored size.
+    Patch(u64),
+    /// The data of memory mapped bulk data, `.m.ubulk` entries stored uncompressed and
+    /// unencrypted, starts on a multiple of the alignment, so that it can be memory mapped in
+    /// place.
+    MemoryMapping(u64),
+}
+
+/// Whether [`Alignment::MemoryMapping`] applies to an entry. Like UnrealPak, only memory mapped
+/// bulk data is aligned, and only if it is stored as is.
+pub(crate) fn memory_mappable(
+    pak_path: &str,
+    compression_method: Compression,
+    encrypted: bool,
+) -> bool {
+    pak_path.to_ascii_lowercase().ends_with(".m.ubulk")
+        && compression_method == Compression::None
+        && !encrypted
+}
+
+impl Alignment {
+    pub(crate) fn padding(
+        self,
+        offset: u64,
+        header_size: u64,
+        stored_size: u64,
+        mappable: bool,
+    ) -> u64 {
+        match self {
+            Alignment::Patch(alignment) if alignment > 1 => {
+                let used = offset % alignment;
+                if used != 0 && used + header_size + stored_size > alignment {
+                    alignment - used
+                } else {
+                    0
+                }
+            }
+            Alignment::MemoryMapping(alignment) if alignment > 1 && mappable => {
+                (alignment - (offset + header_size) % alignment) % alignment
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Uncompressed size of each compression block, which is also the granularity at which entry data
+/// is streamed into the pak.
+pub(crate) const COMPRESSION_BLOCK_SIZE: u64 = 0x10000;
+
+/// Per-entry overrides for [`PakWriterOptions`]. Fields left as `None` fall back to the options
+/// the [`PakBuilder`] was created with.
+#[derive(Debug, Clone, Default)]
+pub struct EntryOptions {
+    pub compression_method: Option<Compression>,
+    /// Whether to encrypt this entry with [`PakWriterOptions::encrypt_data`].
+    pub encrypted: Option<bool>,
+}
+
+impl EntryOptions {
+    fn overridden_by(&self, overrides: &EntryOptions) -> EntryOptions {
+        EntryOptions {
+            compression_method: overrides.compression_method.or(self.compression_method),
+            encrypted: overrides.encrypted.or(self.encrypted),
+        }
+    }
+}
+
+/// Applies [`EntryOptions`] to every entry whose path matches a pattern, like the per-file
+/// compression and encryption settings of a cooked project. Patterns are matched against paths
+/// relative to the mount point, ignoring case, where `*` also matches `/`.
+#[derive(Debug, Clone)]
+pub struct EntryRule {
+    pub pattern: String,
+    pub options: EntryOptions,
+}
+
+impl EntryRule {
+    pub fn glob<P: Into<String>>(pattern: P, options: EntryOptions) -> Self {
+        EntryRule {
+            pattern: pattern.into(),
+            options,
+        }
+    }
+
+    /// Matches files with the given extension in any directory, e.g. `uasset` or `.ini`.
+    pub fn extension(extension: &str, options: EntryOptions) -> Self {
+        EntryRule::glob(format!("*.{}", extension.trim_start_matches('.')), options)
+    }
+
+    fn matches(&self, pak_path: &str) -> bool {
+        wildcard_match(&self.pattern.to_lowercase(), &pak_path.to_lowercase())
+    }
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+enum EntrySource {
+    Bytes(Vec<u8>),
+    Reader(Box<dyn Read>),
+    /// A reader copied into a temporary file, whose length is known.
+    Spooled(fs::File),
+    Disk(PathBuf),
+    /// A record of a source pak, copied as stored.
+    Raw {
+        source: usize,
+        offset: u64,
+        stored_size: u64,
+    },
+    /// A delete record, which has no data.
+    Deleted,
+}
+
+impl EntrySource {
+    /// Copies `record` of the source pak at index `source`, which keeps delete records as such.
+    fn raw(source: usize, record: &Record) -> Self {
+        match record.is_deleted {
+            true => EntrySource::Deleted,
+            false => EntrySource::Raw {
+                source,
+                offset: record.offset,
+                stored_size: stored_size(record),
+            },
+        }
+    }
+
+    /// Opens the source for streaming along with the number of bytes left to read from it.
+    fn open(self) -> Result<(Box<dyn Read>, u64), UnrealpakError> {
+        Ok(match self {
+            EntrySource::Bytes(bytes) => {
+                let len = bytes.len() as u64;
+                (Box::new(Cursor::new(bytes)), len)
+            }
+            EntrySource::Reader(reader) => {
+                let mut source = EntrySource::Reader(reader);
+                source.spool()?;
+                return source.open();
+            }
+            EntrySource::Spooled(file) => {
+                let len = file.metadata()?.len();
+                (Box::new(file), len)
+            }
+            EntrySource::Disk(path) => {
+                let file = fs::File::open(path)?;
+                let len = file.metadata()?.len();
+                (Box::new(file), len)
+            }
+            EntrySource::Raw { .. } => unreachable!("raw entries are copied as stored"),
+            EntrySource::Deleted => unreachable!("delete records have no data"),
+        })
+    }
+
+    /// Number of bytes the source holds, as reported by [`Progress`].
+    fn len(&mut self) -> Result<u64, UnrealpakError> {
+        Ok(match self {
+            EntrySource::Bytes(bytes) => bytes.len() as u64,
+            EntrySource::Reader(_) => {
+                self.spool()?;
+                return self.len();
+            }
+            EntrySource::Spooled(file) => file.metadata()?.len(),
+            EntrySource::Disk(path) => fs::metadata(path)?.len(),
+            EntrySource::Raw { stored_size, .. } => *stored_size,
+            EntrySource::Deleted => 0,
+        })
+    }
+
+    /// Copies the data the source would stream into `sink`, leaving the source ready to be
+    /// opened.
+    fn stream_into(&mut self, sink: &mut dyn Write) -> Result<(), UnrealpakError> {
+        match self {
+            EntrySource::Bytes(bytes) => sink.write_all(bytes)?,
+            EntrySource::Reader(_) => {
+                self.spool()?;
+                self.stream_into(sink)?;
+            }
+            EntrySource::Spooled(file) => {
+                std::io::copy(file, sink)?;
+                file.rewind()?;
+            }
+            EntrySource::Disk(path) => {
+                std::io::copy(&mut fs::File::open(path)?, sink)?;
+            }
+            EntrySource::Raw { .. } | EntrySource::Deleted => {
+                unreachable!("raw entries and delete records are not streamed")
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies a reader into a temporary file, since its length has to be known before its data
+    /// is written and it may have to be read more than once.
+    fn spool(&mut self) -> Result<(), UnrealpakError> {
+        if let EntrySource::Reader(reader) = self {
+            let mut file = tempfile::tempfile()?;
+            std::io::copy(reader, &mut file)?;
+            file.rewind()?;
+            *self = EntrySource::Spooled(file);
+        }
+        Ok(())
+    }
+
+    fn content_hash(&mut self) -> Result<[u8; 20], UnrealpakError> {
+        let mut hasher = Sha1::new();
+        self.stream_into(&mut hasher)?;
+        Ok(hasher.finalize().into())
+    }
+
+    /// Whether compressing the data with `compression_method` makes it smaller, by at least
+    /// `min_savings` bytes.
+    fn shrinks(
+        &mut self,
+        compression_method: Compression,
+        compression_levels: CompressionLevels,
+        min_savings: u64,
+    ) -> Result<bool, UnrealpakError> {
+        let mut counter = CompressedSizeCounter {
+            compression_method,
+            compression_levels,
+            block: Vec::with_capacity(COMPRESSION_BLOCK_SIZE as usize),
+            uncompressed_size: 0,
+            compressed_size: 0,
+        };
+        self.stream_into(&mut counter)?;
+        counter.flush_block()?;
+        let savings = counter
+            .uncompressed_size
+            .saturating_sub(counter.compressed_size);
+        Ok(savings > 0 && savings >= min_savings)
+    }
+}
+
+/// Measures the size of data compressed one block at a time, as [`write_entry`] would store it.
+struct CompressedSizeCounter {
+    compression_method: Compression,
+    compression_levels: CompressionLevels,
+    block: Vec<u8>,
+    uncompressed_size: u64,
+    compressed_size: u64,
+}
+
+impl CompressedSizeCounter {
+    fn flush_block(&mut self) -> std::io::Result<()> {
+        if !self.block.is_empty() {
+            self.compressed_size += compress_block(
+                self.compression_method,
+                self.compression_levels,
+                &self.block,
+            )
+            .map_err(std::io::Error::other)?
+            .len() as u64;
+            self.block.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Write for CompressedSizeCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf
+            .len()
+            .min(COMPRESSION_BLOCK_SIZE as usize - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        self.uncompressed_size += n as u64;
+        if self.block.len() == COMPRESSION_BLOCK_SIZE as usize {
+            self.flush_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Handle to a pak added with [`PakBuilder::add_source_pak`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourcePakId(usize);
+
+struct SourcePak {
+    version: Version,
+    index: Index,
+    reader: Box<dyn ReadSeek>,
+    key: Option<Aes256Dec>,
+}
+
+struct PendingEntry {
+    pak_path: String,
+    source: EntrySource,
+    options: EntryOptions,
+}
+
+/// Incrementally collects entries from memory, readers or disk and writes them out as a pak with
+/// [`PakBuilder::finish`]. Entry data is written in the order the entries were added, unless
+/// [`PakWriterOptions::file_order`] is set; the index is always sorted by path.
+pub struct PakBuilder {
+    version: Version,
+    mount_point: String,
+    path_hash_seed: u64,
+    options: PakWriterOptions,
+    entries: Vec<PendingEntry>,
+    sources: Vec<SourcePak>,
+}
+
+impl PakBuilder {
+    /// `output_pak_path` is only used to derive the path hash seed, the same way UnrealPak does.
+    pub fn new<M, O>(
+        version: Version,
+        mount_point: M,
+        output_pak_path: O,
+        options: PakWriterOptions,
+    ) -> Result<Self, UnrealpakError>
+    where
+        M: Into<String>,
+        O: AsRef<Path>,
+    {
+        let output_pak_path = output_pak_path.as_ref();
+        info!("output_pak_path {:?}", output_pak_path);
+        let path_hash_seed = strcrc32(&utf16le_path_to_bytes(output_pak_path)?) as u64;
+
+        PakBuilder::with_path_hash_seed(version, mount_point.into(), path_hash_seed, options)
+    }
+
+    pub(crate) fn with_path_hash_seed(
+        version: Version,
+        mount_point: String,
+        path_hash_seed: u64,
+        options: PakWriterOptions,
+    ) -> Result<Self, UnrealpakError> {
+        options.check_compression()?;
+        Ok(PakBuilder {
+            version,
+            mount_point,
+            path_hash_seed,
+            options,
+            entries: vec![],
+            sources: vec![],
+        })
+    }
+
+    /// Creates a builder with every input of `plan`, mounted at the plan's
+    /// [common mount point](PackPlan::common_mount_point) like UnrealPak does.
+    pub fn from_plan<O: AsRef<Path>>(
+        version: Version,
+        plan: &PackPlan,
+        output_pak_path: O,
+        options: PakWriterOptions,
+    ) -> Result<Self, UnrealpakError> {
+        let mut builder =
+            PakBuilder::new(version, plan.common_mount_point(), output_pak_path, options)?;
+        builder.add_plan(plan)?;
+        Ok(builder)
+    }
+
+    pub fn add_file<P, B>(&mut self, pak_path: P, bytes: B) -> &mut Self
+    where
+        P: Into<String>,
+        B: Into<Vec<u8>>,
+    {
+        self.add_file_with_options(pak_path, bytes, EntryOptions::default())
+    }
+
+    pub fn add_file_with_options<P, B>(
+        &mut self,
+        pak_path: P,
+        bytes: B,
+        options: EntryOptions,
+    ) -> &mut Self
+    where
+        P: Into<String>,
+        B: Into<Vec<u8>>,
+    {
+        self.push(pak_path.into(), EntrySource::Bytes(bytes.into()), options)
+    }
+
+    /// `reader` is copied into a temporary file when the pak is written, so that the record header
+    /// can be laid out before the data is streamed.
+    pub fn add_reader<P, R>(&mut self, pak_path: P, reader: R) -> &mut Self
+    where
+        P: Into<String>,
+        R: Read + 'static,
+    {
+        self.add_reader_with_options(pak_path, reader, EntryOptions::default())
+    }
+
+    pub fn add_reader_with_options<P, R>(
+        &mut self,
+        pak_path: P,
+        reader: R,
+        options: EntryOptions,
+    ) -> &mut Self
+    where
+        P: Into<String>,
+        R: Read + 'static,
+    {
+        self.push(
+            pak_path.into(),
+            EntrySource::Reader(Box::new(reader)),
+            options,
+        )
+    }
+
+    pub fn add_from_disk<P, F>(&mut self, pak_path: P, fs_path: F) -> &mut Self
+    where
+        P: Into<String>,
+        F: AsRef<Path>,
+    {
+        self.add_from_disk_with_options(pak_path, fs_path, EntryOptions::default())
+    }
+
+    pub fn add_from_disk_with_options<P, F>(
+        &mut self,
+        pak_path: P,
+        fs_path: F,
+        options: EntryOptions,
+    ) -> &mut Self
+    where
+        P: Into<String>,
+        F: AsRef<Path>,
+    {
+        self.push(
+            pak_path.into(),
+            EntrySource::Disk(fs_path.as_ref().to_owned()),
+            options,
+        )
+    }
+
+    /// Adds a delete record, which hides `pak_path` in paks of lower priority when the engine
+    /// mounts them together. Delete records need [`VersionMajor::DeleteRecords`].
+    pub fn add_delete_record<P: Into<String>>(&mut self, pak_path: P) -> &mut Self {
+        self.push(
+            pak_path.into(),
+            EntrySource::Deleted,
+            EntryOptions::default(),
+        )
+    }
+
+    /// Adds every input of a [`PackPlan`]. Destinations must lie under the builder's mount point.
+    /// Like UnrealPak, only files with the `-compress` switch are compressed; the `-encrypt`
+    /// switch forces encryption for its files.
+    pub fn add_plan(&mut self, plan: &PackPlan) -> Result<&mut Self, UnrealpakError> {
+        for input in &plan.inputs {
+            let pak_path = input
+                .destination
+                .strip_prefix(&self.mount_point)
+                .ok_or_else(|| {
+                    UnrealpakError::NotUnderMountPoint(
+                        input.destination.clone(),
+                        self.mount_point.clone(),
+                    )
+                })?
+                .to_owned();
+            let options = EntryOptions {
+                compression_method: Some(match input.compress {
+                    true => Compression::Zlib,
+                    false => Compression::None,
+                }),
+                encrypted: input.encrypt.then_some(true),
+            };
+            self.add_from_disk_with_options(pak_path, &input.source, options);
+        }
+        Ok(self)
+    }
+
+    /// Takes ownership of a pak so that its entries can be copied with
+    /// [`PakBuilder::copy_entry`]. The pak may be of any version; the headers of copied entries
+    /// are re-encoded for the builder's version. Fails with [`UnrealpakError::MissingKey`] if the
+    /// index of the pak is encrypted but [`PakWriterOptions::encrypt_index`] is not set.
+    pub fn add_source_pak<R>(&mut self, pak: PakReader<R>) -> Result<SourcePakId, UnrealpakError>
+    where
+        R: Read + Seek + 'static,
+    {
+        if pak.pak.is_index_encrypted && self.options.encrypt_index.is_none() {
+            return Err(UnrealpakError::MissingKey);
+        }
+        self.sources.push(SourcePak {
+            version: pak.pak.version,
+            index: pak.pak.index,
+            reader: Box::new(pak.reader),
+            key: pak.key,
+        });
+        Ok(SourcePakId(self.sources.len() - 1))
+    }
+
+    /// Copies the entry at `path` of a source pak to `pak_path` without decompressing or
+    /// decrypting it. The stored data and block table are written verbatim; only offsets change.
+    pub fn copy_entry<P: Into<String>>(
+        &mut self,
+        pak_path: P,
+        source: SourcePakId,
+        path: &str,
+    ) -> Result<&mut Self, UnrealpakError> {
+        let record = self.sources[source.0]
+            .index
+            .find_record(path)
+            .ok_or_else(|| UnrealpakError::EntryNotFound(path.to_owned()))?;
+        let source = EntrySource::raw(source.0, record);
+        Ok(self.push(pak_path.into(), source, EntryOptions::default()))
+    }
+
+    fn push(&mut self, pak_path: String, source: EntrySource, options: EntryOptions) -> &mut Self {
+        self.entries.push(PendingEntry {
+            pak_path: normalize_pak_path(&pak_path),
+            source,
+            options,
+        });
+        self
+    }
+
+    /// Whether an entry has been added at `pak_path`, which must be normalized.
+    pub(crate) fn contains(&self, pak_path: &str) -> bool {
+        self.entries.iter().any(|e| e.pak_path == pak_path)
+    }
+
+    /// Drops the entries added at `pak_path`, returning whether there were any.
+    pub(crate) fn remove(&mut self, pak_path: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|e| e.pak_path != pak_path);
+        self.entries.len() != len
+    }
+
+    /// Moves the entries added at `from` to `to`, returning whether there were any.
+    pub(crate) fn rename(&mut self, from: &str, to: &str) -> bool {
+        let mut renamed = false;
+        for entry in self.entries.iter_mut().filter(|e| e.pak_path == from) {
+            entry.pak_path = to.to_owned();
+            renamed = true;
+        }
+        renamed
+    }
+
+    /// Writes every entry followed by the index (with its path hash index and full directory
+    /// index) and the footer.
+    pub fn finish<W: Write + Seek>(self, writer: &mut W) -> Result<WriteSummary, UnrealpakError> {
+        self.finish_with_progress(writer, &mut NoProgress)
+    }
+
+    /// Writes the pak to `path` through a temporary file next to it, which only replaces `path`
+    /// once the pak is complete. The temporary file is removed if writing fails or is cancelled.
+    pub fn finish_to_file<P: AsRef<Path>>(
+        self,
+        path: P,
+        progress: &mut dyn Progress,
+    ) -> Result<WriteSummary, UnrealpakError> {
+        let path = path.as_ref();
+        let mut partial_path = path.as_os_str().to_owned();
+        partial_path.push(".partial");
+        let partial_path = PathBuf::from(partial_path);
+
+        let write = || {
+            let mut writer = BufWriter::new(fs::File::create(&partial_path)?);
+            let summary = self.finish_with_progress(&mut writer, progress)?;
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            Ok(summary)
+        };
+        match write() {
+            Ok(summary) => {
+                fs::rename(&partial_path, path)?;
+                Ok(summary)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&partial_path);
+                Err(e)
+            }
+        }
+    }
+
+    /// Like [`PakBuilder::finish`], reporting each entry's data to `progress` as it is written.
+    /// The sizes reported are those of the entries' sources, or of the stored data for entries
+    /// copied from another pak.
+    pub fn finish_with_progress<W: Write + Seek>(
+        self,
+        writer: &mut W,
+        progress: &mut dyn Progress,
+    ) -> Result<WriteSummary, UnrealpakError> {
+        let (version, mount_point, path_hash_seed) =
+            (self.version, self.mount_point.clone(), self.path_hash_seed);
+        let (index_key, encryption_key_guid) = (
+            self.options.encrypt_index.clone(),
+            self.options.encryption_key_guid,
+        );
+        let (entries, summary) = self.write_entries(writer, progress)?;
+        write_index_and_footer(
+            writer,
+            version,
+            mount_point,
+            path_hash_seed,
+            entries,
+            index_key.as_ref(),
+            encryption_key_guid,
+        )?;
+        Ok(summary)
+    }
+
+    /// Writes the data of every entry from the current position of `writer`, returning the
+    /// records of the entries sorted by path.
+    pub(crate) fn write_entries<W: Write + Seek>(
+        self,
+        writer: &mut W,
+        progress: &mut dyn Progress,
+    ) -> Result<(Vec<(String, Record)>, WriteSummary), UnrealpakError> {
+        let PakBuilder {
+            version,
+            mount_point,
+            path_hash_seed: _,
+            options,
+            entries,
+            mut sources,
+        } = self;
+
+        info!(
+            "collected files {:#?}",
+            entries.iter().map(|e| &e.pak_path).collect::<Vec<_>>()
+        );
+
+        // Records are indexed in path order, whatever order their data is laid out in.
+        let mut entries = entries.into_iter().enumerate().collect::<Vec<_>>();
+        entries.sort_by(|(_, a), (_, b)| a.pak_path.cmp(&b.pak_path));
+
+        // Map<DirectoryName, Map<FileName, EntryIndex>>, checked before any data is written.
+        let mut directories = BTreeMap::new();
+        for (i, (_, entry)) in entries.iter().enumerate() {
+            let (dirname, filename) = split_pak_path(&entry.pak_path);
+            let files: &mut BTreeMap<String, usize> = directories.entry(dirname).or_default();
+            if files.insert(filename, i).is_some() {
+                return Err(UnrealpakError::DuplicateEntry(entry.pak_path.clone()));
+            }
+            if let Some(method) = options.entry_options(entry).compression_method {
+                check_compression(method)?;
+            }
+            if matches!(entry.source, EntrySource::Deleted) && version < VersionMajor::DeleteRecords
+            {
+                return Err(UnrealpakError::IncompatibleEntry(
+                    entry.pak_path.clone(),
+                    version.major() as u32,
+                ));
+            }
+        }
+
+        let mut write_order = (0..entries.len()).collect::<Vec<_>>();
+        match &options.file_order {
+            // Unlisted entries keep their path order after the listed ones.
+            Some(file_order) => write_order.sort_by_key(|&i| {
+                match file_order.position(&full_path(&mount_point, &entries[i].1.pak_path)) {
+                    Some(position) => (false, position),
+                    None => (true, 0),
+                }
+            }),
+            None => write_order.sort_by_key(|&i| entries[i].0),
+        }
+
+        let pak_paths = entries
+            .iter()
+            .map(|(_, e)| e.pak_path.clone())
+            .collect::<Vec<_>>();
+        let mut entries = entries
+            .into_iter()
+            .map(|(_, e)| Some(e))
+            .collect::<Vec<_>>();
+        let sizes = entries
+            .iter_mut()
+            .map(|e| e.as_mut().unwrap().source.len())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut tracker = ProgressTracker::new(progress, entries.len(), sizes.iter().sum());
+        let mut summary = WriteSummary {
+            entry_count: entries.len(),
+            ..Default::default()
+        };
+        // Entries whose data has been written, by their content.
+        let mut written: HashMap<ContentKey, usize> = HashMap::new();
+        let mut records: Vec<Option<Record>> = (0..entries.len()).map(|_| None).collect();
+        for (step, i) in write_order.into_iter().enumerate() {
+            let mut entry = entries[i].take().unwrap();
+            tracker.start_entry(step, &pak_paths[i], sizes[i])?;
+            let entry_options = options.entry_options(&entry);
+            let compression_method = entry_options
+                .compression_method
+                .unwrap_or(options.compression_method);
+            let encrypt_key = match entry_options.encrypted {
+                Some(true) => Some(
+                    options
+                        .encrypt_data
+                        .as_ref()
+                        .ok_or(UnrealpakError::MissingKey)?,
+                ),
+                Some(false) => None,
+                None => options.encrypt_data.as_ref(),
+            };
+
+            let content_key = match &mut entry.source {
+                _ if !options.deduplicate => None,
+                EntrySource::Deleted => None,
+                EntrySource::Raw { source, offset, .. } => Some(ContentKey::Raw {
+                    source: *source,
+                    offset: *offset,
+                }),
+                source => Some(ContentKey::Data {
+                    hash: source.content_hash()?,
+                    compression_method,
+                    encrypted: encrypt_key.is_some(),
+                }),
+            };
+            if let Some(&first) = content_key.as_ref().and_then(|key| written.get(key)) {
+                let record = records[first].clone().unwrap();
+                debug!("{} duplicates {}", pak_paths[i], pak_paths[first]);
+                summary.deduplicated_entries += 1;
+                summary.deduplicated_bytes +=
+                    record.serialized_size(version, EntryLocation::Data) + stored_size(&record);
+                records[i] = Some(record);
+                tracker.advance(sizes[i])?;
+                continue;
+            }
+
+            let record = match entry.source {
+                EntrySource::Raw { source, offset, .. } => write_raw_entry(
+                    writer,
+                    version,
+                    &pak_paths[i],
+                    &mut sources[source],
+                    offset,
+                    &options,
+                    &mut tracker,
+                )
+                .map_err(|e| tracker.error(e))?,
+                EntrySource::Deleted => Record::deleted(),
+                mut source => {
+                    let compression_method = match compression_method {
+                        Compression::None => Compression::None,
+                        method
+                            if options.skip_incompressible
+                                && !source.shrinks(
+                                    method,
+                                    options.compression_levels,
+                                    options.min_compression_savings,
+                                )? =>
+                        {
+                            debug!("storing {} uncompressed", pak_paths[i]);
+                            Compression::None
+                        }
+                        method => method,
+                    };
+                    if version < VersionMajor::CompressionEncryption
+                        && (compression_method != Compression::None || encrypt_key.is_some())
+                    {
+                        return Err(UnrealpakError::IncompatibleEntry(
+                            pak_paths[i].clone(),
+                            version.major() as u32,
+                        ));
+                    }
+                    let (reader, uncompressed_size) = source.open()?;
+                    write_entry(
+                        writer,
+                        version,
+                        &pak_paths[i],
+                        &mut tracker.reader(reader),
+                        uncompressed_size,
+                        compression_method,
+                        encrypt_key,
+                        &options,
+                    )
+                    .map_err(|e| tracker.error(e))?
+                }
+            };
+            if let Some(key) = content_key {
+                written.insert(key, i);
+            }
+            records[i] = Some(record);
+        }
+        let entries = pak_paths
+            .into_iter()
+            .zip(records.into_iter().map(Option::unwrap))
+            .collect();
+        Ok((entries, summary))
+    }
+}
+
+/// Builds an unencrypted pak in memory from `(path, contents, compression)` triples, adding a
+/// delete record for entries without contents.
+#[cfg(test)]
+pub(crate) fn build_test_pak(
+    version: Version,
+    mount_point: &str,
+    files: &[(&str, Option<&str>, Compression)],
+) -> PakReader<Cursor<Vec<u8>>> {
+    let mut builder = PakBuilder::new(version, mount_point, "pak.pak", Default::default()).unwrap();
+    for &(path, contents, compression) in files {
+        match contents {
+            Some(contents) => builder.add_file_with_options(
+                path,
+                contents.as_bytes().to_vec(),
+                EntryOptions {
+                    compression_method: Some(compression),
+                    ..Default::default()
+                },
+            ),
+            None => builder.add_delete_record(path),
+        };
+    }
+    let mut out_bytes = vec![];
+    builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+    PakReader::read(Cursor::new(out_bytes), version, None).unwrap()
+}
+
+/// Writes the index of `entries`, which must be sorted by path, along with its path hash index
+/// and full directory index, followed by the footer.
+pub(crate) fn write_index_and_footer<W: Write + Seek>(
+    writer: &mut W,
+    version: Version,
+    mount_point: String,
+    path_hash_seed: u64,
+    entries: Vec<(String, Record)>,
+    index_key: Option<&Aes256Enc>,
+    encryption_key_guid: u128,
+) -> Result<(), UnrealpakError> {
+    let (pak_paths, records): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+    let encoded_record_offsets = encoded_record_offsets(&records);
+
+    let path_hash_index = PathHashIndex(
+        pak_paths
+            .iter()
+            .zip(&encoded_record_offsets)
+            .map(|(pak_path, &offset)| {
+                let utf16le_path = pak_path
+                    .encode_utf16()
+                    .flat_map(u16::to_le_bytes)
+                    .collect::<Vec<_>>();
+                (fnv64(&utf16le_path, path_hash_seed), offset)
+            })
+            .collect(),
+    );
+    debug!("path_hash_index = {:#X?}", &path_hash_index);
+
+    let full_directory_index = FullDirectoryIndex::from_paths(
+        pak_paths
+            .iter()
+            .map(String::as_str)
+            .zip(encoded_record_offsets.iter().copied()),
+    );
+    debug!("full_directory_index = {:#X?}", &full_directory_index);
+
+    let compression_methods = if version >= VersionMajor::FNameBasedCompression {
+        let mut names = vec![0u8; 32 * version.compression_method_slots()];
+        // Zlib is the only supported method, so it always takes the first slot.
+        if records
+            .iter()
+            .any(|r| r.compression_method == Compression::Zlib)
+        {
+            names[..4].copy_from_slice(b"Zlib");
+        }
+        Some(names)
+    } else {
+        None
+    };
+
+    let index = Index {
+        mount_point,
+        record_count: records.len() as u32,
+        path_hash_seed: Some(path_hash_seed),
+        path_hash_index: Some(path_hash_index),
+        full_directory_index: Some(full_directory_index),
+        record_positions: record_positions(&records),
+        records,
+    };
+
+    let index_offset = writer.stream_position()?;
+    let (index_hash, index_size) = write_index(writer, &index, index_offset, version, index_key)?;
+
+    debug!("index_hash = {:0x?}", index_hash);
+
+    let footer = Footer {
+        encryption_key_guid: (version >= VersionMajor::EncryptionKeyGuid)
+            .then_some(encryption_key_guid),
+        is_index_encrypted: (version >= VersionMajor::IndexEncryption)
+            .then_some(index_key.is_some()),
+        magic: MAGIC,
+        version,
+        index_offset,
+        index_size,
+        index_hash,
+        is_index_frozen: (version == VersionMajor::FrozenIndex).then_some(false),
+        compression_methods,
+    };
+
+    write_footer(writer, &footer)?;
+
+    Ok(())
+}
+
+fn compress_block(
+    compression_method: Compression,
+    compression_levels: CompressionLevels,
+    block: &[u8],
+) -> Result<Vec<u8>, UnrealpakError> {
+    match compression_method {
+        Compression::None => Ok(block.to_vec()),
+        Compression::Zlib => {
+            let mut z =
+                ZlibEncoder::new(Vec::with_capacity(block.len()), compression_levels.zlib());
+            z.write_all(block)?;
+            Ok(z.finish()?)
+        }
+        method => Err(UnrealpakError::UnsupportedCompression(method)),
+    }
+}
+
+/// Size of the data following a record header, including the padding of encrypted data.
+pub(crate) fn stored_size(record: &Record) -> u64 {
+    match record.is_encrypted.unwrap_or_default() {
+        true => {
+            let block_size = Aes256Enc::block_size() as u64;
+            record.compressed_size.div_ceil(block_size) * block_size
+        }
+        false => record.compressed_size,
+    }
+}
+
+/// Pads `writer` for a record of the given sizes, returning the offset the record starts at.
+fn write_padding<W: Write + Seek>(
+    writer: &mut W,
+    alignment: Option<Alignment>,
+    header_size: u64,
+    stored_size: u64,
+    mappable: bool,
+) -> Result<u64, UnrealpakError> {
+    let offset = writer.stream_position()?;
+    let padding = alignment.map_or(0, |alignment| {
+        alignment.padding(offset, header_size, stored_size, mappable)
+    });
+    writer.write_all(&vec![0u8; padding as usize])?;
+    Ok(offset + padding)
 }
 
-const ENCODED_RECORD_SIZE: u32 = {
-    4 // u32 flags
-    + 4 // u32 offset
-    + 4 // u32 uncompressed size
-};
+/// Copies the record at `source_offset` of a source pak and its stored data into `writer`,
+/// re-encoding the record header for `version`. Block offsets are rewritten, since they count
+/// from the start of the header, whose size depends on the version, and are absolute before
+/// [`VersionMajor::RelativeChunkOffsets`]. Encrypted data is re-encrypted if the source pak was
+/// opened with a key other than [`PakWriterOptions::encrypt_data`].
+fn write_raw_entry<W: Write + Seek>(
+    writer: &mut W,
+    version: Version,
+    pak_path: &str,
+    source: &mut SourcePak,
+    source_offset: u64,
+    options: &PakWriterOptions,
+    tracker: &mut ProgressTracker,
+) -> Result<Record, UnrealpakError> {
+    let source_version = source.version;
+    let source_key = source.key.as_ref();
+    let source = &mut source.reader;
+    source.seek(SeekFrom::Start(source_offset))?;
+    let mut record = read_record(source, source_version, EntryLocation::Data)?;
+
+    let encrypted = record.is_encrypted.unwrap_or_default();
+    if version < VersionMajor::CompressionEncryption
+        && (record.compression_method != Compression::None || encrypted)
+    {
+        return Err(UnrealpakError::IncompatibleEntry(
+            pak_path.to_owned(),
+            version.major() as u32,
+        ));
+    }
+
+    let block_count = match record.compression_method {
+        Compression::None => 0,
+        _ => record
+            .blocks
+            .as_ref()
+            .map_or(0, |blocks| blocks.len() as u32),
+    };
+    let block_base = |version: Version, offset: u64| {
+        let base = match version >= VersionMajor::RelativeChunkOffsets {
+            true => 0,
+            false => offset,
+        };
+        base + header_size(version, record.compression_method, block_count)
+    };
+
+    let stored_size = stored_size(&record);
+    let mappable = memory_mappable(pak_path, record.compression_method, encrypted);
+    let offset = write_padding(
+        writer,
+        options.alignment,
+        header_size(version, record.compression_method, block_count),
+        stored_size,
+        mappable,
+    )?;
+    let (source_base, base) = (
+        block_base(source_version, source_offset),
+        block_base(version, offset),
+    );
+    for block in record.blocks.iter_mut().flatten() {
+        block.start = block.start - source_base + base;
+        block.end = block.end - source_base + base;
+    }
+    record.offset = offset;
+
+    let reencrypt = match (source_key, options.encrypt_data.as_ref()) {
+        (Some(source_key), Some(key)) if encrypted && !same_key(key, source_key) => {
+            Some((source_key, key))
+        }
+        _ => None,
+    };
+    let Some((source_key, key)) = reencrypt else {
+        write_record(writer, version, &record, EntryLocation::Data)?;
+        let copied = std::io::copy(&mut tracker.reader(source.take(stored_size)), writer)?;
+        if copied != stored_size {
+            return Err(UnrealpakError::ValidationError("truncated entry data"));
+        }
+        return Ok(record);
+    };
+
+    // The hash covers the stored data, so it changes along with the key. The header is written
+    // again once the data has been re-encrypted and hashed a compression block at a time, which
+    // keeps every chunk aligned to the AES block size.
+    write_record(writer, version, &record, EntryLocation::Data)?;
+    let hashed_size = match record.compression_method {
+        Compression::None => record.uncompressed_size,
+        _ => stored_size,
+    };
+    let source_key = Some(source_key.clone());
+    let mut source = tracker.reader(source);
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0u8; COMPRESSION_BLOCK_SIZE as usize];
+    let mut copied = 0u64;
+    while copied < stored_size {
+        let chunk = &mut buf[..(stored_size - copied).min(COMPRESSION_BLOCK_SIZE) as usize];
+        source.read_exact(chunk)?;
+        decrypt(&source_key, chunk)?;
+        encrypt(key, chunk);
+        let hashed = hashed_size.saturating_sub(copied).min(chunk.len() as u64) as usize;
+        hasher.update(&chunk[..hashed]);
+        writer.write_all(chunk)?;
+        copied += chunk.len() as u64;
+    }
+    record.hash = Some(Hash(hasher.finalize().into()));
+
+    let end = writer.stream_position()?;
+    writer.seek(SeekFrom::Start(offset))?;
+    write_record(writer, version, &record, EntryLocation::Data)?;
+    writer.seek(SeekFrom::Start(end))?;
+
+    Ok(record)
+}
+
+/// Streams one entry from `reader` into `writer` a compression block at a time. The record header
+/// is reserved up front, since its size only depends on the number of blocks, and filled in once
+/// the data and its hash have been written. Any padding required by the alignment of `options`
+/// goes before the record.
+#[allow(clippy::too_many_arguments)]
+fn write_entry<W: Write + Seek>(
+    writer: &mut W,
+    version: Version,
+    pak_path: &str,
+    reader: &mut dyn Read,
+    uncompressed_size: u64,
+    compression_method: Compression,
+    key: Option<&Aes256Enc>,
+    options: &PakWriterOptions,
+) -> Result<Record, UnrealpakError> {
+    let compression_method = match uncompressed_size {
+        0 => Compression::None,
+        _ => compression_method,
+    };
+    let compression_block_size = match compression_method {
+        Compression::None => 0,
+        _ => uncompressed_size.min(COMPRESSION_BLOCK_SIZE),
+    };
+    let block_count = match compression_method {
+        Compression::None => 0,
+        _ => uncompressed_size.div_ceil(compression_block_size),
+    };
+
+    let header_size = header_size(version, compression_method, block_count as u32);
+
+    let mut buf = vec![0u8; COMPRESSION_BLOCK_SIZE as usize];
+    let mut head = vec![];
+    let mut remaining = uncompressed_size;
+    // Reads the next compression block of the entry, returning its size, the size of the block
+    // without encryption padding and the block as stored.
+    let mut next_chunk = || -> Result<(usize, usize, Vec<u8>), UnrealpakError> {
+        let n = remaining.min(COMPRESSION_BLOCK_SIZE) as usize;
+        reader.read_exact(&mut buf[..n])?;
+        remaining -= n as u64;
+
+        let mut chunk = match compression_method {
+            Compression::None => {
+                if head.is_empty() {
+                    head.extend_from_slice(&buf[..n.min(Aes256Enc::block_size())]);
+                }
+                buf[..n].to_vec()
+            }
+            _ => compress_block(compression_method, options.compression_levels, &buf[..n])?,
+        };
+        let block_size = chunk.len();
+
+        if let Some(key) = key {
+            // Uncompressed entries are encrypted as a whole, so only the tail is padded, using
+            // the start of the entry. Compressed blocks are each padded with their own start.
+            match compression_method {
+                Compression::None if remaining > 0 => {}
+                Compression::None => pad_with_repeated(&mut chunk, &head),
+                _ => {
+                    let block_head = chunk[..chunk.len().min(Aes256Enc::block_size())].to_vec();
+                    pad_with_repeated(&mut chunk, &block_head)
+                }
+            }
+            encrypt(key, &mut chunk);
+        }
+        Ok((n, block_size, chunk))
+    };
+    let chunk_count = uncompressed_size.div_ceil(COMPRESSION_BLOCK_SIZE) as usize;
+
+    // Patch padding depends on the stored size of the entry, which compressed entries only know
+    // once they are compressed.
+    let mut spooled = match (compression_method, options.alignment) {
+        (Compression::None, _) | (_, None | Some(Alignment::MemoryMapping(_))) => None,
+        _ => {
+            let mut file = tempfile::tempfile()?;
+            let mut sizes = vec![];
+            for _ in 0..chunk_count {
+                let (n, block_size, chunk) = next_chunk()?;
+                file.write_all(&chunk)?;
+                sizes.push((n, block_size, chunk.len()));
+            }
+            file.seek(SeekFrom::Start(0))?;
+            Some((BufReader::new(file), sizes))
+        }
+    };
+    let stored_size = match (&spooled, compression_method, key) {
+        (Some((_, sizes)), _, _) => sizes.iter().map(|&(_, _, len)| len as u64).sum(),
+        (None, Compression::None, Some(_)) => {
+            let block_size = Aes256Enc::block_size() as u64;
+            uncompressed_size.div_ceil(block_size) * block_size
+        }
+        _ => uncompressed_size,
+    };
+    let mappable = memory_mappable(pak_path, compression_method, key.is_some());
+    let offset = write_padding(
+        writer,
+        options.alignment,
+        header_size,
+        stored_size,
+        mappable,
+    )?;
+    writer.write_all(&vec![0u8; header_size as usize])?;
+
+    // Block offsets are relative to the record from v5 onwards.
+    let block_base = match version >= VersionMajor::RelativeChunkOffsets {
+        true => header_size,
+        false => offset + header_size,
+    };
+
+    let mut hasher = Sha1::new();
+    let mut blocks = vec![];
+    let mut stored_size = 0u64;
+    for i in 0..chunk_count {
+        let (n, block_size, chunk) = match &mut spooled {
+            Some((file, sizes)) => {
+                let (n, block_size, len) = sizes[i];
+                let mut chunk = vec![0u8; len];
+                file.read_exact(&mut chunk)?;
+                (n, block_size, chunk)
+            }
+            None => next_chunk()?,
+        };
+        if compression_method != Compression::None {
+            let start = block_base + stored_size;
+            blocks.push(Block {
+                start,
+                end: start + block_size as u64,
+            });
+        }
+
+        // UnrealPak hashes encrypted uncompressed entries without the padding of their tail.
+        let hashed = match compression_method {
+            Compression::None => n,
+            _ => chunk.len(),
+        };
+        hasher.update(&chunk[..hashed]);
+        writer.write_all(&chunk)?;
+        stored_size += chunk.len() as u64;
+    }
+
+    let record = Record {
+        offset,
+        uncompressed_size,
+        compression_method,
+        compressed_size: match compression_method {
+            Compression::None => uncompressed_size,
+            _ => stored_size,
+        },
+        timestamp: None,
+        hash: Some(Hash(hasher.finalize().into())),
+        blocks: match compression_method {
+            Compression::None => None,
+            _ => Some(blocks),
+        },
+        is_encrypted: Some(key.is_some()),
+        compression_block_size: Some(compression_block_size as u32),
+        is_deleted: false,
+    };
+
+    let end = writer.stream_position()?;
+    writer.seek(SeekFrom::Start(offset))?;
+    write_record(writer, version, &record, EntryLocation::Data)?;
+    debug_assert_eq!(writer.stream_position()?, offset + header_size);
+    writer.seek(SeekFrom::Start(end))?;
+
+    Ok(record)
+}
+
+pub fn write_pak<W, P, M, O>(
+    writer: &mut W,
+    version: Version,
+    pack_root_path: P,
+    mount_point: M,
+    output_pak_path: O,
+    options: &PakWriterOptions,
+) -> Result<WriteSummary, UnrealpakError>
+where
+    W: Write + Seek,
+    P: AsRef<Path>,
+    M: AsRef<Path>,
+    O: AsRef<Path>,
+{
+    let pack_root_path = pack_root_path.as_ref();
+
+    let mount_point = mount_point
+        .as_ref()
+        .to_path_buf()
+        .into_os_string()
+        .into_string()
+        .map_err(UnrealpakError::OsString)?;
+
+    let mut builder = PakBuilder::new(version, mount_point, output_pak_path, options.clone())?;
+
+    info!(
+        "collecting directory tree snapshot with root directory {:?}",
+        std::fs::canonicalize(pack_root_path)?
+    );
+    for entry in WalkDir::new(pack_root_path)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+    {
+        if let Ok(metadata) = entry.metadata() {
+            if metadata.is_file() {
+                if let Ok(p) = entry.path().strip_prefix(pack_root_path) {
+                    let utf8_path = p
+                        .to_path_buf()
+                        .into_os_string()
+                        .into_string()
+                        .map_err(UnrealpakError::OsString)?;
+                    builder.add_from_disk(utf8_path, entry.path());
+                }
+            }
+        }
+    }
+
+    builder.finish(writer)
+}
+
+/// Pak paths are stored relative to the mount point with `/` separators.
+pub(crate) fn normalize_pak_path(pak_path: &str) -> String {
+    pak_path
+        .replace('\\', "/")
+        .trim_start_matches('/')
+        .to_owned()
+}
+
+#[cfg(unix)]
+pub(crate) fn utf16le_path_to_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, UnrealpakError> {
+    Ok(path
+        .as_ref()
+        .to_path_buf()
+        .into_os_string()
+        .into_string()
+        .map_err(UnrealpakError::OsString)?
+        .as_bytes()
+        .to_owned())
+}
+
+#[cfg(windows)]
+pub(crate) fn utf16le_path_to_bytes<P: AsRef<Path>>(path: P) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+    let path: Vec<u16> = path.as_ref().as_os_str().encode_wide();
+    let mut buf = Vec::with_capacity(path.len() / 2);
+    LittleEndian::write_u16_into(&path, &mut buf);
+    buf
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn utf16le_path_to_bytes<P: AsRef<Path>>(path: P) -> Vec<u8> {
+    unimplemented!("unsupported platform")
+}
+
+/// Pads `v` to the AES block size by cycling through `source`, like UnrealPak does instead of
+/// padding with zeros.
+pub(crate) fn pad_with_repeated(v: &mut Vec<u8>, source: &[u8]) {
+    let padded_len = v.len().div_ceil(Aes256Enc::block_size()) * Aes256Enc::block_size();
+    for i in 0..padded_len - v.len() {
+        v.push(source[i % source.len()]);
+    }
+}
+
+/// Whether `dec` decrypts what `enc` encrypts, i.e. both were created from the same key.
+fn same_key(enc: &Aes256Enc, dec: &Aes256Dec) -> bool {
+    use aes::cipher::{BlockDecrypt, BlockEncrypt};
+    let mut block = aes::Block::default();
+    enc.encrypt_block(&mut block);
+    dec.decrypt_block(&mut block);
+    block == aes::Block::default()
+}
+
+pub(crate) fn encrypt(key: &Aes256Enc, bytes: &mut [u8]) {
+    use aes::cipher::BlockEncrypt;
+    for chunk in bytes.chunks_mut(16) {
+        key.encrypt_block(aes::Block::from_mut_slice(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{Compression, CompressionLevel};
+    use crate::merge::ConflictPolicy;
+    use crate::progress::ProgressEvent;
+    use aes::cipher::KeyInit;
+    use base64::Engine;
+    use std::io::Cursor;
+    use std::ops::ControlFlow;
+
+    static AES_KEY: &str = "lNJbw660IOC+kU7cnVQ1oeqrXyhk4J6UAZrCBbcnp94=";
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn aes_key() -> Aes256Enc {
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(AES_KEY)
+            .unwrap();
+        Aes256Enc::new_from_slice(&key).unwrap()
+    }
+
+    #[test]
+    fn try_index_hash() {
+        let index: [u8; 0xAD] = [
+            0x15, 0x00, 0x00, 0x00, 0x2E, 0x2E, 0x2F, 0x6D, 0x6F, 0x75, 0x6E, 0x74, 0x2F, 0x70,
+            0x6F, 0x69, 0x6E, 0x74, 0x2F, 0x72, 0x6F, 0x6F, 0x74, 0x2F, 0x00, 0x04, 0x00, 0x00,
+            0x00, 0x7D, 0x5A, 0x5C, 0x20, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xA4,
+            0x35, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x66, 0x8E, 0x2D, 0xAF, 0x1C, 0x70, 0xB4, 0xC3, 0x62, 0x9F, 0x59, 0xCA, 0x98,
+            0xF5, 0xEA, 0x3E, 0xA5, 0x56, 0xEC, 0xA7, 0x01, 0x00, 0x00, 0x00, 0xDC, 0x35, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x68, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x70,
+            0x79, 0xEA, 0xE0, 0x7F, 0x19, 0xA2, 0x51, 0xA1, 0x4E, 0xFD, 0xE5, 0xA4, 0x8D, 0x7D,
+            0x22, 0x7E, 0xD5, 0xAE, 0x73, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x00,
+            0x00, 0x00, 0x00, 0x54, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x89, 0x02, 0x00,
+            0x00, 0x11, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0xCF, 0x2A, 0x00, 0x00, 0xBE,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0xC2, 0x2C, 0x00, 0x00, 0x00, 0x08, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let guess_hash: [u8; 20] = {
+            let mut hasher = Sha1::new();
+            hasher.update(&index[..]);
+            hasher.finalize().into()
+        };
+
+        let expected_hash: [u8; 0x14] = [
+            0x34, 0x72, 0xD7, 0xAA, 0x90, 0x47, 0xD4, 0xC8, 0x05, 0x3F, 0x9B, 0x42, 0x48, 0x13,
+            0x25, 0xC3, 0x88, 0x09, 0x8F, 0x07,
+        ];
+
+        assert_eq!(guess_hash, expected_hash);
+    }
+
+    #[test]
+    fn test_write_pak_v11() {
+        init_logger();
+
+        let mut out_bytes = vec![];
+        let mut writer = Cursor::new(&mut out_bytes);
+        let pack_root_path = "./tests/pack/root";
+        let output_pak_path =
+            "/home/truman/projects/drg-modding/tools/unpak/tests/packs/pack_v11.pak";
+        write_pak(
+            &mut writer,
+            Version::V11,
+            pack_root_path,
+            "../mount/point/root/",
+            output_pak_path,
+            &super::PakWriterOptions {
+                compression_method: Compression::None,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let v11_pak = include_bytes!("../tests/packs/pack_v11.pak");
+
+        fs::write("./target/test_v11.pak", &out_bytes).unwrap();
+
+        assert_eq!(out_bytes.len(), v11_pak.len());
+        assert_eq!(&out_bytes[..], &v11_pak[..]);
+    }
+
+    #[test]
+    fn test_pak_builder_matches_write_pak_v11() {
+        let options = PakWriterOptions {
+            compression_method: Compression::None,
+            ..Default::default()
+        };
+        let mut builder = PakBuilder::new(
+            Version::V11,
+            "../mount/point/root/",
+            "/home/truman/projects/drg-modding/tools/unpak/tests/packs/pack_v11.pak",
+            options,
+        )
+        .unwrap();
+        builder
+            .add_reader(
+                "directory/nested.txt",
+                Cursor::new(fs::read("./tests/pack/root/directory/nested.txt").unwrap()),
+            )
+            .add_from_disk("test.png", "./tests/pack/root/test.png")
+            .add_file("test.txt", fs::read("./tests/pack/root/test.txt").unwrap())
+            .add_file("zeros.bin", vec![0u8; 2048]);
+
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+
+        let v11_pak = include_bytes!("../tests/packs/pack_v11.pak");
+        assert_eq!(&out_bytes[..], &v11_pak[..]);
+    }
+
+    #[test]
+    fn test_pak_builder_encrypts_index() {
+        let fixtures: [(Version, &[u8], &str); 2] = [
+            (
+                Version::V8B,
+                include_bytes!("../tests/packs/pack_v8b_encryptindex.pak"),
+                "pack_v8b_encryptindex.pak",
+            ),
+            (
+                Version::V11,
+                include_bytes!("../tests/packs/pack_v11_encryptindex.pak"),
+                "pack_v11_encryptindex.pak",
+            ),
+        ];
+        for (version, expected, name) in fixtures {
+            let options = PakWriterOptions {
+                encrypt_index: Some(aes_key()),
+                ..Default::default()
+            };
+            let mut builder = PakBuilder::new(
+                version,
+                "../mount/point/root/",
+                format!("/home/truman/projects/drg-modding/tools/unpak/tests/packs/{name}"),
+                options,
+            )
+            .unwrap();
+            builder
+                .add_from_disk(
+                    "directory/nested.txt",
+                    "./tests/pack/root/directory/nested.txt",
+                )
+                .add_from_disk("test.png", "./tests/pack/root/test.png")
+                .add_from_disk("test.txt", "./tests/pack/root/test.txt")
+                .add_from_disk("zeros.bin", "./tests/pack/root/zeros.bin");
+
+            let mut out_bytes = vec![];
+            builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+            assert_eq!(&out_bytes[..], expected, "{version:?}");
+        }
+    }
+
+    #[test]
+    fn test_write_entry_zlib_pack_v11_compress() {
+        let png = fs::read("./tests/pack/root/test.png").unwrap();
+        let mut out_bytes = vec![];
+        let mut writer = Cursor::new(&mut out_bytes);
+        let mut record = write_entry(
+            &mut writer,
+            Version::V11,
+            "test.png",
+            &mut Cursor::new(&png),
+            png.len() as u64,
+            Compression::Zlib,
+            None,
+            &PakWriterOptions::default(),
+        )
+        .unwrap();
+
+        let v11_compress_pak = include_bytes!("../tests/packs/pack_v11_compress.pak");
+        assert_eq!(
+            &out_bytes[..],
+            &v11_compress_pak[0x289..0x289 + 0x49 + 0x1E42]
+        );
+
+        record.offset = 0x289;
+        let mut encoded_record = vec![];
+        write_record(
+            &mut encoded_record,
+            Version::V11,
+            &record,
+            EntryLocation::Index,
+        )
+        .unwrap();
+        assert_eq!(&encoded_record[..], &v11_compress_pak[0x23EC..0x2400]);
+    }
+
+    #[test]
+    fn test_write_entry_encrypted_pack_v11_encrypt() {
+        let nested = fs::read("./tests/pack/root/directory/nested.txt").unwrap();
+        let mut out_bytes = vec![];
+        write_entry(
+            &mut Cursor::new(&mut out_bytes),
+            Version::V11,
+            "directory/nested.txt",
+            &mut Cursor::new(&nested),
+            nested.len() as u64,
+            Compression::None,
+            Some(&aes_key()),
+            &PakWriterOptions::default(),
+        )
+        .unwrap();
+
+        let v11_encrypt_pak = include_bytes!("../tests/packs/pack_v11_encrypt.pak");
+        assert_eq!(&out_bytes[..], &v11_encrypt_pak[..0x35 + 0x260]);
+    }
+
+    #[test]
+    fn test_write_entry_zlib_encrypted_pack_v11_compress_encrypt() {
+        let png = fs::read("./tests/pack/root/test.png").unwrap();
+        let mut out_bytes = vec![];
+        write_entry(
+            &mut Cursor::new(&mut out_bytes),
+            Version::V11,
+            "test.png",
+            &mut Cursor::new(&png),
+            png.len() as u64,
+            Compression::Zlib,
+            Some(&aes_key()),
+            &PakWriterOptions::default(),
+        )
+        .unwrap();
+
+        let v11_compress_encrypt_pak =
+            include_bytes!("../tests/packs/pack_v11_compress_encrypt.pak");
+        assert_eq!(
+            &out_bytes[..],
+            &v11_compress_encrypt_pak[0x295..0x295 + 0x49 + 0x1E50]
+        );
+    }
+
+    #[test]
+    fn test_pak_builder_streams_multiple_blocks() {
+        // Large enough for several compression blocks, with a partial block at the end.
+        let data = (0..COMPRESSION_BLOCK_SIZE * 3 + 1234)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let options = PakWriterOptions {
+            compression_method: Compression::Zlib,
+            ..Default::default()
+        };
+        let mut builder = PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+        builder
+            // `Take` does not implement `Seek`, so the reader is spooled.
+            .add_reader("Game/big.bin", Cursor::new(data.clone()).take(u64::MAX))
+            .add_file_with_options(
+                "Game/small.bin",
+                b"small".to_vec(),
+                EntryOptions {
+                    compression_method: Some(Compression::None),
+                    ..Default::default()
+                },
+            );
+
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+
+        let pak = crate::pak_reader::PakReader::read(Cursor::new(&out_bytes), Version::V11, None)
+            .unwrap();
+        assert_eq!(
+            pak.files().collect::<Vec<_>>(),
+            vec!["Game/big.bin", "Game/small.bin"]
+        );
+
+        let big = &pak.pak.index.records[0];
+        assert_eq!(big.compression_method, Compression::Zlib);
+        assert_eq!(big.uncompressed_size, data.len() as u64);
+        assert_eq!(
+            big.compression_block_size,
+            Some(COMPRESSION_BLOCK_SIZE as u32)
+        );
+        let blocks = big.blocks.as_ref().unwrap();
+        assert_eq!(blocks.len(), 4);
+        let mut decompressed = vec![];
+        for block in blocks {
+            let start = (big.offset + block.start) as usize;
+            let end = (big.offset + block.end) as usize;
+            let mut z = flate2::read::ZlibDecoder::new(&out_bytes[start..end]);
+            z.read_to_end(&mut decompressed).unwrap();
+        }
+        assert_eq!(decompressed, data);
+
+        let small = &pak.pak.index.records[1];
+        assert_eq!(small.compression_method, Compression::None);
+        let start = small.offset as usize + 53;
+        assert_eq!(&out_bytes[start..start + 5], b"small");
+    }
+
+    #[test]
+    fn test_pak_builder_from_response_file_matches_pack_v11() {
+        let plan = PackPlan::parse(r#""pack/*" "../mount/point/""#, "./tests").unwrap();
+        let options = PakWriterOptions {
+            compression_method: Compression::None,
+            ..Default::default()
+        };
+        let mut builder = PakBuilder::new(
+            Version::V11,
+            "../mount/point/root/",
+            "/home/truman/projects/drg-modding/tools/unpak/tests/packs/pack_v11.pak",
+            options,
+        )
+        .unwrap();
+        builder.add_plan(&plan).unwrap();
+
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+
+        let v11_pak = include_bytes!("../tests/packs/pack_v11.pak");
+        assert_eq!(&out_bytes[..], &v11_pak[..]);
+    }
+
+    #[test]
+    fn test_pak_builder_matches_pack_v11_compress() {
+        let plan = PackPlan::parse(r#""pack/*" "../mount/point/" -compress"#, "./tests").unwrap();
+        let options = PakWriterOptions::unrealpak();
+        let builder = PakBuilder::from_plan(
+            Version::V11,
+            &plan,
+            "/home/truman/projects/drg-modding/tools/unpak/tests/packs/pack_v11_compress.pak",
+            options,
+        )
+        .unwrap();
+
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+
+        let v11_compress_pak = include_bytes!("../tests/packs/pack_v11_compress.pak");
+        assert_eq!(&out_bytes[..], &v11_compress_pak[..]);
+    }
+
+    #[test]
+    fn test_compression_levels() {
+        let zeros = vec![0u8; 0x20000];
+        let compressed_size = |zlib| {
+            let levels = CompressionLevels { zlib };
+            compress_block(Compression::Zlib, levels, &zeros)
+                .unwrap()
+                .len()
+        };
+        assert!(
+            compressed_size(CompressionLevel::Best) < compressed_size(CompressionLevel::Fastest)
+        );
+        assert_eq!(
+            compressed_size(CompressionLevel::Default),
+            compressed_size(CompressionLevel::Level(6))
+        );
+        assert_eq!(
+            compressed_size(CompressionLevel::Level(42)),
+            compressed_size(CompressionLevel::Best)
+        );
+        assert_eq!(compressed_size(CompressionLevel::Level(0)), {
+            // Stored deflate blocks only add framing.
+            let mut z = ZlibEncoder::new(vec![], flate2::Compression::none());
+            z.write_all(&zeros).unwrap();
+            z.finish().unwrap().len()
+        });
+    }
+
+    #[test]
+    fn test_pak_builder_from_plan_computes_mount_point() {
+        let plan = PackPlan::parse(r#""pack/*" "../mount/point/""#, "./tests").unwrap();
+        let options = PakWriterOptions {
+            compression_method: Compression::None,
+            ..Default::default()
+        };
+        let builder = PakBuilder::from_plan(
+            Version::V11,
+            &plan,
+            "/home/truman/projects/drg-modding/tools/unpak/tests/packs/pack_v11.pak",
+            options,
+        )
+        .unwrap();
 
-const DATA_RECORD_HEADER_SIZE: u64 = {
-    8 // u64 offset
-    + 8 // u64 size
-    + 8 // uncompressed size
-    + 4 // compression method
-    + 20 // record hash
-    + 5 // u8 zeros[5]
-};
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
 
-pub fn write_pak<W, P, M, O>(
-    writer: &mut W,
-    version: VersionMajor,
-    pack_root_path: P,
-    mount_point: M,
-    output_pak_path: O,
-    options: &PakWriterOptions,
-) -> Result<(), UnrealpakError>
-where
-    W: Write + Seek,
-    P: AsRef<Path>,
-    M: AsRef<Path>,
-    O: AsRef<Path>,
-{
-    let pack_root_path = pack_root_path.as_ref();
+        let v11_pak = include_bytes!("../tests/packs/pack_v11.pak");
+        assert_eq!(&out_bytes[..], &v11_pak[..]);
+    }
 
-    let mount_point = mount_point.as_ref();
+    #[test]
+    fn test_pak_builder_compresses_only_plan_inputs_with_switch() {
+        let plan = PackPlan::parse(
+            "pack/root/zeros.bin ../../../Game/Compressed.bin -compress\n\
+             pack/root/zeros.bin ../../../Game/Stored.bin\n",
+            "./tests",
+        )
+        .unwrap();
+        let options = PakWriterOptions {
+            compression_method: Compression::Zlib,
+            ..Default::default()
+        };
+        let builder = PakBuilder::from_plan(Version::V11, &plan, "pak.pak", options).unwrap();
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
 
-    let output_pak_path = output_pak_path.as_ref();
-    info!("output_pak_path {:?}", output_pak_path);
-    let path_hash_seed = strcrc32(&utf16le_path_to_bytes(output_pak_path)?);
+        let pak = PakReader::read(Cursor::new(out_bytes), Version::V11, None).unwrap();
+        let method = |path| pak.pak.index.find_record(path).unwrap().compression_method;
+        assert_eq!(method("Compressed.bin"), Compression::Zlib);
+        assert_eq!(method("Stored.bin"), Compression::None);
+    }
 
-    info!(
-        "collecting directory tree snapshot with root directory {:?}",
-        std::fs::canonicalize(pack_root_path)?
-    );
-    let mut file_paths_utf16le = vec![];
-    let mut file_paths = vec![];
-    let mut full_directory_index = BTreeMap::new();
-    let mut encoded_record_offset = 0;
-    for entry in WalkDir::new(pack_root_path)
-        .sort_by_file_name()
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-    {
-        if let Ok(metadata) = entry.metadata() {
-            if metadata.is_file() {
-                if let Ok(p) = entry.path().strip_prefix(pack_root_path) {
-                    file_paths.push(p.to_owned());
+    #[test]
+    fn test_pak_builder_rejects_plan_outside_mount_point() {
+        let plan = PackPlan::parse(r#""pack/*" "../mount/point/""#, "./tests").unwrap();
+        let options = PakWriterOptions {
+            compression_method: Compression::None,
+            ..Default::default()
+        };
+        let mut builder = PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+        assert!(matches!(
+            builder.add_plan(&plan),
+            Err(UnrealpakError::NotUnderMountPoint(..))
+        ));
+    }
 
-                    let utf8_path = p
-                        .to_path_buf()
-                        .into_os_string()
-                        .into_string()
-                        .map_err(UnrealpakError::OsString)?;
+    #[test]
+    fn test_pak_builder_rejects_duplicate_entries() {
+        let options = PakWriterOptions {
+            compression_method: Compression::None,
+            ..Default::default()
+        };
+        let mut builder = PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+        builder
+            .add_file("Game/a.txt", b"a".to_vec())
+            .add_file("/Game/a.txt", b"b".to_vec());
 
-                    let (dirname, filename) = {
-                        // Need to +1 so the path on the left has the slash.
-                        let i = utf8_path.rfind("/").map(|i| i + 1);
-                        match i {
-                            Some(i) => {
-                                let (l, r) = utf8_path.split_at(i);
-                                (l.to_owned(), r.to_owned())
-                            }
-                            None => ("/".to_owned(), utf8_path),
-                        }
-                    };
+        let mut out_bytes = vec![];
+        let err = builder
+            .finish(&mut Cursor::new(&mut out_bytes))
+            .unwrap_err();
+        assert!(matches!(err, UnrealpakError::DuplicateEntry(p) if p == "Game/a.txt"));
+    }
 
-                    full_directory_index
-                        .entry(dirname)
-                        .and_modify(|d: &mut BTreeMap<String, u32>| {
-                            d.insert(filename.clone(), encoded_record_offset);
-                        })
-                        .or_insert_with(|| {
-                            let mut files_and_offsets = BTreeMap::new();
-                            files_and_offsets.insert(filename.clone(), encoded_record_offset);
-                            files_and_offsets
-                        });
-
-                    // TODO: convert paths from UTF-8 to UTF-16LE *even on* Unix systems.
-                    #[cfg(unix)]
-                    {
-                        file_paths_utf16le.push(convert_unix_path_to_utf16le_bytes(p));
-                    }
+    #[test]
+    fn test_pak_builder_follows_file_order() {
+        let file_order = FileOrder::parse(
+            "\"../../../Game/Maps/Main.umap\" 2\n\
+             \"../../../game/zeros.bin\" 1\n\
+             \"../../../Game/Missing.uasset\" 3\n",
+        )
+        .unwrap();
+        let options = PakWriterOptions {
+            file_order: Some(file_order),
+            ..Default::default()
+        };
+        let mut builder = PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+        builder
+            .add_file("Game/b.txt", b"b".to_vec())
+            .add_file("Game/Maps/Main.umap", b"main".to_vec())
+            .add_file("Game/a.txt", b"a".to_vec())
+            .add_file("Game/zeros.bin", vec![0u8; 64]);
 
-                    #[cfg(windows)]
-                    {
-                        file_paths_utf16le.push(utf16le_path_to_bytes(p));
-                    }
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+
+        let pak = crate::PakReader::read(Cursor::new(&out_bytes), Version::V11, None).unwrap();
+        let offsets = pak
+            .pak
+            .index
+            .records
+            .iter()
+            .map(|r| r.offset)
+            .collect::<Vec<_>>();
+        // Records stay sorted by path: Game/Maps/Main.umap, Game/a.txt, Game/b.txt, Game/zeros.bin.
+        let header = header_size(Version::V11, Compression::None, 0);
+        assert_eq!(
+            offsets,
+            vec![64 + header, 64 + 4 + 2 * header, 64 + 4 + 1 + 3 * header, 0]
+        );
+    }
+
+    #[test]
+    fn test_pak_builder_follows_file_order_without_trailing_slash() {
+        let file_order = FileOrder::parse("\"../../../Game/b.txt\" 1\n").unwrap();
+        let options = PakWriterOptions {
+            file_order: Some(file_order),
+            ..Default::default()
+        };
+        let mut builder =
+            PakBuilder::new(Version::V11, "../../../Game", "pak.pak", options).unwrap();
+        builder
+            .add_file("a.txt", b"a".to_vec())
+            .add_file("b.txt", b"b".to_vec());
+
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
 
-                    #[cfg(not(any(unix, windows)))]
-                    unimplemented!("unsupported platform");
+        let pak = crate::PakReader::read(Cursor::new(&out_bytes), Version::V11, None).unwrap();
+        let offsets = pak
+            .pak
+            .index
+            .records
+            .iter()
+            .map(|r| r.offset)
+            .collect::<Vec<_>>();
+        let header = header_size(Version::V11, Compression::None, 0);
+        assert_eq!(offsets, vec![1 + header, 0]);
+    }
+
+    #[test]
+    fn test_pak_builder_aligns_records() {
+        let write_aligned = |alignment| {
+            let options = PakWriterOptions {
+                alignment: Some(alignment),
+                ..Default::default()
+            };
+            let mut builder =
+                PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+            builder
+                .add_file("a.m.ubulk", vec![1u8; 100])
+                .add_file("b.bin", vec![2u8; 200])
+                .add_file_with_options(
+                    "c.bin",
+                    vec![3u8; 0x20000],
+                    EntryOptions {
+                        compression_method: Some(Compression::Zlib),
+                        ..Default::default()
+                    },
+                )
+                .add_file("d.m.ubulk", vec![4u8; 10]);
+            let mut out_bytes = vec![];
+            builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
 
-                    encoded_record_offset += ENCODED_RECORD_SIZE;
+            let pak =
+                crate::PakReader::read(Cursor::new(out_bytes.clone()), Version::V11, None).unwrap();
+            assert_eq!(pak.files().count(), 4);
+            let records = pak.pak.index.records;
+            for (record, fill) in records.iter().zip([1u8, 2, 3]) {
+                if record.compression_method == Compression::None {
+                    let start = (record.offset + 0x35) as usize;
+                    let end = start + record.uncompressed_size as usize;
+                    assert!(out_bytes[start..end].iter().all(|&b| b == fill));
                 }
             }
-        }
+            records
+        };
+
+        // Uncompressed headers are 0x35 bytes long. c.bin is stored in more than what is left of
+        // the second 0x100 bytes, and d.m.ubulk fits right after it.
+        let records = write_aligned(Alignment::Patch(0x100));
+        assert_eq!(records[0].offset, 0);
+        assert_eq!(records[1].offset, 0x100);
+        assert_eq!(records[2].offset, 0x200);
+        let c_end = records[2].offset
+            + header_size(Version::V11, Compression::Zlib, 2)
+            + records[2].compressed_size;
+        assert_eq!(records[3].offset, c_end);
+        assert!(c_end % 0x100 + 0x35 + 10 <= 0x100);
+
+        // Only the data of memory mapped bulk data is aligned for memory mapping.
+        let records = write_aligned(Alignment::MemoryMapping(0x1000));
+        assert_eq!(records[0].offset, 0x1000 - 0x35);
+        assert_eq!(records[1].offset, 0x1000 + 100);
+        assert_eq!(records[2].offset, 0x1000 + 100 + 0x35 + 200);
+        assert_eq!((records[3].offset + 0x35) % 0x1000, 0);
     }
-    info!("collected files {:#?}", &file_paths);
-
-    // For each file (as data record)
-    //  - Construct data record header
-    //  - Write header
-    //  - Write file contents
-    // Construct index
-    //  - Construct path hash index
-    //  - Construct full directory index
-    //  - Construct index records
-    //  - Write index
-    // Construct footer
-    //  - Write footer
-
-    let mut offset = 0u64;
-    let mut records = Vec::with_capacity(file_paths.len());
-    let mut file_hashes = Vec::with_capacity(file_paths.len());
-    for file in &file_paths {
-        let mut file_content = fs::read(pack_root_path.join(file))?;
-        let uncompressed_size = file_content.len() as u64;
-        let compressed_size = match options.compression_method {
-            Compression::None => uncompressed_size,
-            Compression::Zlib => {
-                let mut z = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
-                z.write_all(&file_content[..])?;
-                file_content = z.finish()?;
-                file_content.len() as u64
-            }
-            Compression::Gzip | Compression::Oodle => todo!(),
+
+    #[test]
+    fn test_pak_builder_pads_compressed_records_by_stored_size() {
+        let options = PakWriterOptions {
+            alignment: Some(Alignment::Patch(0x1000)),
+            compression_method: Compression::Zlib,
+            ..Default::default()
         };
+        let mut builder = PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+        builder
+            .add_file("a.bin", vec![1u8; 100])
+            .add_file("b.bin", vec![2u8; 0x2000])
+            .add_file(
+                "c.bin",
+                (0..0x8000)
+                    .scan(1u32, |state, _| {
+                        *state ^= *state << 13;
+                        *state ^= *state >> 17;
+                        *state ^= *state << 5;
+                        Some((*state >> 24) as u8)
+                    })
+                    .collect::<Vec<_>>(),
+            );
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
 
-        if let Some(key) = &options.encrypt_data {
-            zero_pad(&mut file_content, Aes256Enc::block_size());
-            encrypt(*key, &mut file_content);
-        }
+        let mut pak =
+            crate::PakReader::read(Cursor::new(out_bytes.clone()), Version::V11, None).unwrap();
+        let records = pak.pak.index.records.clone();
+        let record_end = |record: &Record| {
+            record.offset
+                + header_size(Version::V11, record.compression_method, 1)
+                + record.compressed_size
+        };
+        // b.bin is larger than the alignment, but compresses to fit right after a.bin, while c.bin
+        // is still stored in more than what is left of the first 0x1000 bytes.
+        assert!(records
+            .iter()
+            .all(|r| r.compression_method == Compression::Zlib));
+        assert_eq!(records[1].offset, record_end(&records[0]));
+        assert!(record_end(&records[1]) + record_end(&records[2]) - records[2].offset > 0x1000);
+        assert_eq!(records[2].offset, 0x1000);
+        assert_eq!(pak.get("b.bin").unwrap(), vec![2u8; 0x2000]);
+        assert_eq!(pak.get("c.bin").unwrap().len(), 0x8000);
+    }
 
-        let mut hasher = Sha1::new();
-        hasher.update(&file_content[..]);
-        let file_hash = Hash(hasher.finalize().into());
-        file_hashes.push(file_hash.clone());
-
-        let data_start_offset = offset + DATA_RECORD_HEADER_SIZE;
-
-        let record = Record {
-            offset,
-            uncompressed_size,
-            compression_method: options.compression_method,
-            compressed_size,
-            timestamp: None,
-            hash: Some(file_hash.clone()),
-            blocks: Some(vec![Block {
-                start: data_start_offset,
-                end: data_start_offset + file_content.len() as u64,
-            }]),
-            is_encrypted: Some(options.encrypt_data.is_some()),
-            compression_block_size: None,
-        };
-
-        write_record(writer, version, &record, crate::record::EntryLocation::Data)?;
-        records.push(record);
-        writer.write_all(&mut file_content)?;
-        offset = writer.stream_position()?;
-    }
-    assert_eq!(file_paths.len(), records.len());
-    assert_eq!(file_hashes.len(), records.len());
-
-    let path_hash_index = {
-        let path_hashes = {
-            let mut path_hashes = vec![];
-            for utf16le_path in &file_paths_utf16le {
-                path_hashes.push(fnv64(utf16le_path, path_hash_seed as u64));
-            }
-            assert_eq!(path_hashes.len(), records.len());
-            path_hashes
+    #[test]
+    fn test_pak_builder_copies_raw_entries() {
+        let v11_pak = include_bytes!("../tests/packs/pack_v11_compress_encrypt.pak");
+        let read_v11_pak =
+            || crate::PakReader::read(Cursor::new(v11_pak), Version::V11, None).unwrap();
+        let expected = read_v11_pak();
+        let mut paths = expected.files().collect::<Vec<_>>();
+        paths.sort();
+        let stored_record_size = |record: &Record| {
+            let block_count = record
+                .blocks
+                .as_ref()
+                .map_or(0, |blocks| blocks.len() as u32);
+            header_size(Version::V11, record.compression_method, block_count)
+                + record.compressed_size
         };
 
-        let mut path_hash_index = vec![];
-        for i in 0..path_hashes.len() {
-            path_hash_index.push((path_hashes[i], ENCODED_RECORD_SIZE * i as u32));
+        let mut builder = PakBuilder::new(
+            Version::V11,
+            expected.mount_point(),
+            "pak.pak",
+            PakWriterOptions::default(),
+        )
+        .unwrap();
+        let source = builder.add_source_pak(read_v11_pak()).unwrap();
+        for path in &paths {
+            builder.copy_entry(path.as_str(), source, path).unwrap();
         }
-        PathHashIndex(path_hash_index)
-    };
+        assert!(matches!(
+            builder.copy_entry("missing.txt", source, "missing.txt"),
+            Err(UnrealpakError::EntryNotFound(_))
+        ));
 
-    debug!("path_hash_index = {:#X?}", &path_hash_index);
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
 
-    let full_directory_index = FullDirectoryIndex(full_directory_index);
+        // The entries were laid out in path order, so the data section is copied as is.
+        let copy = crate::PakReader::read(Cursor::new(&out_bytes), Version::V11, None).unwrap();
+        assert_eq!(copy.pak.index.records, expected.pak.index.records);
+        let last = copy.pak.index.records.last().unwrap();
+        let data_end = last.offset + stored_record_size(last);
+        assert_eq!(
+            &out_bytes[..data_end as usize],
+            &v11_pak[..data_end as usize]
+        );
 
-    debug!("full_directory_index = {:#X?}", &full_directory_index);
+        // Copying into a padded layout only moves the records.
+        let mut builder = PakBuilder::new(
+            Version::V11,
+            expected.mount_point(),
+            "pak.pak",
+            PakWriterOptions {
+                alignment: Some(Alignment::Patch(0x1000)),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let source = builder.add_source_pak(read_v11_pak()).unwrap();
+        for path in &paths {
+            builder.copy_entry(path.as_str(), source, path).unwrap();
+        }
+        let mut padded_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut padded_bytes)).unwrap();
+        let padded =
+            crate::PakReader::read(Cursor::new(&padded_bytes), Version::V11, None).unwrap();
+        for (record, expected) in padded.pak.index.records.iter().zip(&copy.pak.index.records) {
+            let size = stored_record_size(record) as usize;
+            let (start, expected_start) = (record.offset as usize, expected.offset as usize);
+            assert_eq!(
+                &padded_bytes[start..start + size],
+                &out_bytes[expected_start..expected_start + size]
+            );
+        }
+    }
 
-    let mount_point = mount_point
-        .to_path_buf()
-        .into_os_string()
-        .into_string()
-        .map_err(UnrealpakError::OsString)?;
+    #[test]
+    fn test_pak_builder_deduplicates_identical_entries() {
+        let write = |deduplicate| {
+            let options = PakWriterOptions {
+                deduplicate,
+                ..Default::default()
+            };
+            let mut builder =
+                PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+            builder
+                .add_file("Game/a.uexp", vec![0u8; 2048])
+                .add_reader("Game/b.uexp", Cursor::new(vec![0u8; 2048]))
+                .add_file("Game/c.uexp", vec![1u8; 2048])
+                .add_file_with_options(
+                    "Game/d.uexp",
+                    vec![0u8; 2048],
+                    EntryOptions {
+                        compression_method: Some(Compression::Zlib),
+                        ..Default::default()
+                    },
+                );
+            let mut out_bytes = vec![];
+            let summary = builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+            (out_bytes, summary)
+        };
 
-    let index = Index {
-        mount_point,
-        record_count: file_paths.len() as u32,
-        path_hash_seed: Some(path_hash_seed as u64),
-        path_hash_index: Some(path_hash_index),
-        full_directory_index: Some(full_directory_index),
-        records,
-    };
+        let (out_bytes, summary) = write(true);
+        assert_eq!(
+            summary,
+            WriteSummary {
+                entry_count: 4,
+                deduplicated_entries: 1,
+                deduplicated_bytes: 0x35 + 2048,
+            }
+        );
+        let pak =
+            crate::PakReader::read(Cursor::new(out_bytes.clone()), Version::V11, None).unwrap();
+        assert_eq!(pak.files().count(), 4);
+        let records = &pak.pak.index.records;
+        assert_eq!(records[0].offset, records[1].offset);
+        assert_ne!(records[0].offset, records[3].offset);
 
-    let mut index_buf = vec![];
-    let mut index_buf_writer = Cursor::new(&mut index_buf);
-    write_index(
-        &mut index_buf_writer,
-        &index,
-        writer.stream_position()?,
-        version,
-    )?;
+        let (duplicated_bytes, summary) = write(false);
+        assert_eq!(summary.deduplicated_entries, 0);
+        // The index has the same size either way, since every path keeps its own record.
+        assert_eq!(duplicated_bytes.len() - out_bytes.len(), 0x35 + 2048);
+    }
 
-    let index_offset = writer.stream_position()?;
-    let index_size = index.serialized_size(version);
-    dbg!(index_buf.len());
-    let index_hash = {
-        let mut hasher = Sha1::new();
-        dbg!(&index_buf[..index_size as usize].len());
-        hasher.update(&index_buf[..index_size as usize]);
-        Hash(hasher.finalize().into())
-    };
+    #[test]
+    fn test_pak_builder_applies_entry_rules() {
+        let encrypted = EntryOptions {
+            encrypted: Some(true),
+            ..Default::default()
+        };
+        let options = PakWriterOptions {
+            compression_method: Compression::Zlib,
+            encrypt_data: Some(aes_key()),
+            rules: vec![
+                EntryRule::glob(
+                    "*",
+                    EntryOptions {
+                        encrypted: Some(false),
+                        ..Default::default()
+                    },
+                ),
+                EntryRule::extension("uasset", encrypted.clone()),
+                EntryRule::extension(".INI", encrypted),
+                EntryRule::glob(
+                    "game/movies/*",
+                    EntryOptions {
+                        compression_method: Some(Compression::None),
+                        ..Default::default()
+                    },
+                ),
+            ],
+            ..Default::default()
+        };
+        let mut builder = PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+        builder
+            .add_file("Game/Config/Game.ini", vec![0u8; 64])
+            .add_file("Game/Maps/Main.uasset", vec![0u8; 64])
+            .add_file("Game/Maps/Main.uexp", vec![0u8; 64])
+            .add_file("Game/Movies/Intro.uasset", vec![0u8; 64])
+            .add_file_with_options(
+                "Game/Movies/Outro.mp4",
+                vec![0u8; 64],
+                EntryOptions {
+                    compression_method: Some(Compression::Zlib),
+                    ..Default::default()
+                },
+            );
+        let mut out_bytes = vec![];
+        builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
 
-    debug!("index_hash = {:0x?}", index_hash);
+        let pak = crate::PakReader::read(Cursor::new(&out_bytes), Version::V11, None).unwrap();
+        let layout = pak
+            .pak
+            .index
+            .records
+            .iter()
+            .map(|r| (r.compression_method, r.is_encrypted.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            layout,
+            vec![
+                (Compression::Zlib, true),
+                (Compression::Zlib, true),
+                (Compression::Zlib, false),
+                (Compression::None, true),
+                (Compression::Zlib, false),
+            ]
+        );
+    }
 
-    writer.write_all(&index_buf)?;
+    #[test]
+    fn test_pak_builder_rejects_unsupported_compression() {
+        let options = PakWriterOptions {
+            compression_method: Compression::Gzip,
+            ..Default::default()
+        };
+        assert!(matches!(
+            PakBuilder::new(Version::V11, "../../../", "pak.pak", options),
+            Err(UnrealpakError::UnsupportedCompression(Compression::Gzip))
+        ));
 
-    let footer = Footer {
-        encryption_key_guid: Some(options.encrypt_data.unwrap_or(0)),
-        is_index_encrypted: Some(false),
-        magic: MAGIC,
-        version,
-        index_offset,
-        index_size,
-        index_hash,
-        is_index_frozen: None,
-        // TODO: implement compression
-        compression_methods: Some(vec![0u8; 160]),
-    };
+        let oodle = EntryOptions {
+            compression_method: Some(Compression::Oodle),
+            ..Default::default()
+        };
+        let options = PakWriterOptions {
+            rules: vec![EntryRule::extension("uasset", oodle.clone())],
+            ..Default::default()
+        };
+        assert!(matches!(
+            PakBuilder::new(Version::V11, "../../../", "pak.pak", options),
+            Err(UnrealpakError::UnsupportedCompression(Compression::Oodle))
+        ));
 
-    write_footer(writer, &footer)?;
+        let mut builder =
+            PakBuilder::new(Version::V11, "../../../", "pak.pak", Default::default()).unwrap();
+        builder
+            .add_file("Game/a.txt", vec![0u8; 64])
+            .add_file_with_options("Game/b.uasset", vec![0u8; 64], oodle);
+        let mut out_bytes = vec![];
+        assert!(matches!(
+            builder.finish(&mut Cursor::new(&mut out_bytes)),
+            Err(UnrealpakError::UnsupportedCompression(Compression::Oodle))
+        ));
+        // The entry options are checked before any data is written.
+        assert!(out_bytes.is_empty());
+    }
 
-    Ok(())
-}
+    #[test]
+    fn test_pak_builder_skips_incompressible_entries() {
+        let mut state = 0x2545F491u32;
+        let noise = (0..0x18000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
 
-#[cfg(unix)]
-fn convert_unix_path_to_utf16le_bytes<P: AsRef<Path>>(path: P) -> Vec<u8> {
-    use std::os::unix::ffi::OsStrExt;
-    path.as_ref()
-        .as_os_str()
-        .as_bytes()
-        .iter()
-        .flat_map(|&b| [b, 0])
-        .collect()
-}
+        let write = |skip_incompressible| {
+            let options = PakWriterOptions {
+                compression_method: Compression::Zlib,
+                skip_incompressible,
+                ..Default::default()
+            };
+            let mut builder =
+                PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+            builder
+                .add_reader("noise.bin", Cursor::new(noise.clone()))
+                .add_file("zeros.bin", vec![0u8; 2048]);
+            let mut out_bytes = vec![];
+            builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+            let pak = crate::PakReader::read(Cursor::new(out_bytes), Version::V11, None).unwrap();
+            pak.pak
+                .index
+                .records
+                .iter()
+                .map(|r| (r.compression_method, r.compressed_size))
+                .collect::<Vec<_>>()
+        };
 
-#[cfg(unix)]
-fn utf16le_path_to_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, UnrealpakError> {
-    Ok(path
-        .as_ref()
-        .to_path_buf()
-        .into_os_string()
-        .into_string()
-        .map_err(UnrealpakError::OsString)?
-        .as_bytes()
-        .to_owned())
-}
+        let records = write(false);
+        assert_eq!(records[0].0, Compression::Zlib);
+        assert!(records[0].1 >= noise.len() as u64);
+        assert_eq!(
+            write(true),
+            vec![
+                (Compression::None, noise.len() as u64),
+                (Compression::Zlib, records[1].1),
+            ]
+        );
+    }
 
-#[cfg(windows)]
-fn utf16le_path_to_bytes<P: AsRef<Path>>(path: P) -> Vec<u8> {
-    use std::os::windows::ffi::OsStrExt;
-    let path: Vec<u16> = path.as_ref().as_os_str().encode_wide();
-    let mut buf = Vec::with_capacity(path.len() / 2);
-    LittleEndian::write_u16_into(&path, &mut buf);
-    buf
-}
+    #[test]
+    fn test_pak_builder_reports_progress() {
+        let mut builder =
+            PakBuilder::new(Version::V11, "../../../", "pak.pak", Default::default()).unwrap();
+        builder
+            .add_file("Game/b.bin", vec![1u8; 0x18000])
+            .add_file("Game/a.bin", vec![2u8; 100])
+            .add_file("Game/empty.bin", vec![]);
 
-#[cfg(not(any(unix, windows)))]
-fn utf16le_path_to_bytes<P: AsRef<Path>>(path: P) -> Vec<u8> {
-    unimplemented!("unsupported platform")
-}
+        let mut events = vec![];
+        builder
+            .finish_with_progress(&mut Cursor::new(vec![]), &mut |event: &ProgressEvent| {
+                events.push((
+                    event.path.to_owned(),
+                    event.entry_index,
+                    event.entry_bytes,
+                    event.entry_bytes_total,
+                    event.bytes,
+                ));
+                assert_eq!(event.entry_count, 3);
+                assert_eq!(event.bytes_total, 0x18000 + 100);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
 
-#[track_caller]
-fn zero_pad(v: &mut Vec<u8>, alignment: usize) {
-    assert!(alignment >= 1);
-    if v.len() % alignment != 0 {
-        v.extend(std::iter::repeat(0).take(((v.len() + alignment - 1) / alignment) * alignment))
+        assert_eq!(
+            events.first().unwrap(),
+            &("Game/b.bin".to_owned(), 0, 0, 0x18000, 0)
+        );
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| e.0 == "Game/b.bin")
+                .map(|e| e.2)
+                .max(),
+            Some(0x18000)
+        );
+        assert!(events.contains(&("Game/a.bin".to_owned(), 1, 100, 100, 0x18000 + 100)));
+        assert_eq!(
+            events.last().unwrap(),
+            &("Game/empty.bin".to_owned(), 2, 0, 0, 0x18000 + 100)
+        );
+        assert!(events.windows(2).all(|w| w[0].4 <= w[1].4));
     }
-    assert!(v.len() % alignment == 0);
-}
 
-fn encrypt(key: u128, bytes: &mut [u8]) {
-    use aes::cipher::BlockEncrypt;
-    let key = Aes256Enc::new_from_slice(&key.to_le_bytes()).unwrap();
-    for chunk in bytes.chunks_mut(16) {
-        key.encrypt_block(aes::Block::from_mut_slice(chunk))
-    }
-}
+    #[test]
+    fn test_pak_builder_cancelled_write_leaves_no_file() {
+        let dir = Path::new("./target/test_cancelled_write");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join("cancelled.pak");
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::compression::Compression;
-    use std::io::{Cursor, Write};
+        let build = || {
+            let mut builder = PakBuilder::new(
+                Version::V11,
+                "../mount/point/root/",
+                "/home/truman/projects/drg-modding/tools/unpak/tests/packs/pack_v11.pak",
+                Default::default(),
+            )
+            .unwrap();
+            builder
+                .add_from_disk(
+                    "directory/nested.txt",
+                    "./tests/pack/root/directory/nested.txt",
+                )
+                .add_from_disk("test.png", "./tests/pack/root/test.png")
+                .add_from_disk("test.txt", "./tests/pack/root/test.txt")
+                .add_from_disk("zeros.bin", "./tests/pack/root/zeros.bin");
+            builder
+        };
 
-    fn init_logger() {
-        let _ = env_logger::builder().is_test(true).try_init();
+        let err = build()
+            .finish_to_file(&path, &mut |event: &ProgressEvent| match event.bytes {
+                0 => ControlFlow::Continue(()),
+                _ => ControlFlow::Break(()),
+            })
+            .unwrap_err();
+        assert!(matches!(err, UnrealpakError::Cancelled));
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 0);
+
+        build()
+            .finish_to_file(&path, &mut |_: &ProgressEvent| ControlFlow::Continue(()))
+            .unwrap();
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+        let v11_pak = include_bytes!("../tests/packs/pack_v11.pak");
+        assert_eq!(&fs::read(&path).unwrap()[..], &v11_pak[..]);
     }
 
     #[test]
-    fn try_index_hash() {
-        let index: [u8; 0xAD] = [
-            0x15, 0x00, 0x00, 0x00, 0x2E, 0x2E, 0x2F, 0x6D, 0x6F, 0x75, 0x6E, 0x74, 0x2F, 0x70,
-            0x6F, 0x69, 0x6E, 0x74, 0x2F, 0x72, 0x6F, 0x6F, 0x74, 0x2F, 0x00, 0x04, 0x00, 0x00,
-            0x00, 0x7D, 0x5A, 0x5C, 0x20, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xA4,
-            0x35, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x66, 0x8E, 0x2D, 0xAF, 0x1C, 0x70, 0xB4, 0xC3, 0x62, 0x9F, 0x59, 0xCA, 0x98,
-            0xF5, 0xEA, 0x3E, 0xA5, 0x56, 0xEC, 0xA7, 0x01, 0x00, 0x00, 0x00, 0xDC, 0x35, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x68, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x70,
-            0x79, 0xEA, 0xE0, 0x7F, 0x19, 0xA2, 0x51, 0xA1, 0x4E, 0xFD, 0xE5, 0xA4, 0x8D, 0x7D,
-            0x22, 0x7E, 0xD5, 0xAE, 0x73, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x00,
-            0x00, 0x00, 0x00, 0x54, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x89, 0x02, 0x00,
-            0x00, 0x11, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0xCF, 0x2A, 0x00, 0x00, 0xBE,
-            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0xC2, 0x2C, 0x00, 0x00, 0x00, 0x08, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00,
-        ];
-        let guess_hash: [u8; 20] = {
-            let mut hasher = Sha1::new();
-            hasher.update(&index[..]);
-            hasher.finalize().into()
+    fn test_pak_builder_encrypted_entry_requires_key() {
+        let options = PakWriterOptions {
+            compression_method: Compression::None,
+            ..Default::default()
         };
+        let mut builder = PakBuilder::new(Version::V11, "../../../", "pak.pak", options).unwrap();
+        builder.add_file_with_options(
+            "a.bin",
+            vec![0u8; 2048],
+            EntryOptions {
+                encrypted: Some(true),
+                ..Default::default()
+            },
+        );
 
-        let expected_hash: [u8; 0x14] = [
-            0x34, 0x72, 0xD7, 0xAA, 0x90, 0x47, 0xD4, 0xC8, 0x05, 0x3F, 0x9B, 0x42, 0x48, 0x13,
-            0x25, 0xC3, 0x88, 0x09, 0x8F, 0x07,
-        ];
-
-        assert_eq!(guess_hash, expected_hash);
+        let mut out_bytes = vec![];
+        let err = builder
+            .finish(&mut Cursor::new(&mut out_bytes))
+            .unwrap_err();
+        assert!(matches!(err, UnrealpakError::MissingKey));
     }
 
     #[test]
-    fn test_write_pak_v11() {
+    fn test_write_pak_v11_non_ascii_paths() {
         init_logger();
 
+        let pack_root_path = Path::new("./target/test_non_ascii_root");
+        let _ = fs::remove_dir_all(pack_root_path);
+        fs::create_dir_all(pack_root_path.join("テクスチャ")).unwrap();
+        fs::write(pack_root_path.join("テクスチャ/草.uasset"), b"grass").unwrap();
+        fs::write(pack_root_path.join("资源.txt"), b"resource").unwrap();
+        fs::write(pack_root_path.join("test.txt"), b"test").unwrap();
+
         let mut out_bytes = vec![];
         let mut writer = Cursor::new(&mut out_bytes);
-        let pack_root_path = "./tests/pack/root";
-        let output_pak_path =
-            "/home/truman/projects/drg-modding/tools/unpak/tests/packs/pack_v11.pak";
         write_pak(
             &mut writer,
-            VersionMajor::Fnv64BugFix,
+            Version::V11,
             pack_root_path,
-            "../mount/point/root/",
-            output_pak_path,
+            "../mount/point/ルート/",
+            "/tmp/pack_v11_non_ascii.pak",
             &super::PakWriterOptions {
                 compression_method: Compression::None,
-                encrypt_data: None,
-                encrypt_index: None,
+                ..Default::default()
             },
         )
         .unwrap();
 
-        let v11_pak = include_bytes!("../tests/packs/pack_v11.pak");
+        let pak = crate::pak_reader::PakReader::read(Cursor::new(&out_bytes), Version::V11, None)
+            .unwrap();
+        assert_eq!(pak.pak.index.mount_point, "../mount/point/ルート/");
+        assert_eq!(
+            pak.files().collect::<Vec<_>>(),
+            vec!["test.txt", "资源.txt", "テクスチャ/草.uasset"]
+        );
+    }
 
-        fs::write("./target/test_v11.pak", &out_bytes).unwrap();
+    #[test]
+    fn test_from_pak_reencrypts_entries_in_blocks() {
+        let data = (0..COMPRESSION_BLOCK_SIZE * 2 + 1234)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let keys = [[1u8; 32], [2u8; 32]];
+        let options = |key: &[u8; 32]| PakWriterOptions {
+            encrypt_data: Some(Aes256Enc::new_from_slice(key).unwrap()),
+            ..Default::default()
+        };
+        let read = |out_bytes: Vec<u8>, key: &[u8; 32]| {
+            let key = Aes256Dec::new_from_slice(key).unwrap();
+            crate::PakReader::read(Cursor::new(out_bytes), Version::V11, Some(key)).unwrap()
+        };
 
-        assert_eq!(out_bytes.len(), v11_pak.len());
-        assert_eq!(&out_bytes[..], &v11_pak[..]);
+        let build = |key: &[u8; 32]| {
+            let mut builder =
+                PakBuilder::new(Version::V11, "../../../", "pak.pak", options(key)).unwrap();
+            builder
+                .add_file("Game/big.bin", data.clone())
+                .add_file_with_options(
+                    "Game/big.zlib",
+                    data.clone(),
+                    EntryOptions {
+                        compression_method: Some(Compression::Zlib),
+                        ..Default::default()
+                    },
+                );
+            let mut out_bytes = vec![];
+            builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+            out_bytes
+        };
+
+        let mut reencrypted = vec![];
+        PakBuilder::from_pak(read(build(&keys[0]), &keys[0]), options(&keys[1]))
+            .unwrap()
+            .finish(&mut Cursor::new(&mut reencrypted))
+            .unwrap();
+        // Re-encrypting gives the same data and hashes as encrypting with the new key.
+        assert_eq!(reencrypted, build(&keys[1]));
+        let mut pak = read(reencrypted, &keys[1]);
+        assert_eq!(pak.get("Game/big.bin").unwrap(), data);
+        assert_eq!(pak.get("Game/big.zlib").unwrap(), data);
+    }
+
+    #[test]
+    fn test_merge_reencrypts_entries() {
+        init_logger();
+
+        let v11_pak = include_bytes!("../tests/packs/pack_v11_compress_encrypt.pak");
+        let source_key = {
+            let key = base64::engine::general_purpose::STANDARD
+                .decode(AES_KEY)
+                .unwrap();
+            Aes256Dec::new_from_slice(&key).unwrap()
+        };
+        let read_v11_pak = || {
+            crate::PakReader::read(Cursor::new(v11_pak), Version::V11, Some(source_key.clone()))
+                .unwrap()
+        };
+        let merge_with = |key: Aes256Enc| {
+            let options = PakWriterOptions {
+                encrypt_data: Some(key),
+                ..Default::default()
+            };
+            let builder = PakBuilder::merge(
+                vec![read_v11_pak()],
+                Version::V11,
+                "merged.pak",
+                ConflictPolicy::LastWins,
+                options,
+            )
+            .unwrap();
+            let mut out_bytes = vec![];
+            builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+            out_bytes
+        };
+        let stored = |pak: &[u8], record: &Record| {
+            let start = (record.offset + record.serialized_size(Version::V11, EntryLocation::Data))
+                as usize;
+            pak[start..start + stored_size(record) as usize].to_vec()
+        };
+        let expected = read_v11_pak();
+
+        // With the same key, the data is copied as stored.
+        let out_bytes = merge_with(aes_key());
+        let merged = crate::PakReader::read(Cursor::new(&out_bytes), Version::V11, None).unwrap();
+        assert_eq!(merged.pak.index.records, expected.pak.index.records);
+
+        let other_key = [7u8; 32];
+        let out_bytes = merge_with(Aes256Enc::new_from_slice(&other_key).unwrap());
+        let merged = crate::PakReader::read(Cursor::new(&out_bytes), Version::V11, None).unwrap();
+        let other_key = Some(Aes256Dec::new_from_slice(&other_key).unwrap());
+        for path in expected.files() {
+            let record = merged.pak.index.find_record(&path).unwrap();
+            let expected_record = expected.pak.index.find_record(&path).unwrap();
+            assert_eq!(record.blocks, expected_record.blocks);
+
+            let mut data = stored(&out_bytes, record);
+            let mut header = Cursor::new(&out_bytes);
+            header.set_position(record.offset);
+            let header = read_record(&mut header, Version::V11, EntryLocation::Data).unwrap();
+            let hashed = match record.compression_method {
+                Compression::None => &data[..record.uncompressed_size as usize],
+                _ => &data[..],
+            };
+            assert_eq!(header.hash, Some(Hash(Sha1::digest(hashed).into())));
+            let mut expected_data = stored(v11_pak, expected_record);
+            assert_ne!(data, expected_data);
+            decrypt(&other_key, &mut data).unwrap();
+            decrypt(&Some(source_key.clone()), &mut expected_data).unwrap();
+            assert_eq!(data, expected_data, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_pak_builder_writes_delete_records() {
+        init_logger();
+
+        for version in [Version::V6, Version::V9, Version::V11] {
+            let mut builder =
+                PakBuilder::new(version, "../mount/point/", "pak.pak", Default::default()).unwrap();
+            builder
+                .add_file("kept.txt", b"kept".to_vec())
+                .add_delete_record("directory/gone.txt");
+            let mut out_bytes = vec![];
+            builder.finish(&mut Cursor::new(&mut out_bytes)).unwrap();
+
+            // Compacting copies the delete record along with the data of the rest.
+            let pak = crate::PakReader::read(Cursor::new(out_bytes), version, None).unwrap();
+            let mut compacted = vec![];
+            let options = PakWriterOptions {
+                deduplicate: true,
+                ..Default::default()
+            };
+            PakBuilder::from_pak(pak, options)
+                .unwrap()
+                .finish(&mut Cursor::new(&mut compacted))
+                .unwrap();
+
+            let mut pak = crate::PakReader::read(Cursor::new(compacted), version, None).unwrap();
+            assert_eq!(
+                pak.files().collect::<Vec<_>>(),
+                vec!["kept.txt", "directory/gone.txt"]
+            );
+            let record = pak.pak.index.find_record("directory/gone.txt").unwrap();
+            assert!(record.is_deleted);
+            assert!(!pak.pak.index.find_record("kept.txt").unwrap().is_deleted);
+            assert_eq!(pak.get("kept.txt").unwrap(), b"kept");
+            assert!(matches!(
+                pak.get("directory/gone.txt"),
+                Err(UnrealpakError::EntryNotFound(_))
+            ));
+            assert_eq!(pak.data_layout().unused, vec![]);
+        }
+
+        let mut builder = PakBuilder::new(
+            Version::V5,
+            "../mount/point/",
+            "pak.pak",
+            Default::default(),
+        )
+        .unwrap();
+        builder
+            .add_file("kept.txt", b"kept".to_vec())
+            .add_delete_record("gone.txt");
+        let mut out_bytes = vec![];
+        assert!(matches!(
+            builder.finish(&mut Cursor::new(&mut out_bytes)),
+            Err(UnrealpakError::IncompatibleEntry(path, 5)) if path == "gone.txt"
+        ));
+        // The delete record is rejected before any data is written.
+        assert!(out_bytes.is_empty());
     }
 }
@@ -0,0 +1,143 @@
+//! Patch paks holding the differences between two builds.
+
+use crate::diff::diff;
+use crate::errors::UnrealpakError;
+use crate::pak_reader::PakReader;
+use crate::pak_writer::{PakBuilder, PakWriterOptions};
+use crate::response_file::common_directory;
+use crate::version::VersionMajor;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+impl PakBuilder {
+    /// Creates a builder for a patch pak that brings `base` up to date with `build`, holding the
+    /// entries of `build` that are new or whose contents changed, as [`diff`] finds them. Entries
+    /// that `build` removed get delete records, unless the version of `build` predates
+    /// [`VersionMajor::DeleteRecords`], in which case they are left out and stay visible.
+    ///
+    /// The patch has the version of `build` and is mounted at the longest directory shared by both
+    /// mount points. Entries are copied as stored, in the order of their data in `build`. Name the
+    /// patch `*_P.pak` so that it overrides the paks of `base`, as [`pak_order`] explains.
+    ///
+    /// [`pak_order`]: crate::pak_order
+    pub fn patch<A, B, O>(
+        base: &mut PakReader<A>,
+        mut build: PakReader<B>,
+        output_pak_path: O,
+        options: PakWriterOptions,
+    ) -> Result<Self, UnrealpakError>
+    where
+        A: Read + Seek,
+        B: Read + Seek + 'static,
+        O: AsRef<Path>,
+    {
+        let pak_diff = diff(base, &mut build)?;
+        let version = build.pak.version;
+        let build_mount_point = build.full_path("");
+        let mount_point = common_directory([base.full_path("").as_str(), &build_mount_point]);
+
+        let mut updated = pak_diff
+            .added
+            .iter()
+            .chain(
+                pak_diff
+                    .changed
+                    .iter()
+                    .filter(|change| change.content_changed)
+                    .map(|change| &change.b),
+            )
+            .map(|entry| {
+                let path = entry.path[build_mount_point.len()..].to_owned();
+                let offset = build
+                    .pak
+                    .index
+                    .find_record(&path)
+                    .map(|record| record.offset);
+                (entry.path.as_str(), path, offset)
+            })
+            .collect::<Vec<_>>();
+        updated.sort_by_key(|&(_, _, offset)| offset);
+
+        let mut builder = PakBuilder::new(version, mount_point.clone(), output_pak_path, options)?;
+        let source = builder.add_source_pak(build)?;
+        for (full_path, path, _) in updated {
+            builder.copy_entry(&full_path[mount_point.len()..], source, &path)?;
+        }
+        if version >= VersionMajor::DeleteRecords {
+            for entry in pak_diff.removed.iter().filter(|entry| !entry.deleted) {
+                builder.add_delete_record(&entry.path[mount_point.len()..]);
+            }
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::pak_writer::build_test_pak;
+    use crate::version::Version;
+    use std::io::Cursor;
+
+    fn init_logger() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_patch_from_two_builds() {
+        init_logger();
+
+        for version in [Version::V5, Version::V8B, Version::V11] {
+            let mut base = build_test_pak(
+                version,
+                "../../../Game/",
+                &[
+                    ("Content/same.txt", Some("same"), Compression::None),
+                    ("Content/edited.txt", Some("before"), Compression::None),
+                    ("Config/removed.ini", Some("removed"), Compression::None),
+                ],
+            );
+            let build = build_test_pak(
+                version,
+                "../../../Game/Content/",
+                &[
+                    ("same.txt", Some("same"), Compression::None),
+                    ("edited.txt", Some("after"), Compression::None),
+                    ("added.txt", Some("added"), Compression::None),
+                ],
+            );
+            let options = PakWriterOptions {
+                deduplicate: true,
+                ..Default::default()
+            };
+            let mut out_bytes = vec![];
+            PakBuilder::patch(&mut base, build, "pak_P.pak", options)
+                .unwrap()
+                .finish(&mut Cursor::new(&mut out_bytes))
+                .unwrap();
+
+            let mut patch = crate::PakReader::read(Cursor::new(out_bytes), version, None).unwrap();
+            assert_eq!(patch.mount_point(), "../../../Game/");
+            let mut files = patch.files().collect::<Vec<_>>();
+            files.sort();
+            match version >= VersionMajor::DeleteRecords {
+                true => {
+                    assert_eq!(
+                        files,
+                        vec![
+                            "Config/removed.ini",
+                            "Content/added.txt",
+                            "Content/edited.txt"
+                        ]
+                    );
+                    let record = patch.pak.index.find_record("Config/removed.ini").unwrap();
+                    assert!(record.is_deleted);
+                }
+                false => assert_eq!(files, vec!["Content/added.txt", "Content/edited.txt"]),
+            }
+            assert_eq!(patch.get("Content/edited.txt").unwrap(), b"after");
+            assert_eq!(patch.get("Content/added.txt").unwrap(), b"added");
+        }
+    }
+}
@@ -4,7 +4,7 @@ use crate::errors::UnrealpakError;
 use std::io::{Read, Write};
 
 /// Hash and EncodedRecord offset entries.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct PathHashIndex(pub(crate) Vec<(u64, u32)>);
 
 impl PathHashIndex {
@@ -0,0 +1,191 @@
+//! Progress reporting for jobs that process many entries, such as writing a pak.
+
+use crate::errors::UnrealpakError;
+use std::io::{self, Read, Write};
+use std::ops::ControlFlow;
+
+/// Progress of a job, reported as each entry's data is processed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProgressEvent<'a> {
+    pub path: &'a str,
+    /// Index of the entry being processed, out of `entry_count`.
+    pub entry_index: usize,
+    pub entry_count: usize,
+    /// Bytes of this entry processed so far, out of `entry_bytes_total`.
+    pub entry_bytes: u64,
+    pub entry_bytes_total: u64,
+    /// Bytes of every entry processed so far, out of `bytes_total`.
+    pub bytes: u64,
+    pub bytes_total: u64,
+}
+
+/// Receives [`ProgressEvent`]s. Returning [`ControlFlow::Break`] cancels the job, which then
+/// fails with [`UnrealpakError::Cancelled`].
+pub trait Progress {
+    fn on_progress(&mut self, event: &ProgressEvent) -> ControlFlow<()>;
+}
+
+impl<F> Progress for F
+where
+    F: FnMut(&ProgressEvent) -> ControlFlow<()>,
+{
+    fn on_progress(&mut self, event: &ProgressEvent) -> ControlFlow<()> {
+        self(event)
+    }
+}
+
+/// A [`Progress`] that ignores every event.
+pub(crate) struct NoProgress;
+
+impl Progress for NoProgress {
+    fn on_progress(&mut self, _: &ProgressEvent) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+}
+
+/// Keeps the running totals of a job and forwards them to a [`Progress`].
+pub(crate) struct ProgressTracker<'a> {
+    progress: &'a mut dyn Progress,
+    path: String,
+    entry_index: usize,
+    entry_count: usize,
+    entry_bytes: u64,
+    entry_bytes_total: u64,
+    bytes: u64,
+    bytes_total: u64,
+    cancelled: bool,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub(crate) fn new(
+        progress: &'a mut dyn Progress,
+        entry_count: usize,
+        bytes_total: u64,
+    ) -> Self {
+        ProgressTracker {
+            progress,
+            path: String::new(),
+            entry_index: 0,
+            entry_count,
+            entry_bytes: 0,
+            entry_bytes_total: 0,
+            bytes: 0,
+            bytes_total,
+            cancelled: false,
+        }
+    }
+
+    pub(crate) fn start_entry(
+        &mut self,
+        entry_index: usize,
+        path: &str,
+        entry_bytes_total: u64,
+    ) -> Result<(), UnrealpakError> {
+        self.resume_entry(entry_index, path, entry_bytes_total, 0);
+        self.report()
+    }
+
+    /// Makes `entry_index` the current entry again without reporting it, with `entry_bytes` of it
+    /// already processed, for jobs that process several entries at once.
+    pub(crate) fn resume_entry(
+        &mut self,
+        entry_index: usize,
+        path: &str,
+        entry_bytes_total: u64,
+        entry_bytes: u64,
+    ) {
+        self.path = path.to_owned();
+        self.entry_index = entry_index;
+        self.entry_bytes = entry_bytes;
+        self.entry_bytes_total = entry_bytes_total;
+    }
+
+    pub(crate) fn advance(&mut self, bytes: u64) -> Result<(), UnrealpakError> {
+        self.entry_bytes += bytes;
+        self.bytes += bytes;
+        self.report()
+    }
+
+    /// Wraps `inner` so that every read advances the current entry.
+    pub(crate) fn reader<R: Read>(&mut self, inner: R) -> ProgressReader<'_, 'a, R> {
+        ProgressReader {
+            inner,
+            tracker: self,
+        }
+    }
+
+    /// Wraps `inner` so that every write advances the current entry.
+    pub(crate) fn writer<W: Write>(&mut self, inner: W) -> ProgressWriter<'_, 'a, W> {
+        ProgressWriter {
+            inner,
+            tracker: self,
+        }
+    }
+
+    /// Turns an error raised while reading through [`ProgressTracker::reader`] or writing through
+    /// [`ProgressTracker::writer`] back into [`UnrealpakError::Cancelled`] if that is what caused
+    /// it.
+    pub(crate) fn error(&self, error: UnrealpakError) -> UnrealpakError {
+        match self.cancelled {
+            true => UnrealpakError::Cancelled,
+            false => error,
+        }
+    }
+
+    fn report(&mut self) -> Result<(), UnrealpakError> {
+        let event = ProgressEvent {
+            path: &self.path,
+            entry_index: self.entry_index,
+            entry_count: self.entry_count,
+            entry_bytes: self.entry_bytes,
+            entry_bytes_total: self.entry_bytes_total,
+            bytes: self.bytes,
+            bytes_total: self.bytes_total,
+        };
+        match self.progress.on_progress(&event) {
+            ControlFlow::Continue(()) => Ok(()),
+            ControlFlow::Break(()) => {
+                self.cancelled = true;
+                Err(UnrealpakError::Cancelled)
+            }
+        }
+    }
+}
+
+pub(crate) struct ProgressReader<'t, 'a, R> {
+    inner: R,
+    tracker: &'t mut ProgressTracker<'a>,
+}
+
+impl<R: Read> Read for ProgressReader<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.tracker
+                .advance(n as u64)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        Ok(n)
+    }
+}
+
+pub(crate) struct ProgressWriter<'t, 'a, W> {
+    inner: W,
+    tracker: &'t mut ProgressTracker<'a>,
+}
+
+impl<W: Write> Write for ProgressWriter<'_, '_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.tracker
+                .advance(n as u64)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
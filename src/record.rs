@@ -1,14 +1,13 @@
-use crate::block::{write_block, Block};
+use crate::block::{read_block, write_block, Block};
 use crate::compression::Compression;
 use crate::errors::UnrealpakError;
-use crate::ext::WriteExt;
+use crate::ext::ReadExt;
 use crate::hash::Hash;
-use crate::version::VersionMajor;
+use crate::version::{Version, VersionMajor};
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use sha1::digest::typenum::Pow;
 use std::io::{Read, Write};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct Record {
     pub(crate) offset: u64,
     pub(crate) uncompressed_size: u64,
@@ -19,134 +18,150 @@ pub(crate) struct Record {
     pub(crate) blocks: Option<Vec<Block>>,
     pub(crate) is_encrypted: Option<bool>,
     pub(crate) compression_block_size: Option<u32>,
+    /// Marks a path as deleted, hiding it in paks of lower priority. Delete records have no data.
+    pub(crate) is_deleted: bool,
 }
 
 impl Record {
-    pub(crate) fn serialized_size(
-        &self,
-        version: VersionMajor,
-        compression_method: Compression,
-    ) -> u64 {
-        if version >= VersionMajor::PathHashIndex {
-            let mut size = 4; // flags
-
-            size += if self.offset > u32::MAX as u64 { 8 } else { 4 };
-            size += if self.uncompressed_size > u32::MAX as u64 {
-                8
-            } else {
-                4
-            };
-            if self.compression_method != Compression::None {
-                size += if self.compressed_size > u32::MAX as u64 {
-                    8
-                } else {
-                    4
-                }
-            }
-
-            if let Some(blocks) = &self.blocks {
-                if blocks.len() > 0 && (self.is_encrypted.unwrap_or_default() || blocks.len() == 1)
-                {
-                    for _ in blocks {
-                        size += 4;
-                    }
-                }
-            }
+    /// A record deleting its path from paks of lower priority.
+    pub(crate) fn deleted() -> Self {
+        Record {
+            offset: 0,
+            uncompressed_size: 0,
+            compression_method: Compression::None,
+            compressed_size: 0,
+            timestamp: None,
+            hash: Some(Hash([0; 20])),
+            blocks: None,
+            is_encrypted: Some(false),
+            compression_block_size: Some(0),
+            is_deleted: true,
+        }
+    }
 
-            size
+    /// Size of the record when written by [`write_record`] at `location`.
+    pub(crate) fn serialized_size(&self, version: Version, location: EntryLocation) -> u64 {
+        let block_count = match self.compression_method {
+            Compression::None => 0,
+            _ => self.blocks.as_ref().map_or(0, |blocks| blocks.len() as u32),
+        };
+        if is_encoded(version, location, self) {
+            encoded_size(
+                self.compression_method,
+                self.offset,
+                self.uncompressed_size,
+                self.compressed_size,
+                self.compression_block_size.unwrap_or_default(),
+                block_count,
+                self.is_encrypted.unwrap_or_default(),
+            )
         } else {
-            let mut size = 0;
-            size += 8; // offset
-            size += 8; // compressed
-            size += 8; // uncompressed
-                       // FIXME: this does not handle v8a for now.
-            size += match version != VersionMajor::FNameBasedCompression {
-                true => 4,  // 32 bit compression
-                false => 1, // 8 bit compression
-            };
-            size += match version == VersionMajor::Initial {
-                true => 8, // timestamp
-                false => 0,
-            };
-            size += 20; // hash
-            size += match compression_method != Compression::None {
-                true => 4 + (8 + 8) * self.blocks.as_ref().unwrap().len() as u64, // blocks
-                false => 0,
-            };
-            size += 1; // encrypted
-            size += match version >= VersionMajor::CompressionEncryption {
-                true => 4, // blocks uncompressed
-                false => 0,
-            };
-            size
+            header_size(version, self.compression_method, block_count)
         }
     }
 }
 
-fn serialized_size(
-    version: VersionMajor,
+/// Size of the record header written in front of each entry's data. Indexes before
+/// [`VersionMajor::PathHashIndex`] store their records in the same layout.
+pub(crate) fn header_size(
+    version: Version,
+    compression_method: Compression,
+    block_count: u32,
+) -> u64 {
+    let mut size = 0;
+    size += 8; // offset
+    size += 8; // compressed
+    size += 8; // uncompressed
+    size += match version != Version::V8A {
+        true => 4,  // 32 bit compression
+        false => 1, // 8 bit compression
+    };
+    size += match version == VersionMajor::Initial {
+        true => 8, // timestamp
+        false => 0,
+    };
+    size += 20; // hash
+    if version >= VersionMajor::CompressionEncryption {
+        size += match compression_method != Compression::None {
+            true => 4 + (8 + 8) * block_count as u64, // blocks
+            false => 0,
+        };
+        size += 1; // encrypted
+        size += 4; // blocks uncompressed
+    }
+    size
+}
+
+/// Size of a record in the encoded record section of a [`VersionMajor::PathHashIndex`] index.
+fn encoded_size(
     compression_method: Compression,
     offset: u64,
     uncompressed_size: u64,
     compressed_size: u64,
+    compression_block_size: u32,
     block_count: u32,
     is_encrypted: bool,
 ) -> u64 {
-    if version >= VersionMajor::PathHashIndex {
-        let mut size = 4u64; // flags
+    let mut size = 4u64; // flags
+
+    if encode_compression_block_size(compression_block_size, block_count) == 0x3f {
+        size += 4; // compression block size which does not fit into the flags
+    }
 
-        size += if offset > u32::MAX as u64 { 8 } else { 4 };
-        size += if uncompressed_size > u32::MAX as u64 {
+    size += if offset > u32::MAX as u64 { 8 } else { 4 };
+    size += if uncompressed_size > u32::MAX as u64 {
+        8
+    } else {
+        4
+    };
+    if compression_method != Compression::None {
+        size += if compressed_size > u32::MAX as u64 {
             8
         } else {
             4
         };
-        if compression_method != Compression::None {
-            size += if compressed_size > u32::MAX as u64 {
-                8
-            } else {
-                4
-            }
-        }
 
-        if block_count > 0 && (is_encrypted || block_count == 1) {
+        if block_count > 1 || (block_count == 1 && is_encrypted) {
             size += 4 * block_count as u64;
         }
+    }
+
+    size
+}
 
-        size
+/// Compression block sizes are stored in the flags as multiples of 2KiB; `0x3f` marks a block
+/// size that is written out separately.
+fn encode_compression_block_size(compression_block_size: u32, block_count: u32) -> u32 {
+    if block_count == 0 {
+        return 0;
+    }
+    let encoded = compression_block_size >> 11;
+    if encoded << 11 != compression_block_size || encoded > 0x3f {
+        0x3f
     } else {
-        let mut size = 0;
-        size += 8; // offset
-        size += 8; // compressed
-        size += 8; // uncompressed
-                   // FIXME: this does not handle v8a for now.
-        size += match version != VersionMajor::FNameBasedCompression {
-            true => 4,  // 32 bit compression
-            false => 1, // 8 bit compression
-        };
-        size += match version == VersionMajor::Initial {
-            true => 8, // timestamp
-            false => 0,
-        };
-        size += 20; // hash
-        size += match compression_method != Compression::None {
-            true => 4 + (8 + 8) * block_count as u64, // blocks
-            false => 0,
-        };
-        size += 1; // encrypted
-        size += match version >= VersionMajor::CompressionEncryption {
-            true => 4, // blocks uncompressed
-            false => 0,
-        };
-        size
+        encoded
     }
 }
 
+/// Flags of records in the legacy layout from [`VersionMajor::CompressionEncryption`] on.
+const FLAG_ENCRYPTED: u8 = 0x01;
+const FLAG_DELETED: u8 = 0x02;
+
+/// Whether `record` is encoded at `location`. Delete records cannot be encoded, so indexes from
+/// [`VersionMajor::PathHashIndex`] on list them separately in the legacy layout.
+fn is_encoded(version: Version, location: EntryLocation, record: &Record) -> bool {
+    version >= VersionMajor::PathHashIndex && location == EntryLocation::Index && !record.is_deleted
+}
+
+/// Reads a record written by [`write_record`] at `location`. Records in front of entry data
+/// always use the legacy layout, whose offset is zero, as do the records of an index that
+/// cannot be encoded, which are read at [`EntryLocation::Data`].
 pub(crate) fn read_record<R: Read>(
     reader: &mut R,
-    version: VersionMajor,
+    version: Version,
+    location: EntryLocation,
 ) -> Result<Record, UnrealpakError> {
-    if version >= VersionMajor::PathHashIndex {
+    if version >= VersionMajor::PathHashIndex && location == EntryLocation::Index {
         let bits = reader.read_u32::<LE>()?;
         let compression_method = match (bits >> 23) & 0x3f {
             0x01 | 0x10 | 0x20 => Compression::Zlib,
@@ -189,15 +204,7 @@ pub(crate) fn read_record<R: Read>(
         let offset_base = match version >= VersionMajor::RelativeChunkOffsets {
             true => 0,
             false => offset,
-        } + serialized_size(
-            version,
-            compression_method,
-            offset,
-            compressed_size,
-            uncompressed_size,
-            compression_block_count,
-            is_encrypted,
-        );
+        } + header_size(version, compression_method, compression_block_count);
 
         let blocks = if compression_block_count == 1 && !is_encrypted {
             Some(vec![Block {
@@ -236,9 +243,55 @@ pub(crate) fn read_record<R: Read>(
             is_encrypted: Some(is_encrypted),
             compression_block_size: Some(block_uncompressed_size),
             hash: None,
+            is_deleted: false,
         })
     } else {
-        todo!()
+        let offset = reader.read_u64::<LE>()?;
+        let compressed_size = reader.read_u64::<LE>()?;
+        let uncompressed_size = reader.read_u64::<LE>()?;
+        let compression = if version == Version::V8A {
+            reader.read_u8()? as u32
+        } else {
+            reader.read_u32::<LE>()?
+        };
+        let compression_method = match compression {
+            0x01 | 0x10 | 0x20 => Compression::Zlib,
+            _ => Compression::None,
+        };
+        let timestamp = match version == VersionMajor::Initial {
+            true => Some(reader.read_u64::<LE>()?),
+            false => None,
+        };
+        let hash = Hash(reader.read_hash()?);
+
+        let (blocks, flags, compression_block_size) =
+            if version >= VersionMajor::CompressionEncryption {
+                let blocks = match compression_method {
+                    Compression::None => None,
+                    _ => Some(ReadExt::read_array(reader, read_block)?),
+                };
+                let flags = reader.read_u8()?;
+                if flags & !(FLAG_ENCRYPTED | FLAG_DELETED) != 0 {
+                    return Err(UnrealpakError::Bool(flags as u64));
+                }
+                let compression_block_size = reader.read_u32::<LE>()?;
+                (blocks, Some(flags), Some(compression_block_size))
+            } else {
+                (None, None, None)
+            };
+
+        Ok(Record {
+            offset,
+            compressed_size,
+            uncompressed_size,
+            timestamp,
+            compression_method,
+            blocks,
+            is_encrypted: flags.map(|flags| flags & FLAG_ENCRYPTED != 0),
+            compression_block_size,
+            hash: Some(hash),
+            is_deleted: flags.is_some_and(|flags| flags & FLAG_DELETED != 0),
+        })
     }
 }
 
@@ -255,33 +308,43 @@ pub(crate) enum EntryLocation {
 
 pub(crate) fn write_record<W: Write>(
     writer: &mut W,
-    version: VersionMajor,
+    version: Version,
     record: &Record,
     location: EntryLocation,
 ) -> Result<(), UnrealpakError> {
-    if version >= VersionMajor::PathHashIndex && location == EntryLocation::Index {
+    let compression_blocks_count = if record.compression_method != Compression::None {
+        record
+            .blocks
+            .as_ref()
+            .map_or(0, |blocks| blocks.len() as u32)
+    } else {
+        0
+    };
+
+    if is_encoded(version, location, record) {
         let compression_block_size = record.compression_block_size.unwrap_or_default();
-        let compression_blocks_count = if record.compression_method != Compression::None {
-            record.blocks.as_ref().unwrap().len() as u32
-        } else {
-            0
-        };
+        let encoded_compression_block_size =
+            encode_compression_block_size(compression_block_size, compression_blocks_count);
         let is_encrypted = record.is_encrypted.unwrap_or(false);
         let compression_method = record.compression_method as u32;
         let is_size_32_bit_safe = record.compressed_size <= u32::MAX as u64;
         let is_uncompressed_size_32_bit_safe = record.uncompressed_size <= u32::MAX as u64;
         let is_offset_32_bit_safe = record.offset <= u32::MAX as u64;
 
-        let flags = (compression_block_size)
+        let flags = encoded_compression_block_size
             | (compression_blocks_count << 6)
             | ((is_encrypted as u32) << 22)
-            | ((compression_method as u32) << 23)
+            | (compression_method << 23)
             | ((is_size_32_bit_safe as u32) << 29)
             | ((is_uncompressed_size_32_bit_safe as u32) << 30)
             | ((is_offset_32_bit_safe as u32) << 31);
 
         writer.write_u32::<LE>(flags)?;
 
+        if encoded_compression_block_size == 0x3f {
+            writer.write_u32::<LE>(compression_block_size)?;
+        }
+
         if is_offset_32_bit_safe {
             writer.write_u32::<LE>(record.offset as u32)?;
         } else {
@@ -301,18 +364,17 @@ pub(crate) fn write_record<W: Write>(
                 writer.write_u64::<LE>(record.compressed_size)?;
             }
 
-            assert!(record.blocks.is_some());
             let blocks = record.blocks.as_ref().unwrap();
-            if blocks.len() > 1 || (blocks.len() == 1 && record.is_encrypted.unwrap()) {
+            if blocks.len() > 1 || (blocks.len() == 1 && is_encrypted) {
                 for b in blocks {
                     let block_size = b.end - b.start;
-                    writer.write_u64::<LE>(block_size)?
+                    writer.write_u32::<LE>(block_size as u32)?
                 }
             }
         }
 
         Ok(())
-    } else if version >= VersionMajor::PathHashIndex && location == EntryLocation::Data {
+    } else {
         writer.write_u64::<LE>(match location {
             EntryLocation::Data => 0,
             EntryLocation::Index => record.offset,
@@ -326,40 +388,12 @@ pub(crate) fn write_record<W: Write>(
             Compression::Oodle => todo!(),
         };
 
-        writer.write_u32::<LE>(compression.into())?;
-
-        if let Some(hash) = &record.hash {
-            writer.write_all(&hash.0)?;
+        if version == Version::V8A {
+            writer.write_u8(compression)?;
         } else {
-            panic!("hash missing");
+            writer.write_u32::<LE>(compression.into())?;
         }
 
-        if version >= VersionMajor::CompressionEncryption {
-            // if let Some(blocks) = &record.blocks {
-            //     for block in blocks {
-            //         write_block(writer, block)?;
-            //     }
-            // }
-            writer.write_bool(record.is_encrypted.unwrap())?;
-            writer.write_u32::<LE>(record.compression_block_size.unwrap_or_default())?;
-        }
-        Ok(())
-    } else {
-        writer.write_u64::<LE>(match location {
-            EntryLocation::Data => 0,
-            EntryLocation::Index => record.offset,
-        })?;
-        writer.write_u64::<LE>(record.compressed_size)?;
-        writer.write_u64::<LE>(record.uncompressed_size)?;
-        let compression: u8 = match record.compression_method {
-            Compression::None => 0,
-            Compression::Zlib => 1,
-            Compression::Gzip => todo!(),
-            Compression::Oodle => todo!(),
-        };
-
-        writer.write_u32::<LE>(compression.into())?;
-
         if version == VersionMajor::Initial {
             writer.write_u64::<LE>(record.timestamp.unwrap_or_default())?;
         }
@@ -370,6 +404,25 @@ pub(crate) fn write_record<W: Write>(
             panic!("hash missing");
         }
 
+        if version >= VersionMajor::CompressionEncryption {
+            if compression_blocks_count > 0 {
+                let blocks = record.blocks.as_ref().unwrap();
+                writer.write_u32::<LE>(blocks.len() as u32)?;
+                for block in blocks {
+                    write_block(writer, block)?;
+                }
+            }
+            let flags = match record.is_encrypted.unwrap_or_default() {
+                true => FLAG_ENCRYPTED,
+                false => 0,
+            } | match record.is_deleted {
+                true => FLAG_DELETED,
+                false => 0,
+            };
+            writer.write_u8(flags)?;
+            writer.write_u32::<LE>(record.compression_block_size.unwrap_or_default())?;
+        }
+
         Ok(())
     }
 }
@@ -386,7 +439,7 @@ mod tests {
             0x00, 0x00, 0x00, 0xE0, 0x00, 0x00, 0x00, 0x00, 0x54, 0x02, 0x00, 0x00,
         ];
         let mut reader = Cursor::new(&mut v11_encoded_record);
-        let parsed_record = read_record(&mut reader, VersionMajor::Fnv64BugFix).unwrap();
+        let parsed_record = read_record(&mut reader, Version::V11, EntryLocation::Index).unwrap();
         assert_eq!(parsed_record.offset, 0);
         assert_eq!(
             parsed_record.uncompressed_size,
@@ -401,4 +454,28 @@ mod tests {
         assert_eq!(parsed_record.is_encrypted, Some(false));
         assert_eq!(parsed_record.compression_block_size, Some(0));
     }
+
+    #[test]
+    fn test_read_data_record_pack_v11_compress() {
+        let v11_compress_pak = include_bytes!("../tests/packs/pack_v11_compress.pak");
+        let header = &v11_compress_pak[0x289..0x289 + 0x49];
+        let record =
+            read_record(&mut Cursor::new(header), Version::V11, EntryLocation::Data).unwrap();
+        assert_eq!(record.offset, 0);
+        assert_eq!(record.compression_method, Compression::Zlib);
+        assert_eq!(record.compressed_size, 0x1E42);
+        assert_eq!(
+            record.blocks,
+            Some(vec![Block {
+                start: 0x49,
+                end: 0x49 + 0x1E42
+            }])
+        );
+        assert_eq!(record.is_encrypted, Some(false));
+        assert!(record.hash.is_some());
+
+        let mut written = vec![];
+        write_record(&mut written, Version::V11, &record, EntryLocation::Data).unwrap();
+        assert_eq!(&written[..], header);
+    }
 }
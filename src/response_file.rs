@@ -0,0 +1,376 @@
+//! Parser for UnrealPak response files, as passed with `-Create=`. Each line names a source file
+//! or wildcard and its destination inside the pak, optionally followed by per-file switches:
+//!
+//! ```text
+//! "/abs/path/pack/*" "../mount/point/"
+//! "Content/Maps/Main.umap" "../../../Game/Content/Maps/Main.umap" -compress -encrypt
+//! ```
+
+use crate::errors::UnrealpakError;
+use log::warn;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// A single file to be packed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackInput {
+    /// Path of the file on disk.
+    pub source: PathBuf,
+    /// Full virtual path of the file, e.g. `../../../Game/Content/Maps/Main.umap`.
+    pub destination: String,
+    /// Set by the `-compress` switch.
+    pub compress: bool,
+    /// Set by the `-encrypt` switch.
+    pub encrypt: bool,
+}
+
+/// The files a response file resolves to, in the order they are listed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PackPlan {
+    pub inputs: Vec<PackInput>,
+}
+
+impl PackPlan {
+    /// Reads a response file. Relative source paths are resolved against `base_dir`.
+    pub fn from_response_file<P, B>(path: P, base_dir: B) -> Result<Self, UnrealpakError>
+    where
+        P: AsRef<Path>,
+        B: AsRef<Path>,
+    {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(&contents, base_dir)
+    }
+
+    /// The longest directory shared by every destination, which UnrealPak uses as the mount point
+    /// so that entries are stored relative to it.
+    pub fn common_mount_point(&self) -> String {
+        common_directory(self.inputs.iter().map(|input| {
+            let destination = &input.destination;
+            &destination[..destination.rfind('/').map_or(0, |i| i + 1)]
+        }))
+    }
+
+    pub fn parse<B: AsRef<Path>>(contents: &str, base_dir: B) -> Result<Self, UnrealpakError> {
+        let base_dir = base_dir.as_ref();
+        let mut inputs = vec![];
+        for (i, line) in contents.lines().enumerate() {
+            let tokens = tokenize_quoted(line)
+                .ok_or_else(|| UnrealpakError::ResponseFile(i + 1, "unterminated quote".into()))?;
+            // Quoted tokens are always paths, even if they start with a '-'.
+            let (paths, switches): (Vec<_>, Vec<_>) = tokens
+                .into_iter()
+                .partition(|(token, quoted)| *quoted || !token.starts_with('-'));
+            let paths = paths.into_iter().map(|(path, _)| path).collect::<Vec<_>>();
+            let (source, destination) = match &paths[..] {
+                [] => continue,
+                [source, destination] => (source, destination),
+                _ => {
+                    return Err(UnrealpakError::ResponseFile(
+                        i + 1,
+                        "expected a source and a destination".into(),
+                    ))
+                }
+            };
+
+            let mut compress = false;
+            let mut encrypt = false;
+            for (switch, _) in switches {
+                match switch.to_ascii_lowercase().as_str() {
+                    "-compress" => compress = true,
+                    "-encrypt" => encrypt = true,
+                    _ => warn!("ignoring unsupported switch {} on line {}", switch, i + 1),
+                }
+            }
+
+            for (source, destination) in resolve(base_dir, source, destination)? {
+                inputs.push(PackInput {
+                    source,
+                    destination,
+                    compress,
+                    encrypt,
+                });
+            }
+        }
+        Ok(PackPlan { inputs })
+    }
+}
+
+/// Splits a line into whitespace separated tokens, where double quotes group a token.
+pub(crate) fn tokenize(line: &str) -> Option<Vec<String>> {
+    let tokens = tokenize_quoted(line)?;
+    Some(tokens.into_iter().map(|(token, _)| token).collect())
+}
+
+/// Like [`tokenize`], also telling whether each token was quoted.
+fn tokenize_quoted(line: &str) -> Option<Vec<(String, bool)>> {
+    let mut tokens = vec![];
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    c => token.push(c),
+                }
+            }
+            tokens.push((token, true));
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push((token, false));
+        }
+    }
+    Some(tokens)
+}
+
+/// Expands a source to the files it names. A wildcard in the file name matches recursively below
+/// its directory and treats the destination as a directory, like UnrealPak does.
+fn resolve(
+    base_dir: &Path,
+    source: &str,
+    destination: &str,
+) -> Result<Vec<(PathBuf, String)>, UnrealpakError> {
+    let source = base_dir.join(source.replace('\\', "/"));
+    let destination = destination.replace('\\', "/");
+    let pattern = source
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    if !pattern.contains(['*', '?']) {
+        let destination = if destination.ends_with('/') {
+            destination + &pattern
+        } else {
+            destination
+        };
+        return Ok(vec![(source, destination)]);
+    }
+
+    let source_dir = source.parent().unwrap_or(base_dir);
+    let destination_dir = match destination.rfind('/') {
+        Some(i) => &destination[..i + 1],
+        None => "",
+    };
+    let mut files = vec![];
+    for entry in WalkDir::new(source_dir).sort_by_file_name() {
+        let entry = entry.map_err(std::io::Error::from)?;
+        if !entry.file_type().is_file()
+            || !wildcard_match(&pattern, &entry.file_name().to_string_lossy())
+        {
+            continue;
+        }
+        let relative = entry
+            .path()
+            .strip_prefix(source_dir)
+            .unwrap()
+            .to_path_buf()
+            .into_os_string()
+            .into_string()
+            .map_err(UnrealpakError::OsString)?
+            .replace('\\', "/");
+        files.push((
+            entry.path().to_owned(),
+            format!("{destination_dir}{relative}"),
+        ));
+    }
+    Ok(files)
+}
+
+/// The longest prefix of `directories`, each ending with `/`, that ends at a `/`.
+pub(crate) fn common_directory<'a, I>(directories: I) -> String
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut directories = directories.into_iter();
+    let Some(mut common) = directories.next() else {
+        return String::new();
+    };
+    for directory in directories {
+        let shared = common
+            .bytes()
+            .zip(directory.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let end = common.as_bytes()[..shared]
+            .iter()
+            .rposition(|&b| b == b'/')
+            .map_or(0, |i| i + 1);
+        common = &common[..end];
+    }
+    common.to_owned()
+}
+
+/// Matches `name` against a pattern where `*` matches any run of characters and `?` matches a
+/// single character.
+pub(crate) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((bp, bn)) = backtrack {
+            p = bp + 1;
+            n = bn + 1;
+            backtrack = Some((bp, bn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize(r#"  "a b/*" ../mount/point/  -compress "#).unwrap(),
+            vec!["a b/*", "../mount/point/", "-compress"]
+        );
+        assert_eq!(tokenize(r#""a b/*"#), None);
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*", "test.png"));
+        assert!(wildcard_match("*.uasset", "Hero.uasset"));
+        assert!(!wildcard_match("*.uasset", "Hero.uexp"));
+        assert!(wildcard_match("te?t.*", "test.txt"));
+        assert!(wildcard_match("*e*s*", "zeros.bin"));
+        assert!(!wildcard_match("test", "test.txt"));
+    }
+
+    #[test]
+    fn test_parse_generate_sh_response_file() {
+        let plan = PackPlan::parse(r#""pack/*" "../mount/point/""#, "./tests").unwrap();
+        let destinations = plan
+            .inputs
+            .iter()
+            .map(|i| i.destination.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            destinations,
+            vec![
+                "../mount/point/root/directory/nested.txt",
+                "../mount/point/root/test.png",
+                "../mount/point/root/test.txt",
+                "../mount/point/root/zeros.bin",
+            ]
+        );
+        assert_eq!(
+            plan.inputs[1].source,
+            Path::new("./tests/pack/root/test.png")
+        );
+        assert!(plan.inputs.iter().all(|i| !i.compress && !i.encrypt));
+    }
+
+    #[test]
+    fn test_parse_switches_and_single_files() {
+        let plan = PackPlan::parse(
+            "\"pack/root/*.txt\" \"../../../Game/Text/\" -compress\n\
+             \n\
+             pack/root/test.png ../../../Game/Textures/ -Encrypt -rehydrate\n\
+             pack/root/zeros.bin ../../../Game/Zeros.bin\n",
+            "./tests",
+        )
+        .unwrap();
+        assert_eq!(
+            plan.inputs,
+            vec![
+                PackInput {
+                    source: PathBuf::from("./tests/pack/root/directory/nested.txt"),
+                    destination: "../../../Game/Text/directory/nested.txt".into(),
+                    compress: true,
+                    encrypt: false,
+                },
+                PackInput {
+                    source: PathBuf::from("./tests/pack/root/test.txt"),
+                    destination: "../../../Game/Text/test.txt".into(),
+                    compress: true,
+                    encrypt: false,
+                },
+                PackInput {
+                    source: PathBuf::from("./tests/pack/root/test.png"),
+                    destination: "../../../Game/Textures/test.png".into(),
+                    compress: false,
+                    encrypt: true,
+                },
+                PackInput {
+                    source: PathBuf::from("./tests/pack/root/zeros.bin"),
+                    destination: "../../../Game/Zeros.bin".into(),
+                    compress: false,
+                    encrypt: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_common_mount_point() {
+        let plan = PackPlan::parse(r#""pack/*" "../mount/point/""#, "./tests").unwrap();
+        assert_eq!(plan.common_mount_point(), "../mount/point/root/");
+
+        let plan = PackPlan::parse(
+            "pack/root/test.png ../../../Game/Content/Textures/\n\
+             pack/root/test.txt ../../../Game/Config/test.txt\n\
+             pack/root/zeros.bin ../../../Game/ContentExtra/zeros.bin\n",
+            "./tests",
+        )
+        .unwrap();
+        assert_eq!(plan.common_mount_point(), "../../../Game/");
+
+        let plan = PackPlan::parse("pack/root/test.png ../../../Game/test.png", "./tests").unwrap();
+        assert_eq!(plan.common_mount_point(), "../../../Game/");
+
+        assert_eq!(PackPlan::default().common_mount_point(), "");
+    }
+
+    #[test]
+    fn test_parse_quoted_paths_starting_with_dash() {
+        let plan = PackPlan::parse(
+            r#""pack/root/test.txt" "-Game/test.txt" -compress"#,
+            "./tests",
+        )
+        .unwrap();
+        assert_eq!(
+            plan.inputs,
+            vec![PackInput {
+                source: PathBuf::from("./tests/pack/root/test.txt"),
+                destination: "-Game/test.txt".into(),
+                compress: true,
+                encrypt: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_lines() {
+        assert!(matches!(
+            PackPlan::parse("\"pack/*\"", "./tests"),
+            Err(UnrealpakError::ResponseFile(1, _))
+        ));
+        assert!(matches!(
+            PackPlan::parse("a b\n\"pack/*", "./tests"),
+            Err(UnrealpakError::ResponseFile(2, _))
+        ));
+    }
+}
@@ -0,0 +1,385 @@
+//! Pak signatures, stored next to the pak as a `.sig` file. The signature holds the CRC32 of every
+//! 64KiB chunk of the pak and the SHA1 of those CRCs, encrypted with the private signing key.
+//! This is the layout used by `FPakSignatureFile` since UE 4.25.
+
+use crate::errors::UnrealpakError;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use rsa::rand_core::OsRng;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const SIGNATURE_MAGIC: u32 = 0x73832DAA;
+const SIGNATURE_VERSION: u32 = 1;
+
+/// Size of the pak chunks covered by one CRC32.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Length of the SHA1 of the chunk hashes plus the 11 bytes of PKCS#1 v1.5 padding around it.
+const MIN_MODULUS_LEN: usize = 20 + 11;
+
+/// One half of an RSA key pair.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RsaKey {
+    exponent: BigUint,
+    modulus: BigUint,
+}
+
+impl RsaKey {
+    /// Takes both numbers little-endian, as they are stored in the `SigningKey` section of
+    /// `Crypto.json`. The modulus must be odd and long enough to sign a SHA1 hash, and the
+    /// exponent must be non-zero and smaller than the modulus.
+    pub fn new(exponent: &[u8], modulus: &[u8]) -> Result<Self, UnrealpakError> {
+        let modulus_len = modulus.len() - modulus.iter().rev().take_while(|&&b| b == 0).count();
+        if modulus_len < MIN_MODULUS_LEN {
+            return Err(UnrealpakError::InvalidKey("modulus is too short"));
+        }
+        if modulus[0] & 1 == 0 {
+            return Err(UnrealpakError::InvalidKey("modulus is even"));
+        }
+        let exponent = BigUint::from_bytes_le(exponent);
+        let modulus = BigUint::from_bytes_le(modulus);
+        if exponent == BigUint::default() || exponent >= modulus {
+            return Err(UnrealpakError::InvalidKey("exponent is out of range"));
+        }
+        Ok(RsaKey { exponent, modulus })
+    }
+
+    fn public_key(&self) -> Result<RsaPublicKey, UnrealpakError> {
+        RsaPublicKey::new(self.modulus.clone(), self.exponent.clone())
+            .map_err(|_| UnrealpakError::InvalidKey("unsupported public key"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SigningKeyPair {
+    pub public_key: RsaKey,
+    pub private_key: RsaKey,
+}
+
+/// Contents of a `.sig` file.
+#[derive(Debug, PartialEq)]
+pub struct PakSignature {
+    pub(crate) encrypted_hash: Vec<u8>,
+    pub(crate) chunk_hashes: Vec<u32>,
+}
+
+impl PakSignature {
+    fn principal_hash(&self) -> [u8; 20] {
+        principal_hash(&self.chunk_hashes)
+    }
+}
+
+/// Signs the pak read from `pak`, typically the output of [`PakBuilder`](crate::PakBuilder).
+pub fn sign_pak<R: Read>(
+    pak: R,
+    key_pair: &SigningKeyPair,
+) -> Result<PakSignature, UnrealpakError> {
+    let chunk_hashes = chunk_hashes(pak)?;
+    let hash = principal_hash(&chunk_hashes);
+
+    let (public_key, private_key) = (&key_pair.public_key, &key_pair.private_key);
+    if public_key.modulus != private_key.modulus {
+        return Err(UnrealpakError::InvalidKey("key pair moduli differ"));
+    }
+    let private_key = RsaPrivateKey::from_components(
+        private_key.modulus.clone(),
+        public_key.exponent.clone(),
+        private_key.exponent.clone(),
+        vec![],
+    )
+    .map_err(|_| UnrealpakError::InvalidKey("private key does not match public key"))?;
+
+    // PKCS#1 v1.5 type 1 padding around the raw hash, without a DigestInfo prefix. The private
+    // key is applied with blinding.
+    let encrypted_hash = private_key
+        .sign_with_rng(&mut OsRng, Pkcs1v15Sign::new_unprefixed(), &hash)
+        .map_err(|_| UnrealpakError::InvalidKey("signing key size"))?;
+    Ok(PakSignature {
+        encrypted_hash,
+        chunk_hashes,
+    })
+}
+
+/// Checks the pak read from `pak` against its signature. The decrypted hash must be exactly as
+/// long as the modulus and padded with at least 8 bytes of `0xFF`.
+pub fn verify_pak<R: Read>(
+    pak: R,
+    signature: &PakSignature,
+    public_key: &RsaKey,
+) -> Result<(), UnrealpakError> {
+    public_key
+        .public_key()?
+        .verify(
+            Pkcs1v15Sign::new_unprefixed(),
+            &signature.principal_hash(),
+            &signature.encrypted_hash,
+        )
+        .map_err(|_| UnrealpakError::InvalidSignature)?;
+
+    let actual = chunk_hashes(pak)?;
+    if actual.len() != signature.chunk_hashes.len() {
+        return Err(UnrealpakError::ChunkHashMismatch(
+            actual.len().min(signature.chunk_hashes.len()),
+        ));
+    }
+    if let Some(chunk) = actual
+        .iter()
+        .zip(&signature.chunk_hashes)
+        .position(|(a, b)| a != b)
+    {
+        return Err(UnrealpakError::ChunkHashMismatch(chunk));
+    }
+    Ok(())
+}
+
+/// Signs the pak at `pak_path` and writes the signature next to it, e.g. `pakchunk0-Windows.sig`
+/// for `pakchunk0-Windows.pak`. Returns the path of the signature file.
+pub fn sign_pak_file<P: AsRef<Path>>(
+    pak_path: P,
+    key_pair: &SigningKeyPair,
+) -> Result<PathBuf, UnrealpakError> {
+    let pak_path = pak_path.as_ref();
+    let signature = sign_pak(BufReader::new(File::open(pak_path)?), key_pair)?;
+    let signature_path = pak_path.with_extension("sig");
+    let mut writer = BufWriter::new(File::create(&signature_path)?);
+    write_signature(&mut writer, &signature)?;
+    writer.flush()?;
+    Ok(signature_path)
+}
+
+/// Checks the pak at `pak_path` against the signature file next to it.
+pub fn verify_pak_file<P: AsRef<Path>>(
+    pak_path: P,
+    public_key: &RsaKey,
+) -> Result<(), UnrealpakError> {
+    let pak_path = pak_path.as_ref();
+    let signature = read_signature(&mut BufReader::new(File::open(
+        pak_path.with_extension("sig"),
+    )?))?;
+    verify_pak(
+        BufReader::new(File::open(pak_path)?),
+        &signature,
+        public_key,
+    )
+}
+
+fn chunk_hashes<R: Read>(mut pak: R) -> Result<Vec<u32>, UnrealpakError> {
+    let mut hashes = vec![];
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let mut len = 0;
+        while len < CHUNK_SIZE {
+            match pak.read(&mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        if len == 0 {
+            break;
+        }
+        hashes.push(crc32fast::hash(&buf[..len]));
+        if len < CHUNK_SIZE {
+            break;
+        }
+    }
+    Ok(hashes)
+}
+
+fn principal_hash(chunk_hashes: &[u32]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for hash in chunk_hashes {
+        hasher.update(hash.to_le_bytes());
+    }
+    hasher.finalize().into()
+}
+
+pub fn read_signature<R: Read>(reader: &mut R) -> Result<PakSignature, UnrealpakError> {
+    if reader.read_u32::<LE>()? != SIGNATURE_MAGIC {
+        return Err(UnrealpakError::ValidationError("signature magic"));
+    }
+    let version = reader.read_u32::<LE>()?;
+    if version != SIGNATURE_VERSION {
+        return Err(UnrealpakError::VersionMismatch {
+            expected: SIGNATURE_VERSION,
+            actual: version,
+        });
+    }
+    let len = reader.read_u32::<LE>()? as usize;
+    let mut encrypted_hash = vec![0u8; len];
+    reader.read_exact(&mut encrypted_hash)?;
+    let count = reader.read_u32::<LE>()? as usize;
+    let mut chunk_hashes = Vec::with_capacity(count);
+    for _ in 0..count {
+        chunk_hashes.push(reader.read_u32::<LE>()?);
+    }
+    Ok(PakSignature {
+        encrypted_hash,
+        chunk_hashes,
+    })
+}
+
+pub fn write_signature<W: Write>(
+    writer: &mut W,
+    signature: &PakSignature,
+) -> Result<(), UnrealpakError> {
+    writer.write_u32::<LE>(SIGNATURE_MAGIC)?;
+    writer.write_u32::<LE>(SIGNATURE_VERSION)?;
+    writer.write_u32::<LE>(signature.encrypted_hash.len() as u32)?;
+    writer.write_all(&signature.encrypted_hash)?;
+    writer.write_u32::<LE>(signature.chunk_hashes.len() as u32)?;
+    for hash in &signature.chunk_hashes {
+        writer.write_u32::<LE>(*hash)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use std::io::Cursor;
+
+    fn key_pair() -> SigningKeyPair {
+        let decode = |s| base64::engine::general_purpose::STANDARD.decode(s).unwrap();
+        let modulus = decode("+b8ZsYldle4vQXnOQRiOdgXmVebiXhXX1sBeu8dGoHlcFF3maTtQH0XsEtnS4S7z7SwQvpZStvoJmIERX5Mb22Vd8T2LRMSOCH+Q7tzVDClfEoQqxRT/K2g4YI2Lyg+Ww6pUAHpPPdPYuwKJPY3eraJeZ86mYN/0ZX0zHM7/JOE=");
+        SigningKeyPair {
+            public_key: RsaKey::new(&decode("AQAB"), &modulus).unwrap(),
+            private_key: RsaKey::new(&decode("MbYJN/tblGq6qAV3h61+2nUO4r6fyXBDYfn9AZFjUbUHF15bO7N7xOifDXyOxlgWtmoPHbd3raxXqWLMq7vu2570pYWphh0ehy/vmovQVvUsRdJvRH/zMrL2sxzSD6vUUCP8iREtI21Xgl4Q7U+E96j4jnET1HyPkK6VO8/Q9T4="), &modulus).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_sign_pak_v11() {
+        let pak = include_bytes!("../tests/packs/pack_v11.pak");
+        let signature = sign_pak(&pak[..], &key_pair()).unwrap();
+        assert_eq!(signature.chunk_hashes, vec![0x81747AF9]);
+        // Computed with Python's pow() over the PKCS#1 v1.5 padded SHA1 of the chunk hashes.
+        assert_eq!(
+            signature.encrypted_hash,
+            vec![
+                0x43, 0x0D, 0x5D, 0x19, 0x38, 0x12, 0xA7, 0xDC, 0xAC, 0xAA, 0x2F, 0xFA, 0x29, 0x39,
+                0x51, 0x3F, 0x15, 0xC7, 0x46, 0xB2, 0x4B, 0x62, 0xCB, 0x7E, 0xAA, 0xC5, 0xAD, 0x54,
+                0x75, 0x4B, 0x54, 0x8C, 0x41, 0xBF, 0xB1, 0x37, 0xCF, 0x1A, 0x74, 0xE7, 0xA0, 0x40,
+                0xCE, 0x26, 0x6D, 0x08, 0x8D, 0xCC, 0xFE, 0x58, 0x75, 0x81, 0xD8, 0xA7, 0xBE, 0x0F,
+                0x2E, 0x94, 0x59, 0x69, 0xBC, 0xA1, 0x5D, 0x9E, 0x90, 0x78, 0x9C, 0x21, 0xC4, 0x1C,
+                0xB8, 0x10, 0xE4, 0x48, 0x82, 0x1E, 0xEF, 0x53, 0xED, 0x62, 0x6E, 0xBC, 0x08, 0xC6,
+                0x02, 0xB0, 0xEE, 0xC8, 0x23, 0x96, 0x83, 0x4C, 0x9E, 0x0E, 0x6A, 0x8E, 0x5B, 0xDC,
+                0xAE, 0xCA, 0x5A, 0x36, 0x9F, 0xE1, 0x91, 0x56, 0x18, 0x8F, 0xDC, 0x24, 0x30, 0x2C,
+                0x93, 0x35, 0x31, 0xE8, 0x70, 0xAD, 0xE5, 0x08, 0x30, 0x13, 0xDD, 0xEA, 0xAD, 0x86,
+                0x92, 0x56,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_signature_round_trip_and_verify() {
+        let key_pair = key_pair();
+        let mut pak = (0..CHUNK_SIZE * 3 + 100)
+            .map(|i| (i % 253) as u8)
+            .collect::<Vec<_>>();
+        let signature = sign_pak(&pak[..], &key_pair).unwrap();
+        assert_eq!(signature.chunk_hashes.len(), 4);
+
+        let mut sig_bytes = vec![];
+        write_signature(&mut Cursor::new(&mut sig_bytes), &signature).unwrap();
+        assert_eq!(&sig_bytes[..4], &SIGNATURE_MAGIC.to_le_bytes());
+        let read_back = read_signature(&mut Cursor::new(&sig_bytes)).unwrap();
+        assert_eq!(read_back, signature);
+
+        verify_pak(&pak[..], &read_back, &key_pair.public_key).unwrap();
+
+        pak[CHUNK_SIZE * 2 + 7] ^= 0xFF;
+        assert!(matches!(
+            verify_pak(&pak[..], &read_back, &key_pair.public_key),
+            Err(UnrealpakError::ChunkHashMismatch(2))
+        ));
+    }
+
+    #[test]
+    fn test_sign_and_verify_pak_file() {
+        let key_pair = key_pair();
+        let pak_path = Path::new("./target/test_signed.pak");
+        std::fs::write(pak_path, include_bytes!("../tests/packs/pack_v11.pak")).unwrap();
+
+        let signature_path = sign_pak_file(pak_path, &key_pair).unwrap();
+        assert_eq!(signature_path, Path::new("./target/test_signed.sig"));
+        verify_pak_file(pak_path, &key_pair.public_key).unwrap();
+    }
+
+    #[test]
+    fn test_verify_rejects_forged_chunk_hashes() {
+        let key_pair = key_pair();
+        let pak = include_bytes!("../tests/packs/pack_v11.pak");
+        let mut signature = sign_pak(&pak[..], &key_pair).unwrap();
+        signature.chunk_hashes[0] ^= 1;
+        assert!(matches!(
+            verify_pak(&pak[..], &signature, &key_pair.public_key),
+            Err(UnrealpakError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn test_rsa_key_rejects_invalid_moduli() {
+        let modulus = key_pair().public_key.modulus.to_bytes_le();
+        let invalid_key = |exponent: &[u8], modulus: &[u8]| {
+            matches!(
+                RsaKey::new(exponent, modulus),
+                Err(UnrealpakError::InvalidKey(_))
+            )
+        };
+        assert!(invalid_key(&[1, 0, 1], &[]));
+        assert!(invalid_key(&[1, 0, 1], &[0xF1, 0x01]));
+        assert!(invalid_key(&[1, 0, 1], &[0xFF; MIN_MODULUS_LEN - 1]));
+        let mut even = modulus.clone();
+        even[0] &= !1;
+        assert!(invalid_key(&[1, 0, 1], &even));
+        assert!(invalid_key(&[], &modulus));
+        assert!(invalid_key(&[0xFF; 129], &modulus));
+        assert!(!invalid_key(&[1, 0, 1], &modulus));
+    }
+
+    #[test]
+    fn test_verify_rejects_malformed_signatures() {
+        let key_pair = key_pair();
+        let pak = include_bytes!("../tests/packs/pack_v11.pak");
+        let signature = sign_pak(&pak[..], &key_pair).unwrap();
+        let verify = |encrypted_hash: Vec<u8>| {
+            let signature = PakSignature {
+                encrypted_hash,
+                chunk_hashes: signature.chunk_hashes.clone(),
+            };
+            verify_pak(&pak[..], &signature, &key_pair.public_key)
+        };
+
+        // The decrypted block must be exactly as long as the key.
+        let mut truncated = signature.encrypted_hash.clone();
+        truncated.remove(0);
+        assert!(matches!(
+            verify(truncated),
+            Err(UnrealpakError::InvalidSignature)
+        ));
+        let mut extended = signature.encrypted_hash.clone();
+        extended.insert(0, 0);
+        assert!(matches!(
+            verify(extended),
+            Err(UnrealpakError::InvalidSignature)
+        ));
+        assert!(matches!(
+            verify(vec![0xFF; signature.encrypted_hash.len()]),
+            Err(UnrealpakError::InvalidSignature)
+        ));
+
+        // A signature from a mismatched key pair is rejected rather than panicking.
+        let mismatched = SigningKeyPair {
+            public_key: RsaKey::new(&[3], &key_pair.public_key.modulus.to_bytes_le()).unwrap(),
+            private_key: key_pair.private_key.clone(),
+        };
+        assert!(matches!(
+            sign_pak(&pak[..], &mismatched),
+            Err(UnrealpakError::InvalidKey(_))
+        ));
+    }
+}
@@ -0,0 +1,400 @@
+//! Splitting a pak into several paks that each stay under a size limit, for distribution channels
+//! that cap file sizes. Every chunk is a complete pak with its own index and footer, named like
+//! UnrealPak names patch paks: `name_0_P.pak`, `name_1_P.pak` and so on.
+
+use crate::errors::UnrealpakError;
+use crate::ext::cstring_serialized_size;
+use crate::full_directory_index::split_pak_path;
+use crate::pak_reader::PakReader;
+use crate::pak_writer::{
+    memory_mappable, stored_size, write_pak, Alignment, PakBuilder, PakWriterOptions,
+};
+use crate::progress::NoProgress;
+use crate::record::{EntryLocation, Record};
+use crate::version::{Version, VersionMajor};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufWriter, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Lets the builder of every chunk copy entries from the same source pak.
+struct SharedReader<R>(Rc<RefCell<R>>);
+
+impl<R: Read> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl<R: Seek> Seek for SharedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.borrow_mut().seek(pos)
+    }
+}
+
+/// Size of a chunk being filled with entries, including its index and footer.
+struct ChunkSize {
+    version: Version,
+    alignment: Option<Alignment>,
+    index_encrypted: bool,
+    data_size: u64,
+    index_size: u64,
+    directories: HashSet<String>,
+}
+
+impl ChunkSize {
+    fn new(version: Version, mount_point: &str, options: &PakWriterOptions) -> Self {
+        let index_size = 4 + cstring_serialized_size(mount_point) + 4;
+        let index_size = match version >= VersionMajor::PathHashIndex {
+            // Path hash seed, the locations of the path hash index and full directory index and
+            // the encoded record and file counts, then the path hash index count and padding and
+            // the directory count.
+            true => index_size + 8 + (4 + 36) * 2 + 4 + 4 + (4 + 4) + 4,
+            false => index_size,
+        };
+        ChunkSize {
+            version,
+            alignment: options.alignment,
+            index_encrypted: options.encrypt_index.is_some(),
+            data_size: 0,
+            index_size,
+            directories: HashSet::new(),
+        }
+    }
+
+    fn total(&self) -> u64 {
+        // An encrypted index, path hash index and full directory index are each padded to the
+        // AES block size, by at most 15 bytes.
+        let index_padding = match (self.index_encrypted, self.version) {
+            (false, _) => 0,
+            (true, version) if version >= VersionMajor::PathHashIndex => 15 * 3,
+            (true, _) => 15,
+        };
+        self.data_size + self.index_size + index_padding + self.version.footer_size()
+    }
+
+    /// How much the data and the index grow by when `record` is copied to `path`.
+    fn growth(&self, path: &str, record: &Record) -> (u64, u64) {
+        let (header_size, stored_size) = match record.is_deleted {
+            true => (0, 0),
+            false => (
+                record.serialized_size(self.version, EntryLocation::Data),
+                stored_size(record),
+            ),
+        };
+        let mappable = memory_mappable(
+            path,
+            record.compression_method,
+            record.is_encrypted.unwrap_or_default(),
+        );
+        let padding = match (self.alignment, record.is_deleted) {
+            (Some(alignment), false) => {
+                alignment.padding(self.data_size, header_size, stored_size, mappable)
+            }
+            _ => 0,
+        };
+
+        let mut record = record.clone();
+        record.offset = self.data_size + padding;
+        let record_size = record.serialized_size(self.version, EntryLocation::Index);
+        let index_size = match self.version >= VersionMajor::PathHashIndex {
+            true => {
+                let (directory, filename) = split_pak_path(path);
+                let directory_size = match self.directories.contains(&directory) {
+                    true => 0,
+                    false => 4 + cstring_serialized_size(&directory) + 4,
+                };
+                // The record, encoded unless it is a delete record, its path hash and its full
+                // directory index entry.
+                record_size + 12 + directory_size + 4 + cstring_serialized_size(&filename) + 4
+            }
+            false => 4 + cstring_serialized_size(path) + record_size,
+        };
+        (padding + header_size + stored_size, index_size)
+    }
+
+    fn add(&mut self, path: &str, record: &Record) {
+        let (data_size, index_size) = self.growth(path, record);
+        self.data_size += data_size;
+        self.index_size += index_size;
+        self.directories.insert(split_pak_path(path).0);
+    }
+}
+
+/// Splits `pak` into paks of at most `max_chunk_size` bytes in `output_dir`, returning their
+/// paths. Entries are copied as stored, in the order of their data, and never split across
+/// chunks; an entry that does not fit in a chunk on its own fails with
+/// [`UnrealpakError::EntryTooLarge`] before anything is written. Each chunk keeps the version
+/// and mount point of `pak`, and derives its path hash seed from its own path.
+///
+/// `options` apply to every chunk as they would to [`PakBuilder::merge`]. Chunks written before
+/// an error are removed.
+pub fn split_pak<R, P>(
+    pak: PakReader<R>,
+    output_dir: P,
+    name: &str,
+    max_chunk_size: u64,
+    options: PakWriterOptions,
+) -> Result<Vec<PathBuf>, UnrealpakError>
+where
+    R: Read + Seek + 'static,
+    P: AsRef<Path>,
+{
+    let output_dir = output_dir.as_ref();
+    let version = pak.pak.version;
+    let mount_point = pak.mount_point().to_owned();
+
+    let mut entries = pak
+        .pak
+        .index
+        .entries()
+        .into_iter()
+        .map(|(path, record)| (path, record.clone()))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|(_, record)| record.offset);
+
+    let mut chunks: Vec<Vec<String>> = vec![];
+    let mut size = ChunkSize::new(version, &mount_point, &options);
+    for (path, record) in entries {
+        let (data_size, index_size) = size.growth(&path, &record);
+        if size.total() + data_size + index_size > max_chunk_size || chunks.is_empty() {
+            size = ChunkSize::new(version, &mount_point, &options);
+            let (data_size, index_size) = size.growth(&path, &record);
+            if size.total() + data_size + index_size > max_chunk_size {
+                return Err(UnrealpakError::EntryTooLarge(path, max_chunk_size));
+            }
+            chunks.push(vec![]);
+        }
+        size.add(&path, &record);
+        chunks.last_mut().unwrap().push(path);
+    }
+
+    let PakReader { pak, reader, key } = pak;
+    let reader = Rc::new(RefCell::new(reader));
+    let mut written = vec![];
+    let mut write_chunks = || {
+        for (i, paths) in chunks.iter().enumerate() {
+            let chunk_path = output_dir.join(format!("{name}_{i}_P.pak"));
+            let mut builder =
+                PakBuilder::new(version, mount_point.clone(), &chunk_path, options.clone())?;
+            let source = builder.add_source_pak(PakReader {
+                pak: pak.clone(),
+                reader: SharedReader(reader.clone()),
+                key: key.clone(),
+            })?;
+            for path in paths {
+                builder.copy_entry(path.as_str(), source, path)?;
+            }
+            builder.finish_to_file(&chunk_path, &mut NoProgress)?;
+            written.push(chunk_path);
+        }
+        Ok(())
+    };
+    match write_chunks() {
+        Ok(()) => Ok(written),
+        Err(e) => {
+            for chunk_path in &written {
+                let _ = fs::remove_file(chunk_path);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Packs the files under `pack_root_path` like [`write_pak`] and splits them with
+/// [`split_pak`]. The files are packed into a temporary pak in `output_dir` first, so that chunks
+/// can be filled by the size entries take once compressed.
+pub fn split_directory<P, M, O>(
+    version: Version,
+    pack_root_path: P,
+    mount_point: M,
+    output_dir: O,
+    name: &str,
+    max_chunk_size: u64,
+    options: PakWriterOptions,
+) -> Result<Vec<PathBuf>, UnrealpakError>
+where
+    P: AsRef<Path>,
+    M: AsRef<Path>,
+    O: AsRef<Path>,
+{
+    let output_dir = output_dir.as_ref();
+    let unsplit_path = output_dir.join(format!("{name}.unsplit.pak"));
+    let split = || {
+        // Only the chunks get an encrypted index, so that this pak can be read without a key.
+        let unsplit_options = PakWriterOptions {
+            encrypt_index: None,
+            ..options.clone()
+        };
+        let mut writer = BufWriter::new(fs::File::create(&unsplit_path)?);
+        write_pak(
+            &mut writer,
+            version,
+            pack_root_path,
+            mount_point,
+            &unsplit_path,
+            &unsplit_options,
+        )?;
+        drop(writer);
+
+        // Entries are copied as they were encrypted, since the pak is read without a key.
+        let pak = PakReader::read(fs::File::open(&unsplit_path)?, version, None)?;
+        split_pak(pak, output_dir, name, max_chunk_size, options.clone())
+    };
+    let result = split();
+    let _ = fs::remove_file(&unsplit_path);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compression;
+    use std::io::Cursor;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = Path::new("./target").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read_v11_pak() -> PakReader<Cursor<&'static [u8]>> {
+        let pak = &include_bytes!("../tests/packs/pack_v11.pak")[..];
+        PakReader::read(Cursor::new(pak), Version::V11, None).unwrap()
+    }
+
+    #[test]
+    fn test_split_pak() {
+        let dir = test_dir("test_split_pak");
+        let whole = split_pak(read_v11_pak(), &dir, "whole", u64::MAX, Default::default()).unwrap();
+        assert_eq!(whole, vec![dir.join("whole_0_P.pak")]);
+        let whole_size = fs::metadata(&whole[0]).unwrap().len();
+
+        // The size of a chunk is known exactly, so one byte less than the whole takes two chunks.
+        let exact = split_pak(
+            read_v11_pak(),
+            &dir,
+            "exact",
+            whole_size,
+            Default::default(),
+        );
+        assert_eq!(exact.unwrap().len(), 1);
+
+        let max_chunk_size = whole_size - 1;
+        let chunks = split_pak(
+            read_v11_pak(),
+            &dir,
+            "pack",
+            max_chunk_size,
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(chunks.len(), 2);
+        let mut files = vec![];
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk, &dir.join(format!("pack_{i}_P.pak")));
+            assert!(fs::metadata(chunk).unwrap().len() <= max_chunk_size);
+            let pak = PakReader::read(fs::File::open(chunk).unwrap(), Version::V11, None).unwrap();
+            assert_eq!(pak.mount_point(), read_v11_pak().mount_point());
+            files.extend(pak.files());
+        }
+        files.sort();
+        let mut expected = read_v11_pak().files().collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(files, expected);
+    }
+
+    #[test]
+    fn test_split_pak_rejects_entries_larger_than_a_chunk() {
+        let dir = test_dir("test_split_pak_rejects_entries_larger_than_a_chunk");
+        let result = split_pak(read_v11_pak(), &dir, "pack", 4096, Default::default());
+        assert!(matches!(
+            result,
+            Err(UnrealpakError::EntryTooLarge(path, 4096)) if path == "test.png"
+        ));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_split_directory() {
+        let dir = test_dir("test_split_directory");
+        let options = PakWriterOptions {
+            compression_method: Compression::Zlib,
+            ..Default::default()
+        };
+        let chunks = split_directory(
+            Version::V8B,
+            "./tests/pack/root",
+            "../mount/point/root/",
+            &dir,
+            "pack",
+            9000,
+            options,
+        )
+        .unwrap();
+        assert!(chunks.len() > 1);
+        assert!(!dir.join("pack.unsplit.pak").exists());
+
+        let mut files = vec![];
+        for chunk in &chunks {
+            assert!(fs::metadata(chunk).unwrap().len() <= 9000);
+            let pak = PakReader::read(fs::File::open(chunk).unwrap(), Version::V8B, None).unwrap();
+            files.extend(pak.files());
+        }
+        files.sort();
+        assert_eq!(
+            files,
+            vec!["directory/nested.txt", "test.png", "test.txt", "zeros.bin"]
+        );
+    }
+
+    #[test]
+    fn test_split_directory_encrypts_index() {
+        use aes::cipher::KeyInit;
+        use aes::{Aes256Dec, Aes256Enc};
+
+        let key = [7u8; 32];
+        let dir = test_dir("test_split_directory_encrypts_index");
+        let options = PakWriterOptions {
+            compression_method: Compression::Zlib,
+            encrypt_index: Some(Aes256Enc::new_from_slice(&key).unwrap()),
+            ..Default::default()
+        };
+        let chunks = split_directory(
+            Version::V11,
+            "./tests/pack/root",
+            "../mount/point/root/",
+            &dir,
+            "pack",
+            9000,
+            options,
+        )
+        .unwrap();
+        assert!(chunks.len() > 1);
+
+        let mut files = vec![];
+        for chunk in &chunks {
+            assert!(fs::metadata(chunk).unwrap().len() <= 9000);
+            assert!(matches!(
+                PakReader::read(fs::File::open(chunk).unwrap(), Version::V11, None),
+                Err(UnrealpakError::Encrypted)
+            ));
+            let key = Aes256Dec::new_from_slice(&key).unwrap();
+            let mut pak =
+                PakReader::read(fs::File::open(chunk).unwrap(), Version::V11, Some(key)).unwrap();
+            for file in pak.files().collect::<Vec<_>>() {
+                let expected = fs::read(Path::new("./tests/pack/root").join(&file)).unwrap();
+                assert_eq!(pak.get(&file).unwrap(), expected);
+                files.push(file);
+            }
+        }
+        files.sort();
+        assert_eq!(
+            files,
+            vec!["directory/nested.txt", "test.png", "test.txt", "zeros.bin"]
+        );
+    }
+}
@@ -72,7 +72,7 @@ mod tests {
         let actual_checksum = strcrc32(s) as u64;
         let mut v11_bytes = include_bytes!("../tests/packs/pack_v11.pak");
         let reader = std::io::Cursor::new(&mut v11_bytes);
-        let pak = PakReader::read(reader, crate::version::VersionMajor::Fnv64BugFix, None).unwrap();
+        let pak = PakReader::read(reader, crate::version::Version::V11, None).unwrap();
         assert_eq!(pak.pak.index.path_hash_seed, Some(actual_checksum));
     }
 }
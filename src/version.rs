@@ -17,48 +17,88 @@ pub enum VersionMajor {
     Fnv64BugFix = 11,          // v11
 }
 
-impl VersionMajor {
+/// A pak version, telling apart the two layouts UnrealPak wrote as version 8. v8a (UE 4.22)
+/// stores the compression method of a record in one byte and names four compression methods in
+/// the footer; v8b (UE 4.23 and 4.24) uses four bytes and names five, like later versions.
+///
+/// Versions compare against [`VersionMajor`] by their major version.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Version {
+    V1,
+    V2,
+    V3,
+    V4,
+    V5,
+    V6,
+    V7,
+    V8A,
+    V8B,
+    V9,
+    V10,
+    V11,
+}
+
+impl Version {
     pub(crate) fn iterator() -> Iter<'static, Self> {
-        use VersionMajor::*;
-        static VERSIONS: [VersionMajor; 11] = [
-            Initial,
-            NoTimestamps,
-            CompressionEncryption,
-            IndexEncryption,
-            RelativeChunkOffsets,
-            DeleteRecords,
-            EncryptionKeyGuid,
-            FNameBasedCompression,
-            FrozenIndex,
-            PathHashIndex,
-            Fnv64BugFix,
-        ];
+        use Version::*;
+        static VERSIONS: [Version; 12] = [V1, V2, V3, V4, V5, V6, V7, V8A, V8B, V9, V10, V11];
         VERSIONS.iter()
     }
-}
 
-impl VersionMajor {
-    pub(crate) fn footer_size(&self) -> u64 {
+    pub fn major(self) -> VersionMajor {
+        match self {
+            Version::V1 => VersionMajor::Initial,
+            Version::V2 => VersionMajor::NoTimestamps,
+            Version::V3 => VersionMajor::CompressionEncryption,
+            Version::V4 => VersionMajor::IndexEncryption,
+            Version::V5 => VersionMajor::RelativeChunkOffsets,
+            Version::V6 => VersionMajor::DeleteRecords,
+            Version::V7 => VersionMajor::EncryptionKeyGuid,
+            Version::V8A | Version::V8B => VersionMajor::FNameBasedCompression,
+            Version::V9 => VersionMajor::FrozenIndex,
+            Version::V10 => VersionMajor::PathHashIndex,
+            Version::V11 => VersionMajor::Fnv64BugFix,
+        }
+    }
+
+    /// Number of compression method names in the footer.
+    pub(crate) fn compression_method_slots(self) -> usize {
+        match self {
+            v if v < VersionMajor::FNameBasedCompression => 0,
+            Version::V8A => 4,
+            _ => 5,
+        }
+    }
+
+    pub(crate) fn footer_size(self) -> u64 {
         // (magic + version): u32 + (offset + size): u64 + hash: [u8; 20]
         let mut size = 4 + 4 + 8 + 8 + 20;
-        if *self >= VersionMajor::EncryptionKeyGuid {
+        if self >= VersionMajor::EncryptionKeyGuid {
             // encryption uuid: u128
             size += 16;
         }
-        if *self >= VersionMajor::IndexEncryption {
+        if self >= VersionMajor::IndexEncryption {
             // encrypted: bool
             size += 1;
         }
-        if *self == VersionMajor::FrozenIndex {
+        if self == VersionMajor::FrozenIndex {
             // frozen index: bool
             size += 1;
         }
-        // FIXME: this does not handle the distinction between v8a and v8b. v8a has 32 * 4 bytes
-        // while v8b has 32 * 5 bytes.
-        if *self >= VersionMajor::FNameBasedCompression {
-            // additional compression name
-            size += 32 * 5;
-        }
-        size
+        // compression method names
+        size + 32 * self.compression_method_slots() as u64
+    }
+}
+
+impl PartialEq<VersionMajor> for Version {
+    fn eq(&self, other: &VersionMajor) -> bool {
+        self.major() == *other
+    }
+}
+
+impl PartialOrd<VersionMajor> for Version {
+    fn partial_cmp(&self, other: &VersionMajor) -> Option<std::cmp::Ordering> {
+        self.major().partial_cmp(other)
     }
 }
@@ -0,0 +1,264 @@
+//! A layered view of several paks, resolving each virtual path to the entry of the pak with the
+//! highest priority, the way the engine does when it mounts a game's paks.
+
+use crate::errors::UnrealpakError;
+use crate::pak_reader::PakReader;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+/// The order the engine mounts a pak with, judging by its file name. Patch paks, named
+/// `*_P.pak`, come 100 higher per chunk version, where `name_N_P.pak` has version `N + 1` for
+/// `N` of at least 1 and any other patch pak version 1, so that the first patch still overrides
+/// the pak it patches.
+pub fn pak_order<P: AsRef<Path>>(pak_path: P) -> i32 {
+    let name = pak_path
+        .as_ref()
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let Some(stem) = name.strip_suffix("_p.pak") else {
+        return 0;
+    };
+    let chunk_version = stem
+        .rsplit_once('_')
+        .and_then(|(_, version)| version.parse::<i32>().ok())
+        .filter(|&version| version >= 1)
+        .map_or(1, |version| version + 1);
+    100 * chunk_version
+}
+
+struct MountedPak<R> {
+    name: String,
+    order: i32,
+    reader: PakReader<R>,
+}
+
+/// The entry a virtual path resolves to.
+struct VfsEntry {
+    pak: usize,
+    /// Path relative to the mount point of the pak.
+    path: String,
+    is_deleted: bool,
+}
+
+/// Paks mounted together, merged into one tree of full virtual paths such as
+/// `../../../Game/Content/Maps/Main.umap`. A path resolves to the pak with the highest order that
+/// contains it; between paks of the same order, the one mounted first wins, like the engine's
+/// stable sort of its mounted paks. A delete record hides its path in every pak below it.
+pub struct PakVfs<R> {
+    paks: Vec<MountedPak<R>>,
+    entries: BTreeMap<String, VfsEntry>,
+}
+
+impl<R> Default for PakVfs<R> {
+    fn default() -> Self {
+        PakVfs {
+            paks: vec![],
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<R> PakVfs<R>
+where
+    R: Read + Seek,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mounts `pak` with the order [`pak_order`] gives `pak_path`, which also names the pak.
+    pub fn mount<P: AsRef<Path>>(&mut self, pak: PakReader<R>, pak_path: P) -> &mut Self {
+        let pak_path = pak_path.as_ref();
+        let name = pak_path
+            .file_name()
+            .unwrap_or(pak_path.as_os_str())
+            .to_string_lossy()
+            .into_owned();
+        self.mount_with_order(pak, name, pak_order(pak_path))
+    }
+
+    /// Mounts `pak` with an explicit order, such as one from a launcher's load order.
+    pub fn mount_with_order<N: Into<String>>(
+        &mut self,
+        pak: PakReader<R>,
+        name: N,
+        order: i32,
+    ) -> &mut Self {
+        let i = self.paks.len();
+        for (path, record) in pak.pak.index.entries() {
+            let full_path = pak.full_path(&path);
+            if let Some(entry) = self.entries.get(&full_path) {
+                if self.paks[entry.pak].order >= order {
+                    continue;
+                }
+            }
+            let entry = VfsEntry {
+                pak: i,
+                path,
+                is_deleted: record.is_deleted,
+            };
+            self.entries.insert(full_path, entry);
+        }
+        self.paks.push(MountedPak {
+            name: name.into(),
+            order,
+            reader: pak,
+        });
+        self
+    }
+
+    /// Every full virtual path that resolves to an entry, in order.
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_deleted)
+            .map(|(path, _)| path.as_str())
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.find(path).is_some()
+    }
+
+    /// The name of the pak whose entry `path` resolves to.
+    pub fn resolve(&self, path: &str) -> Option<&str> {
+        self.find(path)
+            .map(|entry| self.paks[entry.pak].name.as_str())
+    }
+
+    /// Reads the entry `path` resolves to, decrypted and decompressed.
+    pub fn open(&mut self, path: &str) -> Result<Vec<u8>, UnrealpakError> {
+        let entry = self
+            .find(path)
+            .ok_or_else(|| UnrealpakError::EntryNotFound(path.to_owned()))?;
+        let (pak, pak_path) = (entry.pak, entry.path.clone());
+        self.paks[pak].reader.get(&pak_path)
+    }
+
+    fn find(&self, path: &str) -> Option<&VfsEntry> {
+        self.entries.get(path).filter(|entry| !entry.is_deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::pak_writer::build_test_pak;
+    use crate::version::Version;
+
+    #[test]
+    fn test_pak_order() {
+        assert_eq!(pak_order("Paks/pakchunk0-Windows.pak"), 0);
+        assert_eq!(pak_order("Paks/pakchunk0-Windows_P.pak"), 100);
+        assert_eq!(pak_order("Paks/pakchunk0-Windows_0_P.pak"), 100);
+        assert_eq!(pak_order("Paks/pakchunk0-Windows_1_P.pak"), 200);
+        assert_eq!(pak_order("Paks/pakchunk0-Windows_12_p.pak"), 1300);
+        assert_eq!(pak_order("Paks/MyMod_x_P.pak"), 100);
+    }
+
+    #[test]
+    fn test_patch_paks_override_by_priority() {
+        let mut vfs = PakVfs::new();
+        vfs.mount(
+            build_test_pak(
+                Version::V11,
+                "../../../Game/Content/",
+                &[("a.txt", Some("patch 2 a"), Compression::None)],
+            ),
+            "Paks/pakchunk0-Windows_2_P.pak",
+        )
+        .mount(
+            build_test_pak(
+                Version::V11,
+                "../../../Game/",
+                &[
+                    ("Content/a.txt", Some("base a"), Compression::None),
+                    ("Content/b.txt", Some("base b"), Compression::None),
+                    ("Content/c.txt", Some("base c"), Compression::None),
+                ],
+            ),
+            "Paks/pakchunk0-Windows.pak",
+        )
+        .mount(
+            build_test_pak(
+                Version::V11,
+                "../../../Game/Content/",
+                &[
+                    ("a.txt", Some("patch 0 a"), Compression::None),
+                    ("b.txt", None, Compression::None),
+                ],
+            ),
+            "Paks/pakchunk0-Windows_0_P.pak",
+        );
+
+        assert_eq!(
+            vfs.files().collect::<Vec<_>>(),
+            vec!["../../../Game/Content/a.txt", "../../../Game/Content/c.txt"]
+        );
+        assert_eq!(
+            vfs.resolve("../../../Game/Content/a.txt"),
+            Some("pakchunk0-Windows_2_P.pak")
+        );
+        assert_eq!(
+            vfs.open("../../../Game/Content/a.txt").unwrap(),
+            b"patch 2 a"
+        );
+        assert_eq!(vfs.open("../../../Game/Content/c.txt").unwrap(), b"base c");
+
+        // The delete record of the first patch hides the file of the base pak.
+        assert!(!vfs.contains("../../../Game/Content/b.txt"));
+        assert!(matches!(
+            vfs.open("../../../Game/Content/b.txt"),
+            Err(UnrealpakError::EntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_explicit_orders() {
+        let mut vfs = PakVfs::new();
+        vfs.mount_with_order(
+            build_test_pak(
+                Version::V11,
+                "../../../Game/",
+                &[("a.txt", Some("1"), Compression::None)],
+            ),
+            "one",
+            1,
+        )
+        .mount_with_order(
+            build_test_pak(
+                Version::V11,
+                "../../../Game/",
+                &[("a.txt", Some("2"), Compression::None)],
+            ),
+            "two",
+            1,
+        )
+        .mount_with_order(
+            build_test_pak(
+                Version::V11,
+                "../../../Game/",
+                &[("b.txt", Some("1"), Compression::None)],
+            ),
+            "three",
+            1,
+        )
+        .mount_with_order(
+            build_test_pak(
+                Version::V11,
+                "../../../Game/",
+                &[("b.txt", Some("2"), Compression::None)],
+            ),
+            "four",
+            2,
+        );
+
+        // Of paks with the same order, the first one mounted wins.
+        assert_eq!(vfs.resolve("../../../Game/a.txt"), Some("one"));
+        assert_eq!(vfs.resolve("../../../Game/b.txt"), Some("four"));
+        assert_eq!(vfs.open("../../../Game/b.txt").unwrap(), b"2");
+        assert_eq!(vfs.resolve("../../../Game/c.txt"), None);
+    }
+}